@@ -0,0 +1,335 @@
+use crate::*;
+
+/// NEP-297 event standard name and version emitted by the marketplace
+pub const EVENT_STANDARD: &str = "paras_marketplace";
+pub const EVENT_STANDARD_VERSION: &str = "1.0.0";
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct EventLog<'a> {
+    standard: &'static str,
+    version: &'static str,
+    #[serde(flatten)]
+    event: &'a MarketEvent,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum MarketEvent {
+    // Listing
+    AddMarketData(AddMarketDataLog),
+    DeleteMarketData(DeleteMarketDataLog),
+    ResolvePurchase(PurchaseLog),
+    ResolvePurchaseFail(PurchaseLog),
+    AddBundleListing(BundleListingLog),
+    DeleteBundleListing(BundleListingLog),
+    ResolveBundlePurchase(BundlePurchaseLog),
+    // Offer
+    AddOffer(AddOfferLog),
+    DeleteOffer(DeleteOfferLog),
+    AddCollectionOffer(AddCollectionOfferLog),
+    DeleteCollectionOffer(DeleteCollectionOfferLog),
+    // Bid
+    AddBid(BidLog),
+    CancelBid(CancelBidLog),
+    RefundBid(TransferLog),
+    ExtendAuction(ExtendAuctionLog),
+    // Trade
+    AddTrade(AddTradeLog),
+    DeleteTrade(DeleteTradeLog),
+    AcceptTrade(AcceptTradeLog),
+    AddBundleTrade(BundleTradeLog),
+    DeleteBundleTrade(BundleTradeLog),
+    AcceptBundleTrade(BundleTradeLog),
+    UpdateEscrow(EscrowLog),
+    // Payout
+    ReferralFee(ReferralFeeLog),
+    ResolveTransferFail(TransferLog),
+    Claim(TransferLog),
+    Deposit(TransferLog),
+    WithdrawTreasury(TransferLog),
+    // Fee
+    SetTransactionFee(SetTransactionFeeLog),
+    RemoveNftContractTransactionFee(NftContractLog),
+    // Admin
+    SetTreasury(SetTreasuryLog),
+    ProposeOwnership(TransferOwnershipLog),
+    TransferOwnership(TransferOwnershipLog),
+    QueueAdminAction(QueuedAdminAction),
+    ExecuteAdminAction(QueuedAdminAction),
+    CancelAdminAction(QueuedAdminAction),
+    AddApprovedNftContractIds(AccountIdsLog),
+    RemoveApprovedNftContractIds(AccountIdsLog),
+    AddApprovedParasNftContractIds(AccountIdsLog),
+    AddApprovedFtTokenIds(AccountIdsLog),
+    GrantRole(RoleLog),
+    RevokeRole(RoleLog),
+    SetPaused(PauseLog),
+}
+
+impl MarketEvent {
+    pub fn emit(&self) {
+        let log = EventLog {
+            standard: EVENT_STANDARD,
+            version: EVENT_STANDARD_VERSION,
+            event: self,
+        };
+        env::log_str(&format!(
+            "EVENT_JSON:{}",
+            near_sdk::serde_json::to_string(&log).unwrap()
+        ));
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AddMarketDataLog {
+    pub owner_id: AccountId,
+    pub approval_id: U64,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub ft_token_id: AccountId,
+    pub price: U128,
+    pub started_at: Option<U64>,
+    pub ended_at: Option<U64>,
+    pub end_price: Option<U128>,
+    pub is_auction: Option<bool>,
+    pub accept_nft_contract_id: Option<AccountId>,
+    pub accept_token_id: Option<TokenId>,
+    pub transaction_fee: U128,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DeleteMarketDataLog {
+    pub owner_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+}
+
+/// Shared by listings (is_offer false) and accepted offers (is_offer true)
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PurchaseLog {
+    pub owner_id: AccountId,
+    pub buyer_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub token_series_id: Option<TokenSeriesId>,
+    pub ft_token_id: AccountId,
+    pub price: U128,
+    pub is_offer: bool,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundleListingLog {
+    pub bundle_id: U64,
+    #[serde(flatten)]
+    pub bundle_listing: BundleListing,
+}
+
+/// tokens are the ones transferred to the buyer, refund is the share of the others
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundlePurchaseLog {
+    pub bundle_id: U64,
+    pub owner_id: AccountId,
+    pub buyer_id: AccountId,
+    pub tokens: Vec<BundleToken>,
+    pub ft_token_id: AccountId,
+    pub price: U128,
+    pub refund: U128,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AddOfferLog {
+    pub buyer_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: Option<TokenId>,
+    pub token_series_id: Option<TokenSeriesId>,
+    pub ft_token_id: AccountId,
+    pub price: U128,
+    pub expires_at: Option<U64>,
+    pub is_pooled: bool,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DeleteOfferLog {
+    pub buyer_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: Option<TokenId>,
+    pub token_series_id: Option<TokenSeriesId>,
+    pub is_expired: bool,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AddCollectionOfferLog {
+    pub buyer_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub ft_token_id: AccountId,
+    pub price: U128,
+    pub quantity: u32,
+    pub expires_at: Option<U64>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DeleteCollectionOfferLog {
+    pub buyer_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub is_expired: bool,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BidLog {
+    pub bidder_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub ft_token_id: AccountId,
+    pub amount: U128,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CancelBidLog {
+    pub bidder_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ExtendAuctionLog {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub ended_at: U64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AddTradeLog {
+    pub buyer_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: Option<TokenId>,
+    pub token_series_id: Option<TokenSeriesId>,
+    pub buyer_nft_contract_id: AccountId,
+    pub buyer_token_id: Option<TokenId>,
+    pub buyer_approval_id: U64,
+    pub ft_token_id: Option<AccountId>,
+    pub buyer_amount: Option<U128>,
+    pub seller_amount: Option<U128>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DeleteTradeLog {
+    pub buyer_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: Option<TokenId>,
+    pub token_series_id: Option<TokenSeriesId>,
+    pub buyer_nft_contract_id: AccountId,
+    pub buyer_token_id: TokenId,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AcceptTradeLog {
+    pub seller_id: AccountId,
+    pub buyer_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub buyer_nft_contract_id: AccountId,
+    pub buyer_token_id: TokenId,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundleTradeLog {
+    pub bundle_id: U64,
+    #[serde(flatten)]
+    pub bundle_trade: BundleTrade,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EscrowLog {
+    pub escrow_id: U64,
+    pub state: EscrowState,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReferralFeeLog {
+    pub referrer_id: AccountId,
+    pub buyer_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub ft_token_id: AccountId,
+    pub amount: U128,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TransferLog {
+    pub account_id: AccountId,
+    pub ft_token_id: AccountId,
+    pub amount: U128,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SetTransactionFeeLog {
+    /// set for a per nft contract override
+    pub nft_contract_id: Option<AccountId>,
+    pub next_fee: u16,
+    pub start_time: Option<TimestampSec>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NftContractLog {
+    pub nft_contract_id: AccountId,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SetTreasuryLog {
+    pub old_treasury_id: AccountId,
+    pub new_treasury_id: AccountId,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TransferOwnershipLog {
+    pub old_owner_id: AccountId,
+    pub new_owner_id: AccountId,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccountIdsLog {
+    pub account_ids: Vec<AccountId>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RoleLog {
+    pub account_id: AccountId,
+    pub role: Role,
+    pub sender_id: AccountId,
+}
+
+/// operation is set when pausing an operation class, nft_contract_id when pausing a collection
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PauseLog {
+    pub operation: Option<Operation>,
+    pub nft_contract_id: Option<AccountId>,
+    pub paused: bool,
+}
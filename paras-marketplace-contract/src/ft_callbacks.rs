@@ -0,0 +1,69 @@
+use crate::*;
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+/// transfer callbacks from FT Contracts
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FtMarketArgs {
+    pub market_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nft_contract_id: Option<AccountId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<TokenId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub referrer_id: Option<AccountId>,
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for Contract {
+    /// Returns the amount of unused tokens, which is refunded by ft_resolve_transfer
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        // enforce cross contract call from an approved ft contract
+
+        let ft_token_id = env::predecessor_account_id();
+        assert_ne!(
+            env::current_account_id(), ft_token_id,
+            "Paras: ft_on_transfer should only be called via cross-contract call"
+        );
+        // "near" stands for the native token and is never an ft contract
+        assert_ne!(
+            ft_token_id,
+            near_account(),
+            "Paras: ft_on_transfer should only be called by an ft contract"
+        );
+        assert!(
+            self.approved_ft_token_ids.contains(&ft_token_id),
+            "Paras: ft_token_id not approved"
+        );
+
+        let FtMarketArgs {
+            market_type,
+            nft_contract_id,
+            token_id,
+            referrer_id,
+        } = near_sdk::serde_json::from_str(&msg).expect("Not valid FtMarketArgs");
+
+        if market_type == "buy" {
+            assert!(nft_contract_id.is_some(), "Paras: nft_contract_id is not specified");
+            assert!(token_id.is_some(), "Paras: token_id is not specified");
+
+            PromiseOrValue::Promise(self.internal_buy_with_ft(
+                nft_contract_id.unwrap(),
+                token_id.unwrap(),
+                ft_token_id,
+                sender_id,
+                amount.0,
+                referrer_id,
+            ))
+        } else if market_type == "deposit" {
+            self.internal_deposit(&sender_id, &ft_token_id, amount.0);
+            PromiseOrValue::Value(U128(0))
+        } else {
+            env::panic_str("Paras: market_type is not supported");
+        }
+    }
+}
@@ -1,34 +1,58 @@
+#![allow(clippy::too_many_arguments)]
+
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet};
+use near_sdk::collections::{LookupMap, LookupSet, UnorderedMap, UnorderedSet};
 use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
-    assert_one_yocto, env, ext_contract, near_bindgen, serde_json::json, AccountId, Balance,
-    BorshStorageKey, CryptoHash, Gas, PanicOnDefault, Promise, Timestamp,
+    assert_one_yocto, env, ext_contract, near_bindgen, AccountId, Balance,
+    BorshStorageKey, CryptoHash, Gas, PanicOnDefault, Promise, StorageUsage, Timestamp,
 };
-use near_sdk::{is_promise_success, promise_result_as_success, PromiseOrValue};
-use std::collections::HashMap;
+use near_sdk::{is_promise_success, promise_result_as_success, PromiseOrValue, PromiseResult};
+use std::collections::{HashMap, HashSet};
 
+use crate::events::*;
 use crate::external::*;
 
+pub mod events;
 mod external;
+mod ft_callbacks;
 mod nft_callbacks;
+mod storage_impl;
 
 const GAS_FOR_NFT_TRANSFER: Gas = Gas(20_000_000_000_000);
 const BASE_GAS: Gas = Gas(5_000_000_000_000);
-const GAS_FOR_ROYALTIES: Gas = Gas(BASE_GAS.0 * 10u64);
-const GAS_FOR_CALLBACK_FIRST_TRADE: Gas = Gas(30_000_000_000_000);
-const GAS_FOR_CALLBACK_SECOND_TRADE: Gas = Gas(80_000_000_000_000);
+// the near payouts of a sale share one resolve_near_payouts callback
+const GAS_FOR_ROYALTIES: Gas = Gas(BASE_GAS.0 * 12u64);
+const GAS_FOR_RESOLVE_NEAR_PAYOUTS: Gas = Gas(BASE_GAS.0 * 2u64);
+const GAS_FOR_FT_TRANSFER: Gas = Gas(5_000_000_000_000);
+const GAS_FOR_RESOLVE_FT_TRANSFER: Gas = BASE_GAS;
+// every ft payout is an ft_transfer with its own callback
+const GAS_FOR_FT_ROYALTIES: Gas = Gas(BASE_GAS.0 * 30u64);
+const GAS_FOR_CALLBACK_ESCROW_TRANSFER: Gas = Gas(BASE_GAS.0 * 2u64);
 const NO_DEPOSIT: Balance = 0;
 const MAX_PRICE: Balance = 1_000_000_000 * 10u128.pow(24);
 const MAX_TREASURY_PERCENTAGE: u16 = 10_000;
+const MAX_LEN_PAYOUT: u32 = 50;
+const MAX_LEN_PAYOUT_FT: u32 = 10;
+const MAX_FEE_BENEFICIARIES: usize = 10;
+// every token of a bundle trade is escrowed and released within one transaction
+const MAX_BUNDLE_TOKENS: usize = 6;
+// every token of a bundle listing is escrowed, released and paid out within one purchase
+const MAX_BUNDLE_LISTING_TOKENS: usize = 4;
+const MAX_BUNDLE_LISTING_TOKENS_FT: usize = 2;
 
 pub const STORAGE_ADD_MARKET_DATA: u128 = 8590000000000000000000;
 pub const FIVE_MINUTES: u64 = 300000000000;
+// 24 hours
+pub const DEFAULT_ADMIN_ACTION_DELAY: u64 = 86_400_000_000_000;
+// 1 hour
+pub const MIN_ADMIN_ACTION_DELAY: u64 = 3_600_000_000_000;
 
 pub type PayoutHashMap = HashMap<AccountId, U128>;
 pub type ContractAndTokenId = String;
 pub type ContractAccountIdTokenId = String;
+pub type ContractAccountId = String;
 pub type TokenId = String;
 pub type TokenSeriesId = String;
 pub type TimestampSec = u32;
@@ -39,7 +63,7 @@ pub struct Payout {
     pub payout: PayoutHashMap,
 }
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct TransactionFee {
     pub next_fee: Option<u16>,
@@ -47,6 +71,47 @@ pub struct TransactionFee {
     pub current_fee: u16,
 }
 
+impl TransactionFee {
+    /// schedules next_fee at start_time, a fee whose start_time has already passed, as when the
+    /// admin action is executed late, applies right away
+    fn set(&mut self, next_fee: u16, start_time: Option<TimestampSec>) {
+        match start_time {
+            Some(start_time) if start_time > to_sec(env::block_timestamp()) => {
+                self.next_fee = Some(next_fee);
+                self.start_time = Some(start_time);
+            }
+            _ => {
+                self.current_fee = next_fee;
+                self.next_fee = None;
+                self.start_time = None;
+            }
+        }
+    }
+
+    /// applies the scheduled fee once start_time has passed
+    fn update(&mut self) {
+        if let Some(next_fee) = self.effective_next_fee() {
+            self.current_fee = next_fee;
+            self.next_fee = None;
+            self.start_time = None;
+        }
+    }
+
+    fn effective_fee(&self) -> u16 {
+        self.effective_next_fee().unwrap_or(self.current_fee)
+    }
+
+    /// the scheduled fee, if its start_time has passed
+    fn effective_next_fee(&self) -> Option<u16> {
+        match (self.next_fee, self.start_time) {
+            (Some(next_fee), Some(start_time)) if to_sec(env::block_timestamp()) >= start_time => {
+                Some(next_fee)
+            }
+            _ => None,
+        }
+    }
+}
+
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct Bid {
@@ -56,10 +121,159 @@ pub struct Bid {
 
 pub type Bids = Vec<Bid>;
 
+/// Roles granted by the owner (or an admin) for marketplace administration.
+/// The owner implicitly has every role.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum Role {
+    /// treasury, approved ft tokens, granting every role except admin
+    Admin,
+    /// transaction fee
+    FeeManager,
+    /// approved nft contracts
+    CollectionCurator,
+    /// cancel bids, end auctions and delete listings of other accounts
+    Moderator,
+    /// pausing the marketplace
+    Pauser,
+}
+
+/// Operation classes that can be paused, globally or per nft_contract_id
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum Operation {
+    /// new listings through nft_on_approve
+    Listing,
+    /// buying listings with NEAR or FT
+    Buy,
+    /// adding and accepting offers and collection offers
+    Offer,
+    /// adding and accepting auction bids
+    Bid,
+    /// adding and accepting trades
+    Trade,
+}
+
+/// Treasury and fee changes are queued and can only be executed after `admin_action_delay`
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde", tag = "type", rename_all = "snake_case")]
+pub enum AdminAction {
+    SetTreasury {
+        treasury_id: AccountId,
+    },
+    SetTransactionFee {
+        next_fee: u16,
+        start_time: Option<TimestampSec>,
+    },
+    SetNftContractTransactionFee {
+        nft_contract_id: AccountId,
+        next_fee: u16,
+        start_time: Option<TimestampSec>,
+    },
+    RemoveNftContractTransactionFee {
+        nft_contract_id: AccountId,
+    },
+    SetReferralFeeShare {
+        referral_fee_share: u16,
+    },
+    SetFeeBeneficiaries {
+        fee_beneficiaries: Vec<FeeBeneficiary>,
+    },
+    SetAdminActionDelay {
+        delay: U64,
+    },
+}
+
+impl AdminAction {
+    fn required_role(&self) -> Role {
+        match self {
+            AdminAction::SetTransactionFee { .. }
+            | AdminAction::SetNftContractTransactionFee { .. }
+            | AdminAction::RemoveNftContractTransactionFee { .. }
+            | AdminAction::SetReferralFeeShare { .. } => Role::FeeManager,
+            _ => Role::Admin,
+        }
+    }
+}
+
+/// Receives weight (in basis points) of every treasury fee
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeBeneficiary {
+    pub account_id: AccountId,
+    pub weight: u16,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct QueuedAdminAction {
+    pub action_id: U64,
+    pub action: AdminAction,
+    pub proposer_id: AccountId,
+    pub executable_at: U64,
+}
+
+fn assert_valid_referrer(referrer_id: &Option<AccountId>, buyer_id: &AccountId) {
+    if let Some(referrer_id) = referrer_id {
+        assert_ne!(referrer_id, buyer_id, "Paras: Cannot refer yourself");
+    }
+}
+
 fn near_account() -> AccountId {
     AccountId::new_unchecked("near".to_string())
 }
 
+fn max_len_payout(ft_token_id: &AccountId) -> u32 {
+    if ft_token_id == &near_account() {
+        MAX_LEN_PAYOUT
+    } else {
+        MAX_LEN_PAYOUT_FT
+    }
+}
+
+/// Parses the result of nft_transfer_payout, None when it does not add up to balance
+fn parse_payout(value: &[u8], balance: u128) -> Option<PayoutHashMap> {
+    let payout = match near_sdk::serde_json::from_slice::<PayoutHashMap>(value) {
+        Ok(payout) => payout,
+        Err(_) => near_sdk::serde_json::from_slice::<Payout>(value).ok()?.payout,
+    };
+
+    let mut remainder = balance;
+    for &value in payout.values() {
+        remainder = remainder.checked_sub(value.0)?;
+    }
+    if remainder <= 100 {
+        Some(payout)
+    } else {
+        None
+    }
+}
+
+fn max_bundle_listing_tokens(ft_token_id: &AccountId) -> usize {
+    if ft_token_id == &near_account() {
+        MAX_BUNDLE_LISTING_TOKENS
+    } else {
+        MAX_BUNDLE_LISTING_TOKENS_FT
+    }
+}
+
+fn gas_for_royalties(ft_token_id: &AccountId) -> Gas {
+    if ft_token_id == &near_account() {
+        GAS_FOR_ROYALTIES
+    } else {
+        GAS_FOR_FT_ROYALTIES
+    }
+}
+
+// ft_resolve_transfer refunds the unused amount, a settled FT purchase uses all of it
+fn unused_amount(ft_token_id: &AccountId, price: U128, overpaid_amount: U128) -> U128 {
+    if ft_token_id == &near_account() {
+        price
+    } else {
+        overpaid_amount
+    }
+}
+
 const DELIMETER: &str = "||";
 const NEAR: &str = "near";
 
@@ -118,6 +332,32 @@ pub struct OfferDataJson {
     token_series_id: Option<TokenId>,
     ft_token_id: AccountId, // "near" for NEAR token
     price: U128,
+    expires_at: Option<U64>,
+    transaction_fee: U128,
+    referrer_id: Option<AccountId>,
+    is_pooled: bool,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CollectionOfferData {
+    pub buyer_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub ft_token_id: AccountId, // "near" for NEAR token
+    pub price: u128,            // price per token
+    pub quantity: u32,          // remaining tokens to buy
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CollectionOfferDataJson {
+    buyer_id: AccountId,
+    nft_contract_id: AccountId,
+    ft_token_id: AccountId, // "near" for NEAR token
+    price: U128,
+    quantity: u32,
+    expires_at: Option<U64>,
+    transaction_fee: U128,
 }
 
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
@@ -132,6 +372,175 @@ pub struct TradeData {
     pub token_series_id: Option<TokenSeriesId>,
 }
 
+/// fungible amounts escrowed alongside a swap, released to the other side once both NFTs are
+/// swapped or credited back to the internal balance of their owner if the swap fails
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TradePayment {
+    pub ft_token_id: AccountId,
+    pub buyer_amount: U128,
+    pub seller_amount: U128,
+    pub transaction_fee: U128,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundleToken {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+}
+
+/// N-for-M swap of the buyer's tokens for the seller's tokens
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundleTrade {
+    pub buyer_id: AccountId,
+    pub seller_id: AccountId,
+    pub buyer_tokens: Vec<BundleToken>,
+    pub seller_tokens: Vec<BundleToken>,
+}
+
+/// Escrowing -> Releasing -> Released when every token reached the marketplace,
+/// Escrowing -> RollingBack -> RolledBack otherwise. A failed release or rollback transfer
+/// leaves the escrow in ReleaseFailed or RollbackFailed until it is retried or claimed.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum EscrowState {
+    Escrowing,
+    Releasing,
+    RollingBack,
+    ReleaseFailed,
+    RollbackFailed,
+    Released,
+    RolledBack,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EscrowToken {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub owner_id: AccountId,
+    /// held by the marketplace and not delivered yet
+    pub is_escrowed: bool,
+}
+
+/// Persisted record of a swap in progress, removed once every token is delivered
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Escrow {
+    pub state: EscrowState,
+    pub buyer_id: AccountId,
+    pub seller_id: AccountId,
+    pub tokens: Vec<EscrowToken>,
+    pub payment: Option<TradePayment>,
+    pub bundle_id: Option<U64>,
+    pub purchase: Option<BundlePurchase>,
+}
+
+impl Escrow {
+    /// the other side once the swap is committed, the owner otherwise
+    fn destination(&self, token: &EscrowToken) -> AccountId {
+        match self.state {
+            EscrowState::Releasing | EscrowState::ReleaseFailed | EscrowState::Released => {
+                if token.owner_id == self.buyer_id {
+                    self.seller_id.clone()
+                } else {
+                    self.buyer_id.clone()
+                }
+            }
+            _ => token.owner_id.clone(),
+        }
+    }
+
+    fn is_stuck(&self) -> bool {
+        self.state == EscrowState::ReleaseFailed || self.state == EscrowState::RollbackFailed
+    }
+
+    fn escrowed_token_indexes(&self) -> Vec<u32> {
+        (0..self.tokens.len() as u32)
+            .filter(|index| self.tokens[*index as usize].is_escrowed)
+            .collect()
+    }
+}
+
+/// price paid for a bundle listing, held while its tokens are escrowed and paid out with their
+/// payouts once every token is escrowed, or refunded to the buyer otherwise
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundlePurchase {
+    pub ft_token_id: AccountId,
+    pub price: U128,
+    pub token_prices: Vec<U128>,
+    pub transaction_fees: Vec<U128>,
+    pub referrer_id: Option<AccountId>,
+    pub paid_from_balance: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EscrowJson {
+    escrow_id: U64,
+    #[serde(flatten)]
+    escrow: Escrow,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundleTokenJson {
+    nft_contract_id: AccountId,
+    token_id: TokenId,
+    approval_id: Option<U64>,
+}
+
+/// Several tokens sold as a single lot. The price is split evenly across the tokens, each
+/// share is paid out with the payout of its token.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundleListing {
+    pub owner_id: AccountId,
+    pub tokens: Vec<BundleToken>,
+    pub ft_token_id: AccountId, // "near" for NEAR token
+    pub price: U128,
+    /// the transaction fee of every token, locked when the bundle is listed
+    pub transaction_fees: Vec<U128>,
+}
+
+impl BundleListing {
+    /// the even share of the price for the token at index, the last token also takes the
+    /// remainder of the split
+    fn token_price(&self, index: usize) -> u128 {
+        let token_count = self.tokens.len() as u128;
+        let token_price = self.price.0 / token_count;
+        if index == self.tokens.len() - 1 {
+            self.price.0 - token_price * (token_count - 1)
+        } else {
+            token_price
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundleListingJson {
+    bundle_id: U64,
+    owner_id: AccountId,
+    tokens: Vec<BundleTokenJson>,
+    ft_token_id: AccountId,
+    price: U128,
+    transaction_fees: Vec<U128>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundleTradeJson {
+    bundle_id: U64,
+    buyer_id: AccountId,
+    seller_id: AccountId,
+    buyer_tokens: Vec<BundleTokenJson>,
+    seller_tokens: Vec<BundleTokenJson>,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct MarketDataJson {
@@ -145,12 +554,25 @@ pub struct MarketDataJson {
     started_at: Option<U64>,
     ended_at: Option<U64>,
     end_price: Option<U128>, // dutch auction
+    accept_nft_contract_id: Option<AccountId>,
+    accept_token_id: Option<TokenId>,
     is_auction: Option<bool>,
     transaction_fee: U128
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TradeListJson {
+    buyer_id: AccountId,
+    buyer_nft_contract_id: AccountId,
+    buyer_token_id: TokenId,
+    approval_id: U64,
+    trade_data: Vec<TradeData>,
+}
+
+/// Layout of the deployed contract, read by migrate
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
-pub struct ContractV2 {
+pub struct ContractV3 {
     pub owner_id: AccountId,
     pub treasury_id: AccountId,
     pub old_market: UnorderedMap<ContractAndTokenId, MarketDataV1>,
@@ -163,6 +585,7 @@ pub struct ContractV2 {
     pub paras_nft_contracts: UnorderedSet<AccountId>,
     pub transaction_fee: TransactionFee,
     pub trades: UnorderedMap<ContractAccountIdTokenId, TradeList>,
+    pub market_data_transaction_fee: MarketDataTransactionFee,
 }
 
 #[derive(BorshSerialize, BorshDeserialize)]
@@ -186,7 +609,40 @@ pub struct Contract {
     pub paras_nft_contracts: UnorderedSet<AccountId>,
     pub transaction_fee: TransactionFee,
     pub trades: UnorderedMap<ContractAccountIdTokenId, TradeList>,
-    pub market_data_transaction_fee: MarketDataTransactionFee
+    pub market_data_transaction_fee: MarketDataTransactionFee,
+    pub claimable_balances: LookupMap<String, Balance>,
+    pub collection_offers: UnorderedMap<ContractAccountId, CollectionOfferData>,
+    pub offer_expires_at: UnorderedMap<String, u64>,
+    pub by_nft_contract_id: LookupMap<AccountId, UnorderedSet<ContractAndTokenId>>,
+    pub offers_by_token: LookupMap<ContractAndTokenId, UnorderedSet<ContractAccountIdTokenId>>,
+    pub trades_by_owner_id: LookupMap<AccountId, UnorderedSet<ContractAccountIdTokenId>>,
+    pub storage_used: LookupMap<AccountId, StorageUsage>,
+    pub roles: UnorderedMap<AccountId, Vec<Role>>,
+    pub paused_operations: UnorderedSet<Operation>,
+    pub paused_nft_contract_ids: UnorderedSet<AccountId>,
+    pub pending_owner_id: Option<AccountId>,
+    pub admin_actions: UnorderedMap<u64, QueuedAdminAction>,
+    pub next_admin_action_id: u64,
+    pub admin_action_delay: u64,
+    pub nft_contract_transaction_fees: UnorderedMap<AccountId, TransactionFee>,
+    pub offer_transaction_fee: LookupMap<ContractAccountIdTokenId, u128>,
+    pub referral_fee_share: u16,
+    pub offer_referrer_ids: LookupMap<ContractAccountIdTokenId, AccountId>,
+    pub bid_referrer_ids: LookupMap<ContractAccountIdTokenId, AccountId>,
+    pub fee_beneficiaries: Vec<FeeBeneficiary>,
+    pub treasury_balances: UnorderedMap<AccountId, Balance>,
+    pub pooled_offers: LookupSet<ContractAccountIdTokenId>,
+    pub bundle_trades: UnorderedMap<u64, BundleTrade>,
+    pub bundle_trade_approvals: LookupMap<String, u64>,
+    pub next_bundle_trade_id: u64,
+    pub escrows: UnorderedMap<u64, Escrow>,
+    pub next_escrow_id: u64,
+    pub bundle_listings: UnorderedMap<u64, BundleListing>,
+    pub bundle_listing_approvals: LookupMap<String, u64>,
+    pub next_bundle_listing_id: u64,
+    pub legacy_by_owner_id: LookupMap<AccountId, UnorderedSet<TokenId>>,
+    pub sponsored_claimable_balances: LookupSet<String>,
+    pub trade_transaction_fee: LookupMap<String, u128>,
 }
 
 #[derive(BorshStorageKey, BorshSerialize)]
@@ -206,7 +662,44 @@ pub enum StorageKey {
     OffersV2,
     ParasNFTContractIdsV2,
     Trade,
-    MarketDataTransactionFee
+    MarketDataTransactionFee,
+    ClaimableBalances,
+    CollectionOffers,
+    OfferExpiresAt,
+    ByNFTContractId,
+    ByNFTContractIdInner {
+        account_id_hash: CryptoHash,
+    },
+    OffersByToken,
+    OffersByTokenInner {
+        contract_and_token_id_hash: CryptoHash,
+    },
+    TradesByOwnerId,
+    TradesByOwnerIdInner {
+        account_id_hash: CryptoHash,
+    },
+    StorageUsed,
+    Roles,
+    PausedOperations,
+    PausedNFTContractIds,
+    AdminActions,
+    NFTContractTransactionFees,
+    OfferTransactionFee,
+    OfferReferrerIds,
+    BidReferrerIds,
+    TreasuryBalances,
+    PooledOffers,
+    BundleTrades,
+    BundleTradeApprovals,
+    Escrows,
+    BundleListings,
+    BundleListingApprovals,
+    ByOwnerIdV2,
+    ByOwnerIdInnerV2 {
+        account_id_hash: CryptoHash,
+    },
+    SponsoredClaimableBalances,
+    TradeTransactionFee,
 }
 
 #[near_bindgen]
@@ -230,7 +723,7 @@ impl Contract {
             approved_ft_token_ids: UnorderedSet::new(StorageKey::FTTokenIds),
             approved_nft_contract_ids: UnorderedSet::new(StorageKey::NFTContractIds),
             storage_deposits: LookupMap::new(StorageKey::StorageDeposits),
-            by_owner_id: LookupMap::new(StorageKey::ByOwnerId),
+            by_owner_id: LookupMap::new(StorageKey::ByOwnerIdV2),
             offers: UnorderedMap::new(StorageKey::Offers),
             paras_nft_contracts: UnorderedSet::new(StorageKey::ParasNFTContractIds),
             transaction_fee: TransactionFee {
@@ -241,7 +734,40 @@ impl Contract {
             trades: UnorderedMap::new(StorageKey::Trade),
             market_data_transaction_fee: MarketDataTransactionFee{
                 transaction_fee: UnorderedMap::new(StorageKey::MarketDataTransactionFee)
-            }
+            },
+            claimable_balances: LookupMap::new(StorageKey::ClaimableBalances),
+            collection_offers: UnorderedMap::new(StorageKey::CollectionOffers),
+            offer_expires_at: UnorderedMap::new(StorageKey::OfferExpiresAt),
+            by_nft_contract_id: LookupMap::new(StorageKey::ByNFTContractId),
+            offers_by_token: LookupMap::new(StorageKey::OffersByToken),
+            trades_by_owner_id: LookupMap::new(StorageKey::TradesByOwnerId),
+            storage_used: LookupMap::new(StorageKey::StorageUsed),
+            roles: UnorderedMap::new(StorageKey::Roles),
+            paused_operations: UnorderedSet::new(StorageKey::PausedOperations),
+            paused_nft_contract_ids: UnorderedSet::new(StorageKey::PausedNFTContractIds),
+            pending_owner_id: None,
+            admin_actions: UnorderedMap::new(StorageKey::AdminActions),
+            next_admin_action_id: 0,
+            admin_action_delay: DEFAULT_ADMIN_ACTION_DELAY,
+            nft_contract_transaction_fees: UnorderedMap::new(StorageKey::NFTContractTransactionFees),
+            offer_transaction_fee: LookupMap::new(StorageKey::OfferTransactionFee),
+            referral_fee_share: 0,
+            offer_referrer_ids: LookupMap::new(StorageKey::OfferReferrerIds),
+            bid_referrer_ids: LookupMap::new(StorageKey::BidReferrerIds),
+            fee_beneficiaries: Vec::new(),
+            treasury_balances: UnorderedMap::new(StorageKey::TreasuryBalances),
+            pooled_offers: LookupSet::new(StorageKey::PooledOffers),
+            bundle_trades: UnorderedMap::new(StorageKey::BundleTrades),
+            bundle_trade_approvals: LookupMap::new(StorageKey::BundleTradeApprovals),
+            next_bundle_trade_id: 0,
+            escrows: UnorderedMap::new(StorageKey::Escrows),
+            next_escrow_id: 0,
+            bundle_listings: UnorderedMap::new(StorageKey::BundleListings),
+            bundle_listing_approvals: LookupMap::new(StorageKey::BundleListingApprovals),
+            next_bundle_listing_id: 0,
+            legacy_by_owner_id: LookupMap::new(StorageKey::ByOwnerId),
+            sponsored_claimable_balances: LookupSet::new(StorageKey::SponsoredClaimableBalances),
+            trade_transaction_fee: LookupMap::new(StorageKey::TradeTransactionFee),
         };
 
         this.approved_ft_token_ids.insert(&near_account());
@@ -258,7 +784,7 @@ impl Contract {
 
     #[init(ignore_state)]
     pub fn migrate() -> Self {
-        let prev: ContractV2 = env::state_read().expect("ERR_NOT_INITIALIZED");
+        let prev: ContractV3 = env::state_read().expect("ERR_NOT_INITIALIZED");
         assert_eq!(
             env::predecessor_account_id(),
             prev.owner_id,
@@ -273,117 +799,657 @@ impl Contract {
             approved_ft_token_ids: prev.approved_ft_token_ids,
             approved_nft_contract_ids: prev.approved_nft_contract_ids,
             storage_deposits: prev.storage_deposits,
-            by_owner_id: prev.by_owner_id,
+            by_owner_id: LookupMap::new(StorageKey::ByOwnerIdV2),
             offers: prev.offers,
             paras_nft_contracts: prev.paras_nft_contracts,
             transaction_fee: prev.transaction_fee,
             trades: prev.trades,
-            market_data_transaction_fee: MarketDataTransactionFee{
-                transaction_fee: UnorderedMap::new(StorageKey::MarketDataTransactionFee)
-            }
+            market_data_transaction_fee: prev.market_data_transaction_fee,
+            claimable_balances: LookupMap::new(StorageKey::ClaimableBalances),
+            collection_offers: UnorderedMap::new(StorageKey::CollectionOffers),
+            offer_expires_at: UnorderedMap::new(StorageKey::OfferExpiresAt),
+            by_nft_contract_id: LookupMap::new(StorageKey::ByNFTContractId),
+            offers_by_token: LookupMap::new(StorageKey::OffersByToken),
+            trades_by_owner_id: LookupMap::new(StorageKey::TradesByOwnerId),
+            storage_used: LookupMap::new(StorageKey::StorageUsed),
+            roles: UnorderedMap::new(StorageKey::Roles),
+            paused_operations: UnorderedSet::new(StorageKey::PausedOperations),
+            paused_nft_contract_ids: UnorderedSet::new(StorageKey::PausedNFTContractIds),
+            pending_owner_id: None,
+            admin_actions: UnorderedMap::new(StorageKey::AdminActions),
+            next_admin_action_id: 0,
+            admin_action_delay: DEFAULT_ADMIN_ACTION_DELAY,
+            nft_contract_transaction_fees: UnorderedMap::new(StorageKey::NFTContractTransactionFees),
+            offer_transaction_fee: LookupMap::new(StorageKey::OfferTransactionFee),
+            referral_fee_share: 0,
+            offer_referrer_ids: LookupMap::new(StorageKey::OfferReferrerIds),
+            bid_referrer_ids: LookupMap::new(StorageKey::BidReferrerIds),
+            fee_beneficiaries: Vec::new(),
+            treasury_balances: UnorderedMap::new(StorageKey::TreasuryBalances),
+            pooled_offers: LookupSet::new(StorageKey::PooledOffers),
+            bundle_trades: UnorderedMap::new(StorageKey::BundleTrades),
+            bundle_trade_approvals: LookupMap::new(StorageKey::BundleTradeApprovals),
+            next_bundle_trade_id: 0,
+            escrows: UnorderedMap::new(StorageKey::Escrows),
+            next_escrow_id: 0,
+            bundle_listings: UnorderedMap::new(StorageKey::BundleListings),
+            bundle_listing_approvals: LookupMap::new(StorageKey::BundleListingApprovals),
+            next_bundle_listing_id: 0,
+            // entries made before migrate were never charged to storage_used
+            legacy_by_owner_id: prev.by_owner_id,
+            sponsored_claimable_balances: LookupSet::new(StorageKey::SponsoredClaimableBalances),
+            trade_transaction_fee: LookupMap::new(StorageKey::TradeTransactionFee),
         };
 
         this
     }
+
+    /// Adds listings, offers and trades made before migrate to the by_nft_contract_id,
+    /// offers_by_token and trades_by_owner_id indexes. Walks old listings, listings, offers
+    /// and trades as one sequence and returns the index to continue from, which is past
+    /// the end once everything is indexed.
+    pub fn migrate_indexes(&mut self, from_index: Option<U64>, limit: Option<u64>) -> U64 {
+        self.assert_owner();
+        let mut index: u64 = from_index.map(From::from).unwrap_or_default();
+        let end_index = index + limit.unwrap_or(50);
+
+        while index < end_index {
+            let mut position = index;
+            if position < self.old_market.len() {
+                let contract_and_token_id = self.old_market.keys_as_vector().get(position).unwrap();
+                let market_data = self.old_market.values_as_vector().get(position).unwrap();
+                self.internal_index_market_data(&market_data.nft_contract_id, &contract_and_token_id);
+                index += 1;
+                continue;
+            }
+            position -= self.old_market.len();
+
+            if position < self.market.len() {
+                let contract_and_token_id = self.market.keys_as_vector().get(position).unwrap();
+                let market_data = self.market.values_as_vector().get(position).unwrap();
+                self.internal_index_market_data(&market_data.nft_contract_id, &contract_and_token_id);
+                index += 1;
+                continue;
+            }
+            position -= self.market.len();
+
+            if position < self.offers.len() {
+                let contract_account_id_token_id = self.offers.keys_as_vector().get(position).unwrap();
+                let offer_data = self.offers.values_as_vector().get(position).unwrap();
+                let token = offer_data.token_id.or(offer_data.token_series_id).unwrap();
+                let contract_and_token_id = format!("{}{}{}", offer_data.nft_contract_id, DELIMETER, token);
+                let mut offer_ids = self.offers_by_token.get(&contract_and_token_id).unwrap_or_else(|| {
+                    UnorderedSet::new(
+                        StorageKey::OffersByTokenInner {
+                            contract_and_token_id_hash: hash_contract_account_id_token_id(&contract_and_token_id),
+                        }
+                        .try_to_vec()
+                        .unwrap(),
+                    )
+                });
+                offer_ids.insert(&contract_account_id_token_id);
+                self.offers_by_token.insert(&contract_and_token_id, &offer_ids);
+                index += 1;
+                continue;
+            }
+            position -= self.offers.len();
+
+            if position < self.trades.len() {
+                // nft_contract_id||account_id||token_id
+                let buyer_contract_account_id_token_id = self.trades.keys_as_vector().get(position).unwrap();
+                let buyer_id: AccountId = buyer_contract_account_id_token_id
+                    .split(DELIMETER)
+                    .nth(1)
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                let mut trade_ids = self.trades_by_owner_id.get(&buyer_id).unwrap_or_else(|| {
+                    UnorderedSet::new(
+                        StorageKey::TradesByOwnerIdInner {
+                            account_id_hash: hash_account_id(&buyer_id),
+                        }
+                        .try_to_vec()
+                        .unwrap(),
+                    )
+                });
+                trade_ids.insert(&buyer_contract_account_id_token_id);
+                self.trades_by_owner_id.insert(&buyer_id, &trade_ids);
+                index += 1;
+                continue;
+            }
+
+            break;
+        }
+
+        index.into()
+    }
+
+    fn internal_index_market_data(&mut self, nft_contract_id: &AccountId, contract_and_token_id: &ContractAndTokenId) {
+        let mut nft_token_ids = self.by_nft_contract_id.get(nft_contract_id).unwrap_or_else(|| {
+            UnorderedSet::new(
+                StorageKey::ByNFTContractIdInner {
+                    account_id_hash: hash_account_id(nft_contract_id),
+                }
+                .try_to_vec()
+                .unwrap(),
+            )
+        });
+        nft_token_ids.insert(contract_and_token_id);
+        self.by_nft_contract_id.insert(nft_contract_id, &nft_token_ids);
+    }
     // Changing treasury & ownership
 
     #[payable]
-    pub fn set_treasury(&mut self, treasury_id: AccountId) {
+    pub fn set_treasury(&mut self, treasury_id: AccountId) -> U64 {
         assert_one_yocto();
-        self.assert_owner();
-        self.treasury_id = treasury_id;
+        self.assert_role(Role::Admin);
+
+        self.internal_queue_admin_action(AdminAction::SetTreasury { treasury_id })
     }
 
     #[payable]
-    pub fn set_transaction_fee(&mut self, next_fee: u16, start_time: Option<TimestampSec>) {
+    pub fn set_transaction_fee(&mut self, next_fee: u16, start_time: Option<TimestampSec>) -> U64 {
         assert_one_yocto();
-        self.assert_owner();
+        self.assert_role(Role::FeeManager);
 
         assert!(next_fee < MAX_TREASURY_PERCENTAGE, "Paras: fee is higher than {}", MAX_TREASURY_PERCENTAGE);
+        self.assert_start_time_after_delay(start_time);
 
-        if start_time.is_none() {
-            self.transaction_fee.current_fee = next_fee;
-            self.transaction_fee.next_fee = None;
-            self.transaction_fee.start_time = None;
-            return;
-        } else {
-            let start_time: TimestampSec = start_time.unwrap();
-            assert!(
-                start_time > to_sec(env::block_timestamp()),
-                "start_time is less than current block_timestamp"
-            );
-            self.transaction_fee.next_fee = Some(next_fee);
-            self.transaction_fee.start_time = Some(start_time);
-        }
+        self.internal_queue_admin_action(AdminAction::SetTransactionFee {
+            next_fee,
+            start_time,
+        })
     }
 
-    pub fn calculate_market_data_transaction_fee(&mut self, nft_contract_id: &AccountId, token_id: &TokenId) -> u128{
-        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
-        if let Some(transaction_fee) = self.market_data_transaction_fee.transaction_fee.get(&contract_and_token_id){
-            return transaction_fee;
-        }
+    /// Overrides the transaction fee for every listing and offer of nft_contract_id
+    #[payable]
+    pub fn set_nft_contract_transaction_fee(
+        &mut self,
+        nft_contract_id: AccountId,
+        next_fee: u16,
+        start_time: Option<TimestampSec>,
+    ) -> U64 {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
 
-        // fallback to default transaction fee
-        self.calculate_current_transaction_fee()
+        assert!(next_fee < MAX_TREASURY_PERCENTAGE, "Paras: fee is higher than {}", MAX_TREASURY_PERCENTAGE);
+        self.assert_start_time_after_delay(start_time);
+
+        self.internal_queue_admin_action(AdminAction::SetNftContractTransactionFee {
+            nft_contract_id,
+            next_fee,
+            start_time,
+        })
     }
 
-    pub fn calculate_current_transaction_fee(&mut self) -> u128 {
-        let transaction_fee: &TransactionFee = &self.transaction_fee;
-        if transaction_fee.next_fee.is_some() {
-            if to_sec(env::block_timestamp()) >= transaction_fee.start_time.unwrap() {
-                self.transaction_fee.current_fee = transaction_fee.next_fee.unwrap();
-                self.transaction_fee.next_fee = None;
-                self.transaction_fee.start_time = None;
-            }
-        }
-        self.transaction_fee.current_fee as u128
+    #[payable]
+    pub fn remove_nft_contract_transaction_fee(&mut self, nft_contract_id: AccountId) -> U64 {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+
+        self.internal_queue_admin_action(AdminAction::RemoveNftContractTransactionFee {
+            nft_contract_id,
+        })
     }
 
-    pub fn get_transaction_fee(&self) -> &TransactionFee {
-        &self.transaction_fee
+    /// Share of the treasury fee, in basis points, paid to the referrer of a purchase
+    #[payable]
+    pub fn set_referral_fee_share(&mut self, referral_fee_share: u16) -> U64 {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+
+        assert!(
+            referral_fee_share <= MAX_TREASURY_PERCENTAGE,
+            "Paras: referral fee share is higher than {}",
+            MAX_TREASURY_PERCENTAGE
+        );
+
+        self.internal_queue_admin_action(AdminAction::SetReferralFeeShare { referral_fee_share })
     }
 
-    pub fn get_market_data_transaction_fee (&self, nft_contract_id: &AccountId, token_id: &TokenId) -> u128{
-        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
-        if let Some(transaction_fee) = self.market_data_transaction_fee.transaction_fee.get(&contract_and_token_id){
-            return transaction_fee;
+    /// Splits treasury fees among fee_beneficiaries, an empty list sends them to treasury_id
+    #[payable]
+    pub fn set_fee_beneficiaries(&mut self, fee_beneficiaries: Vec<FeeBeneficiary>) -> U64 {
+        assert_one_yocto();
+        self.assert_role(Role::Admin);
+
+        assert!(
+            fee_beneficiaries.len() <= MAX_FEE_BENEFICIARIES,
+            "Paras: Cannot have more than {} fee beneficiaries",
+            MAX_FEE_BENEFICIARIES
+        );
+        if !fee_beneficiaries.is_empty() {
+            let total_weight: u32 = fee_beneficiaries.iter().map(|beneficiary| beneficiary.weight as u32).sum();
+            assert_eq!(
+                total_weight, MAX_TREASURY_PERCENTAGE as u32,
+                "Paras: Fee beneficiary weights should add up to {}",
+                MAX_TREASURY_PERCENTAGE
+            );
+        }
+        for (i, beneficiary) in fee_beneficiaries.iter().enumerate() {
+            assert!(
+                fee_beneficiaries[..i].iter().all(|other| other.account_id != beneficiary.account_id),
+                "Paras: Duplicate fee beneficiary {}",
+                beneficiary.account_id
+            );
         }
 
-        // fallback to default transaction fee
-        self.transaction_fee.current_fee as u128
+        self.internal_queue_admin_action(AdminAction::SetFeeBeneficiaries { fee_beneficiaries })
     }
 
     #[payable]
-    pub fn transfer_ownership(&mut self, owner_id: AccountId) {
+    pub fn set_admin_action_delay(&mut self, delay: U64) -> U64 {
         assert_one_yocto();
         self.assert_owner();
-        self.owner_id = owner_id;
+
+        assert!(
+            delay.0 >= MIN_ADMIN_ACTION_DELAY,
+            "Paras: Admin action delay is less than {}",
+            MIN_ADMIN_ACTION_DELAY
+        );
+
+        self.internal_queue_admin_action(AdminAction::SetAdminActionDelay { delay })
     }
 
-    // Approved contracts
     #[payable]
-    pub fn add_approved_nft_contract_ids(&mut self, nft_contract_ids: Vec<AccountId>) {
-        self.assert_owner();
-        add_accounts(Some(nft_contract_ids), &mut self.approved_nft_contract_ids);
+    pub fn execute_admin_action(&mut self, action_id: U64) {
+        assert_one_yocto();
+        let queued_action = self
+            .admin_actions
+            .get(&action_id.0)
+            .expect("Paras: Admin action does not exist");
+        self.assert_role(queued_action.action.required_role());
+        assert!(
+            env::block_timestamp() >= queued_action.executable_at.0,
+            "Paras: Admin action is executable at {}",
+            queued_action.executable_at.0
+        );
+
+        self.admin_actions.remove(&action_id.0);
+
+        match queued_action.action.clone() {
+            AdminAction::SetTreasury { treasury_id } => {
+                MarketEvent::SetTreasury(SetTreasuryLog {
+                    old_treasury_id: self.treasury_id.clone(),
+                    new_treasury_id: treasury_id.clone(),
+                })
+                .emit();
+
+                self.treasury_id = treasury_id;
+            }
+            AdminAction::SetTransactionFee { next_fee, start_time } => {
+                MarketEvent::SetTransactionFee(SetTransactionFeeLog {
+                    nft_contract_id: None,
+                    next_fee,
+                    start_time,
+                })
+                .emit();
+
+                self.transaction_fee.set(next_fee, start_time);
+            }
+            AdminAction::SetNftContractTransactionFee {
+                nft_contract_id,
+                next_fee,
+                start_time,
+            } => {
+                MarketEvent::SetTransactionFee(SetTransactionFeeLog {
+                    nft_contract_id: Some(nft_contract_id.clone()),
+                    next_fee,
+                    start_time,
+                })
+                .emit();
+
+                // a new override starts from the global fee until its scheduled fee applies
+                let mut transaction_fee = self
+                    .nft_contract_transaction_fees
+                    .get(&nft_contract_id)
+                    .unwrap_or(TransactionFee {
+                        next_fee: None,
+                        start_time: None,
+                        current_fee: self.transaction_fee.effective_fee(),
+                    });
+                transaction_fee.set(next_fee, start_time);
+                self.nft_contract_transaction_fees
+                    .insert(&nft_contract_id, &transaction_fee);
+            }
+            AdminAction::RemoveNftContractTransactionFee { nft_contract_id } => {
+                MarketEvent::RemoveNftContractTransactionFee(NftContractLog {
+                    nft_contract_id: nft_contract_id.clone(),
+                })
+                .emit();
+
+                self.nft_contract_transaction_fees.remove(&nft_contract_id);
+            }
+            AdminAction::SetReferralFeeShare { referral_fee_share } => {
+                self.referral_fee_share = referral_fee_share;
+            }
+            AdminAction::SetFeeBeneficiaries { fee_beneficiaries } => {
+                self.fee_beneficiaries = fee_beneficiaries;
+            }
+            AdminAction::SetAdminActionDelay { delay } => {
+                self.admin_action_delay = delay.0;
+            }
+        }
+
+        MarketEvent::ExecuteAdminAction(queued_action).emit();
     }
 
     #[payable]
-    pub fn remove_approved_nft_contract_ids(&mut self, nft_contract_ids: Vec<AccountId>) {
+    pub fn cancel_admin_action(&mut self, action_id: U64) {
+        assert_one_yocto();
+        let queued_action = self
+            .admin_actions
+            .get(&action_id.0)
+            .expect("Paras: Admin action does not exist");
+        if queued_action.proposer_id != env::predecessor_account_id() {
+            self.assert_role(Role::Admin);
+        }
+
+        self.admin_actions.remove(&action_id.0);
+
+        MarketEvent::CancelAdminAction(queued_action).emit();
+    }
+
+    pub fn get_admin_actions(&self) -> Vec<QueuedAdminAction> {
+        self.admin_actions.values().collect()
+    }
+
+    pub fn get_admin_action_delay(&self) -> U64 {
+        self.admin_action_delay.into()
+    }
+
+    /// a scheduled fee has to start after the action can be executed
+    fn assert_start_time_after_delay(&self, start_time: Option<TimestampSec>) {
+        if let Some(start_time) = start_time {
+            assert!(
+                start_time > to_sec(env::block_timestamp() + self.admin_action_delay),
+                "Paras: start_time is before the admin action can be executed"
+            );
+        }
+    }
+
+    fn internal_queue_admin_action(&mut self, action: AdminAction) -> U64 {
+        let action_id = self.next_admin_action_id;
+        self.next_admin_action_id += 1;
+
+        let queued_action = QueuedAdminAction {
+            action_id: action_id.into(),
+            action,
+            proposer_id: env::predecessor_account_id(),
+            executable_at: (env::block_timestamp() + self.admin_action_delay).into(),
+        };
+        self.admin_actions.insert(&action_id, &queued_action);
+
+        MarketEvent::QueueAdminAction(queued_action).emit();
+
+        action_id.into()
+    }
+
+    pub fn calculate_market_data_transaction_fee(&mut self, nft_contract_id: &AccountId, token_id: &TokenId) -> u128{
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        if let Some(transaction_fee) = self.market_data_transaction_fee.transaction_fee.get(&contract_and_token_id){
+            return transaction_fee;
+        }
+
+        // fallback to nft contract or default transaction fee
+        self.calculate_nft_contract_transaction_fee(nft_contract_id)
+    }
+
+    pub fn calculate_current_transaction_fee(&mut self) -> u128 {
+        self.transaction_fee.update();
+        self.transaction_fee.current_fee as u128
+    }
+
+    pub fn calculate_nft_contract_transaction_fee(&mut self, nft_contract_id: &AccountId) -> u128 {
+        if let Some(mut transaction_fee) = self.nft_contract_transaction_fees.get(nft_contract_id) {
+            transaction_fee.update();
+            self.nft_contract_transaction_fees.insert(nft_contract_id, &transaction_fee);
+            return transaction_fee.current_fee as u128;
+        }
+
+        self.calculate_current_transaction_fee()
+    }
+
+    pub fn get_transaction_fee(&self) -> &TransactionFee {
+        &self.transaction_fee
+    }
+
+    /// Current split of treasury fees
+    pub fn get_fee_beneficiaries(&self) -> Vec<FeeBeneficiary> {
+        if self.fee_beneficiaries.is_empty() {
+            return vec![FeeBeneficiary {
+                account_id: self.treasury_id.clone(),
+                weight: MAX_TREASURY_PERCENTAGE,
+            }];
+        }
+
+        self.fee_beneficiaries.clone()
+    }
+
+    pub fn get_referral_fee_share(&self) -> u16 {
+        self.referral_fee_share
+    }
+
+    pub fn get_nft_contract_transaction_fee(&self, nft_contract_id: AccountId) -> Option<TransactionFee> {
+        self.nft_contract_transaction_fees.get(&nft_contract_id)
+    }
+
+    pub fn get_nft_contract_transaction_fees(
+        &self,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<(AccountId, TransactionFee)> {
+        let start = u64::from(from_index.unwrap_or(U64(0)));
+        self.nft_contract_transaction_fees
+            .iter()
+            .skip(start as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .collect()
+    }
+
+    /// fee applied to new listings and offers of nft_contract_id
+    pub fn get_effective_transaction_fee(&self, nft_contract_id: AccountId) -> u16 {
+        self.nft_contract_transaction_fees
+            .get(&nft_contract_id)
+            .unwrap_or_else(|| self.transaction_fee.clone())
+            .effective_fee()
+    }
+
+    pub fn get_market_data_transaction_fee (&self, nft_contract_id: &AccountId, token_id: &TokenId) -> u128{
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        if let Some(transaction_fee) = self.market_data_transaction_fee.transaction_fee.get(&contract_and_token_id){
+            return transaction_fee;
+        }
+
+        // fallback to nft contract or default transaction fee
+        self.get_effective_transaction_fee(nft_contract_id.clone()) as u128
+    }
+
+    /// Proposes a new owner, the transfer completes when owner_id calls accept_ownership
+    #[payable]
+    pub fn transfer_ownership(&mut self, owner_id: AccountId) {
+        assert_one_yocto();
         self.assert_owner();
-        remove_accounts(Some(nft_contract_ids), &mut self.approved_nft_contract_ids);
+
+        MarketEvent::ProposeOwnership(TransferOwnershipLog {
+            old_owner_id: self.owner_id.clone(),
+            new_owner_id: owner_id.clone(),
+        })
+        .emit();
+
+        self.pending_owner_id = Some(owner_id);
+    }
+
+    #[payable]
+    pub fn accept_ownership(&mut self) {
+        assert_one_yocto();
+        let owner_id = env::predecessor_account_id();
+        assert_eq!(
+            self.pending_owner_id.as_ref(),
+            Some(&owner_id),
+            "Paras: Pending owner only"
+        );
+
+        MarketEvent::TransferOwnership(TransferOwnershipLog {
+            old_owner_id: self.owner_id.clone(),
+            new_owner_id: owner_id.clone(),
+        })
+        .emit();
+
+        self.owner_id = owner_id;
+        self.pending_owner_id = None;
+    }
+
+    // Roles
+
+    #[payable]
+    pub fn grant_role(&mut self, account_id: AccountId, role: Role) {
+        assert_one_yocto();
+        self.assert_role_manager(role);
+
+        let mut roles = self.roles.get(&account_id).unwrap_or_default();
+        if !roles.contains(&role) {
+            roles.push(role);
+            self.roles.insert(&account_id, &roles);
+
+            MarketEvent::GrantRole(RoleLog {
+                account_id,
+                role,
+                sender_id: env::predecessor_account_id(),
+            })
+            .emit();
+        }
+    }
+
+    #[payable]
+    pub fn revoke_role(&mut self, account_id: AccountId, role: Role) {
+        assert_one_yocto();
+        self.assert_role_manager(role);
+
+        if let Some(mut roles) = self.roles.get(&account_id) {
+            if roles.contains(&role) {
+                roles.retain(|r| *r != role);
+                if roles.is_empty() {
+                    self.roles.remove(&account_id);
+                } else {
+                    self.roles.insert(&account_id, &roles);
+                }
+
+                MarketEvent::RevokeRole(RoleLog {
+                    account_id,
+                    role,
+                    sender_id: env::predecessor_account_id(),
+                })
+                .emit();
+            }
+        }
+    }
+
+    // Pause
+
+    /// Pausing only stops new activity, withdrawals and refunds stay available
+    #[payable]
+    pub fn set_operation_paused(&mut self, operation: Operation, paused: bool) {
+        assert_one_yocto();
+        self.assert_role(Role::Pauser);
+
+        if paused {
+            self.paused_operations.insert(&operation);
+        } else {
+            self.paused_operations.remove(&operation);
+        }
+
+        MarketEvent::SetPaused(PauseLog {
+            operation: Some(operation),
+            nft_contract_id: None,
+            paused,
+        })
+        .emit();
+    }
+
+    #[payable]
+    pub fn set_nft_contract_paused(&mut self, nft_contract_id: AccountId, paused: bool) {
+        assert_one_yocto();
+        self.assert_role(Role::Pauser);
+
+        if paused {
+            self.paused_nft_contract_ids.insert(&nft_contract_id);
+        } else {
+            self.paused_nft_contract_ids.remove(&nft_contract_id);
+        }
+
+        MarketEvent::SetPaused(PauseLog {
+            operation: None,
+            nft_contract_id: Some(nft_contract_id),
+            paused,
+        })
+        .emit();
+    }
+
+    pub fn get_paused_operations(&self) -> Vec<Operation> {
+        self.paused_operations.to_vec()
+    }
+
+    pub fn get_paused_nft_contract_ids(&self) -> Vec<AccountId> {
+        self.paused_nft_contract_ids.to_vec()
+    }
+
+    pub fn get_roles(&self, account_id: AccountId) -> Vec<Role> {
+        self.roles.get(&account_id).unwrap_or_default()
+    }
+
+    pub fn get_role_members(&self, role: Role) -> Vec<AccountId> {
+        self.roles
+            .iter()
+            .filter(|(_, roles)| roles.contains(&role))
+            .map(|(account_id, _)| account_id)
+            .collect()
+    }
+
+    // Approved contracts
+    #[payable]
+    pub fn add_approved_nft_contract_ids(&mut self, nft_contract_ids: Vec<AccountId>) {
+        self.assert_role(Role::CollectionCurator);
+        add_accounts(Some(nft_contract_ids.clone()), &mut self.approved_nft_contract_ids);
+
+        MarketEvent::AddApprovedNftContractIds(AccountIdsLog {
+            account_ids: nft_contract_ids,
+        })
+        .emit();
+    }
+
+    #[payable]
+    pub fn remove_approved_nft_contract_ids(&mut self, nft_contract_ids: Vec<AccountId>) {
+        self.assert_role(Role::CollectionCurator);
+        remove_accounts(Some(nft_contract_ids.clone()), &mut self.approved_nft_contract_ids);
+
+        MarketEvent::RemoveApprovedNftContractIds(AccountIdsLog {
+            account_ids: nft_contract_ids,
+        })
+        .emit();
     }
 
     // Approved paras contracts
     #[payable]
     pub fn add_approved_paras_nft_contract_ids(&mut self, nft_contract_ids: Vec<AccountId>) {
-        self.assert_owner();
-        add_accounts(Some(nft_contract_ids), &mut self.paras_nft_contracts);
+        self.assert_role(Role::CollectionCurator);
+        add_accounts(Some(nft_contract_ids.clone()), &mut self.paras_nft_contracts);
+
+        MarketEvent::AddApprovedParasNftContractIds(AccountIdsLog {
+            account_ids: nft_contract_ids,
+        })
+        .emit();
     }
 
     #[payable]
     pub fn add_approved_ft_token_ids(&mut self, ft_token_ids: Vec<AccountId>) {
-        self.assert_owner();
-        add_accounts(Some(ft_token_ids), &mut self.approved_ft_token_ids);
+        self.assert_role(Role::Admin);
+        add_accounts(Some(ft_token_ids.clone()), &mut self.approved_ft_token_ids);
+
+        MarketEvent::AddApprovedFtTokenIds(AccountIdsLog {
+            account_ids: ft_token_ids,
+        })
+        .emit();
     }
 
     // Buy & Payment
@@ -395,7 +1461,9 @@ impl Contract {
         token_id: TokenId,
         ft_token_id: Option<AccountId>,
         price: Option<U128>,
+        referrer_id: Option<AccountId>,
     ) {
+        self.assert_not_paused(Operation::Buy, &nft_contract_id);
         let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
         let market_data: Option<MarketData> =
             if let Some(market_data) = self.old_market.get(&contract_and_token_id) {
@@ -429,14 +1497,22 @@ impl Contract {
             buyer_id, market_data.owner_id,
             "Paras: Cannot buy your own sale"
         );
-
-        // only NEAR supported for now
-        assert_eq!(
-            market_data.ft_token_id.to_string(),
-            NEAR,
-            "Paras: NEAR support only"
+        assert_valid_referrer(&referrer_id, &buyer_id);
+        assert!(
+            market_data.accept_nft_contract_id.is_none(),
+            "Paras: Listing only accepts an NFT as payment"
         );
 
+        // ft listings are paid through ft_transfer_call or from the internal balance
+        if market_data.ft_token_id.to_string() != NEAR {
+            assert_eq!(
+                env::attached_deposit(),
+                0,
+                "Paras: Use ft_transfer_call to buy with {}",
+                market_data.ft_token_id
+            );
+        }
+
         assert_eq!(is_auction, false, "Paras: the NFT is on auction");
 
         if ft_token_id.is_some() {
@@ -445,19 +1521,146 @@ impl Contract {
                 market_data.ft_token_id.to_string()
             )
         }
+        let current_time = env::block_timestamp();
+
+        if is_dutch_auction(&market_data) {
+            assert!(
+                current_time >= market_data.started_at.unwrap(),
+                "Paras: Sale has not started yet"
+            );
+
+            let current_price = calculate_current_price(&market_data, current_time);
+
+            // on dutch auction, price is the highest price the buyer accepts
+            if let Some(price) = price {
+                assert!(
+                    current_price <= price.0,
+                    "Paras: Current price is higher than {}",
+                    price.0
+                );
+            }
+
+            // overpayment is credited to the internal balance
+            let paid_from_balance =
+                self.internal_collect_payment(&buyer_id, &market_data.ft_token_id, current_price);
+
+            self.internal_process_purchase(
+                nft_contract_id,
+                token_id,
+                buyer_id,
+                current_price,
+                referrer_id,
+                paid_from_balance,
+                0,
+            );
+            return;
+        }
+
         if price.is_some() {
             assert_eq!(price.unwrap().0, market_data.price);
         }
 
+        let price = market_data.price;
+        let paid_from_balance = self.internal_collect_payment(&buyer_id, &market_data.ft_token_id, price);
+
+        self.internal_process_purchase(
+            nft_contract_id,
+            token_id,
+            buyer_id,
+            price,
+            referrer_id,
+            paid_from_balance,
+            0,
+        );
+    }
+
+    /// Collects amount from the attached deposit and tops it up from the internal balance,
+    /// a deposit above amount is credited to the internal balance.
+    /// Returns whether the internal balance was used.
+    fn internal_collect_payment(&mut self, account_id: &AccountId, ft_token_id: &AccountId, amount: Balance) -> bool {
+        let attached_deposit = env::attached_deposit();
+        if attached_deposit >= amount {
+            if attached_deposit > amount {
+                self.internal_add_claimable_balance(account_id, &near_account(), attached_deposit - amount);
+            }
+            return false;
+        }
+
+        self.internal_withdraw_claimable_balance(account_id, ft_token_id, amount - attached_deposit);
+        true
+    }
+
+    fn internal_buy_with_ft(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: AccountId,
+        buyer_id: AccountId,
+        amount: u128,
+        referrer_id: Option<AccountId>,
+    ) -> Promise {
+        self.assert_not_paused(Operation::Buy, &nft_contract_id);
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let market_data = self
+            .internal_get_market_data(&contract_and_token_id)
+            .expect("Paras: Market data does not exist");
+
+        assert_ne!(
+            buyer_id, market_data.owner_id,
+            "Paras: Cannot buy your own sale"
+        );
+        assert_valid_referrer(&referrer_id, &buyer_id);
+        assert!(
+            market_data.accept_nft_contract_id.is_none(),
+            "Paras: Listing only accepts an NFT as payment"
+        );
+
+        assert!(
+            !market_data.is_auction.unwrap_or(false),
+            "Paras: the NFT is on auction"
+        );
+
+        assert_eq!(
+            market_data.ft_token_id, ft_token_id,
+            "Paras: ft_token_id is not {}",
+            market_data.ft_token_id
+        );
+
+        if is_dutch_auction(&market_data) {
+            let current_time = env::block_timestamp();
+            assert!(
+                current_time >= market_data.started_at.unwrap(),
+                "Paras: Sale has not started yet"
+            );
+
+            let current_price = calculate_current_price(&market_data, current_time);
+            assert!(
+                amount >= current_price,
+                "Paras: The transferred amount is less than the current price {}",
+                current_price
+            );
+
+            // overpayment is returned as unused amount to ft_resolve_transfer
+            return self.internal_process_purchase(
+                nft_contract_id,
+                token_id,
+                buyer_id,
+                current_price,
+                referrer_id,
+                false,
+                amount - current_price,
+            );
+        }
+
         let price = market_data.price;
 
         assert_eq!(
-            env::attached_deposit(), price,
-            "Paras: The attached deposit should be exactly the price {}",
+            amount, price,
+            "Paras: The transferred amount should be exactly the price {}",
             price
         );
 
-        self.internal_process_purchase(nft_contract_id.into(), token_id, buyer_id, price);
+        self.internal_process_purchase(nft_contract_id, token_id, buyer_id, price, referrer_id, false, 0)
     }
 
     fn internal_process_purchase(
@@ -466,17 +1669,25 @@ impl Contract {
         token_id: TokenId,
         buyer_id: AccountId,
         price: u128,
+        referrer_id: Option<AccountId>,
+        paid_from_balance: bool,
+        overpaid_amount: u128,
     ) -> Promise {
+        // read before the listing and its locked fee are removed
+        let transaction_fee = self.calculate_market_data_transaction_fee(&nft_contract_id, &token_id);
         let market_data = self
             .internal_delete_market_data(&nft_contract_id, &token_id)
             .expect("Paras: Sale does not exist");
 
+        let max_len_payout = max_len_payout(&market_data.ft_token_id);
+        let gas_for_royalties = gas_for_royalties(&market_data.ft_token_id);
+
         ext_contract::nft_transfer_payout(
             buyer_id.clone(),
             token_id,
             Some(market_data.approval_id),
             Some(price.into()),
-            Some(50u32), // max length payout
+            Some(max_len_payout),
             nft_contract_id,
             1,
             GAS_FOR_NFT_TRANSFER,
@@ -485,151 +1696,137 @@ impl Contract {
             buyer_id,
             market_data,
             price.into(),
+            transaction_fee.into(),
+            referrer_id,
+            paid_from_balance,
+            overpaid_amount.into(),
             env::current_account_id(),
             NO_DEPOSIT,
-            GAS_FOR_ROYALTIES,
+            gas_for_royalties,
         ))
     }
 
+    /// Returns the unused amount for ft_resolve_transfer, overpaid_amount is part of it
     #[private]
     pub fn resolve_purchase(
         &mut self,
         buyer_id: AccountId,
         market_data: MarketData,
         price: U128,
+        transaction_fee: U128,
+        referrer_id: Option<AccountId>,
+        paid_from_balance: bool,
+        overpaid_amount: U128,
     ) -> U128 {
-        let payout_option = promise_result_as_success().and_then(|value| {
-            let parsed_payout = near_sdk::serde_json::from_slice::<PayoutHashMap>(&value);
-            if parsed_payout.is_err() {
-                near_sdk::serde_json::from_slice::<Payout>(&value)
-                    .ok()
-                    .and_then(|payout| {
-                        let mut remainder = price.0;
-                        for &value in payout.payout.values() {
-                            remainder = remainder.checked_sub(value.0)?;
-                        }
-                        if remainder <= 100 {
-                            Some(payout.payout)
-                        } else {
-                            None
-                        }
-                    })
-            } else {
-                parsed_payout
-                    .ok()
-                    .and_then(|payout| {
-                        let mut remainder = price.0;
-                        for &value in payout.values() {
-                            remainder = remainder.checked_sub(value.0)?;
-                        }
-                        if remainder <= 100 {
-                            Some(payout)
-                        } else {
-                            None
-                        }
-                    })
-            }
-        });
+        let payout_option = promise_result_as_success().and_then(|value| parse_payout(&value, price.0));
         let payout = if let Some(payout_option) = payout_option {
             payout_option
         } else {
             // leave function and return all FTs in ft_resolve_transfer
             if !is_promise_success() {
-                if market_data.ft_token_id == near_account() {
+                if paid_from_balance {
+                    self.internal_add_claimable_balance(&buyer_id, &market_data.ft_token_id, price.0);
+                } else if market_data.ft_token_id == near_account() {
                     self.internal_transfer_near(buyer_id.clone(), u128::from(price))
                 }
-                env::log_str(
-                    &json!({
-                    "type": "resolve_purchase_fail",
-                    "params": {
-                        "owner_id": market_data.owner_id,
-                        "nft_contract_id": market_data.nft_contract_id,
-                        "token_id": market_data.token_id,
-                        "ft_token_id": market_data.ft_token_id,
-                        "price": price,
-                        "buyer_id": buyer_id,
-                    }
+                MarketEvent::ResolvePurchaseFail(PurchaseLog {
+                    owner_id: market_data.owner_id,
+                    buyer_id,
+                    nft_contract_id: market_data.nft_contract_id,
+                    token_id: market_data.token_id,
+                    token_series_id: None,
+                    ft_token_id: market_data.ft_token_id,
+                    price,
+                    is_offer: false,
                 })
-                        .to_string(),
-                );
-            } else if market_data.ft_token_id == near_account() {
-                let treasury_fee = price.0 * self.calculate_market_data_transaction_fee(&market_data.nft_contract_id, &market_data.token_id) / (MAX_TREASURY_PERCENTAGE as u128);
-                let contract_and_token_id = format!("{}{}{}", &market_data.nft_contract_id, DELIMETER, &market_data.token_id);
-                self.market_data_transaction_fee.transaction_fee.remove(&contract_and_token_id);
-
-                let price_after = price.0.saturating_sub(treasury_fee);
-                if price_after > 0 {
-                    self.internal_transfer_near(market_data.owner_id.clone(), price_after);
-                }
-                if treasury_fee > 0 {
-                    self.internal_transfer_near(self.treasury_id.clone(), price.0.min(treasury_fee));
-                }
+                .emit();
+                return if paid_from_balance {
+                    U128(0)
+                } else {
+                    U128(price.0 + overpaid_amount.0)
+                };
+            }
 
-                env::log_str(
-                    &json!({
-                    "type": "resolve_purchase",
-                    "params": {
-                        "owner_id": &market_data.owner_id,
-                        "nft_contract_id": &market_data.nft_contract_id,
-                        "token_id": &market_data.token_id,
-                        "ft_token_id": market_data.ft_token_id,
-                        "price": price,
-                        "buyer_id": buyer_id,
-                    }
-                })
-                        .to_string(),
+            let treasury_fee = price.0 * transaction_fee.0 / (MAX_TREASURY_PERCENTAGE as u128);
+
+            let price_after = price.0.saturating_sub(treasury_fee);
+            if price_after > 0 {
+                self.internal_transfer(&market_data.ft_token_id, market_data.owner_id.clone(), price_after);
+            }
+            if treasury_fee > 0 {
+                self.internal_add_treasury_fee(
+                    &market_data.ft_token_id,
+                    price.0.min(treasury_fee),
+                    referrer_id,
+                    &buyer_id,
+                    &market_data.nft_contract_id,
+                    &market_data.token_id,
                 );
             }
-            return price;
+
+            MarketEvent::ResolvePurchase(PurchaseLog {
+                owner_id: market_data.owner_id.clone(),
+                buyer_id,
+                nft_contract_id: market_data.nft_contract_id.clone(),
+                token_id: market_data.token_id.clone(),
+                token_series_id: None,
+                ft_token_id: market_data.ft_token_id.clone(),
+                price,
+                is_offer: false,
+            })
+            .emit();
+
+            return unused_amount(&market_data.ft_token_id, price, overpaid_amount);
         };
 
         // Payout (transfer to royalties and seller)
-        if market_data.ft_token_id == near_account() {
-            let treasury_fee = (price.0 * self.calculate_market_data_transaction_fee(&market_data.nft_contract_id, &market_data.token_id)) / (MAX_TREASURY_PERCENTAGE as u128);
-            let contract_and_token_id = format!("{}{}{}", &market_data.nft_contract_id, DELIMETER, &market_data.token_id);
-            self.market_data_transaction_fee.transaction_fee.remove(&contract_and_token_id);
+        let treasury_fee = price.0 * transaction_fee.0 / (MAX_TREASURY_PERCENTAGE as u128);
 
-            for (receiver_id, amount) in payout {
-                if receiver_id == market_data.owner_id {
+        let mut transfers = vec![];
+        for (receiver_id, amount) in payout {
+            if receiver_id == market_data.owner_id {
 
-                    let amount_after = amount.0.saturating_sub(treasury_fee);
-                    if amount_after > 0 {
-                        self.internal_transfer_near(receiver_id, amount_after);
-                    }
+                let amount_after = amount.0.saturating_sub(treasury_fee);
+                if amount_after > 0 {
+                    transfers.push((receiver_id, amount_after));
+                }
 
-                    if treasury_fee > 0 {
-                        self.internal_transfer_near(self.treasury_id.clone(), amount.0.min(treasury_fee));
-                    }
-                } else {
-                    self.internal_transfer_near(receiver_id, amount.0);
+                if treasury_fee > 0 {
+                    self.internal_add_treasury_fee(
+                        &market_data.ft_token_id,
+                        amount.0.min(treasury_fee),
+                        referrer_id.clone(),
+                        &buyer_id,
+                        &market_data.nft_contract_id,
+                        &market_data.token_id,
+                    );
                 }
+            } else {
+                transfers.push((receiver_id, amount.0));
             }
-            env::log_str(
-                &json!({
-                    "type": "resolve_purchase",
-                    "params": {
-                        "owner_id": &market_data.owner_id,
-                        "nft_contract_id": &market_data.nft_contract_id,
-                        "token_id": &market_data.token_id,
-                        "ft_token_id": market_data.ft_token_id,
-                        "price": price,
-                        "buyer_id": buyer_id,
-                    }
-                })
-                .to_string(),
-            );
+        }
+        self.internal_transfer_payout(&market_data.ft_token_id, transfers);
 
-            let seller_contract_account_id_token_id = make_triple(
-                &market_data.nft_contract_id,
-                &market_data.owner_id,
-                &market_data.token_id,
-            );
-            self.trades.remove(&seller_contract_account_id_token_id);
+        MarketEvent::ResolvePurchase(PurchaseLog {
+            owner_id: market_data.owner_id.clone(),
+            buyer_id,
+            nft_contract_id: market_data.nft_contract_id.clone(),
+            token_id: market_data.token_id.clone(),
+            token_series_id: None,
+            ft_token_id: market_data.ft_token_id.clone(),
+            price,
+            is_offer: false,
+        })
+        .emit();
 
-            return price;
-        } else {
-            U128(0)
-        }
+        let seller_contract_account_id_token_id = make_triple(
+            &market_data.nft_contract_id,
+            &market_data.owner_id,
+            &market_data.token_id,
+        );
+        self.internal_remove_trade_list(&seller_contract_account_id_token_id, &market_data.owner_id);
+
+        unused_amount(&market_data.ft_token_id, price, overpaid_amount)
     }
 
     // Offer
@@ -643,6 +1840,7 @@ impl Contract {
         price: U128,
         buyer_id: AccountId,
     ) {
+        let initial_storage_usage = env::storage_usage();
         let token = if token_id.is_some() {
             token_id.as_ref().unwrap().to_string()
         } else {
@@ -654,7 +1852,7 @@ impl Contract {
             &contract_account_id_token_id,
             &OfferData {
                 buyer_id: buyer_id.clone().into(),
-                nft_contract_id: nft_contract_id.into(),
+                nft_contract_id: nft_contract_id.clone(),
                 token_id,
                 token_series_id,
                 ft_token_id: ft_token_id.into(),
@@ -662,17 +1860,39 @@ impl Contract {
             },
         );
 
-        let mut token_ids = self.by_owner_id.get(&buyer_id).unwrap_or_else(|| {
+        self.internal_add_by_owner_id(&buyer_id, &contract_account_id_token_id);
+
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token);
+        let mut offer_ids = self.offers_by_token.get(&contract_and_token_id).unwrap_or_else(|| {
             UnorderedSet::new(
-                StorageKey::ByOwnerIdInner {
-                    account_id_hash: hash_account_id(&buyer_id),
+                StorageKey::OffersByTokenInner {
+                    contract_and_token_id_hash: hash_contract_account_id_token_id(&contract_and_token_id),
                 }
                 .try_to_vec()
                 .unwrap(),
             )
         });
-        token_ids.insert(&contract_account_id_token_id);
-        self.by_owner_id.insert(&buyer_id, &token_ids);
+        offer_ids.insert(&contract_account_id_token_id);
+        self.offers_by_token.insert(&contract_and_token_id, &offer_ids);
+
+        // set offer transaction fee
+        let current_transaction_fee = self.calculate_nft_contract_transaction_fee(&nft_contract_id);
+        self.offer_transaction_fee.insert(&contract_account_id_token_id, &current_transaction_fee);
+
+        self.internal_update_storage_used(&buyer_id, initial_storage_usage);
+    }
+
+    fn calculate_offer_transaction_fee(
+        &mut self,
+        nft_contract_id: &AccountId,
+        contract_account_id_token_id: &ContractAccountIdTokenId,
+    ) -> u128 {
+        if let Some(transaction_fee) = self.offer_transaction_fee.get(contract_account_id_token_id) {
+            return transaction_fee;
+        }
+
+        // fallback to nft contract or default transaction fee
+        self.calculate_nft_contract_transaction_fee(nft_contract_id)
     }
 
     #[payable]
@@ -683,7 +1903,11 @@ impl Contract {
         token_series_id: Option<String>,
         ft_token_id: AccountId,
         price: U128,
+        expires_at: Option<U64>,
+        referrer_id: Option<AccountId>,
+        is_pooled: Option<bool>,
     ) {
+        self.assert_not_paused(Operation::Offer, &nft_contract_id);
         let token = if token_id.is_some() {
             token_id.as_ref().unwrap().to_string()
         } else {
@@ -694,12 +1918,6 @@ impl Contract {
             token_series_id.as_ref().unwrap().to_string()
         };
 
-        assert_eq!(
-            env::attached_deposit(),
-            price.0,
-            "Paras: Attached deposit != price"
-        );
-
         assert_eq!(
             ft_token_id.to_string(),
             "near",
@@ -711,7 +1929,17 @@ impl Contract {
             "Paras: nft_contract_id is not approved"
         );
 
+        if let Some(expires_at) = expires_at {
+            assert!(
+                expires_at.0 > env::block_timestamp(),
+                "Paras: Expires at should be in the future"
+            );
+        }
+
         let buyer_id = env::predecessor_account_id();
+        assert_valid_referrer(&referrer_id, &buyer_id);
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token);
+        let is_previous_offer_pooled = self.pooled_offers.contains(&contract_account_id_token_id);
         let offer_data = self.internal_delete_offer(
             nft_contract_id.clone().into(),
             buyer_id.clone(),
@@ -720,21 +1948,24 @@ impl Contract {
 
         if let Some(offer) = offer_data{
             // refund previous offer
-            self.internal_transfer_near(buyer_id.clone(), offer.price);
+            if !is_previous_offer_pooled {
+                self.internal_add_claimable_balance(&buyer_id, &near_account(), offer.price);
+            }
         }
-  
-        let storage_amount = self.storage_minimum_balance().0;
-        let owner_paid_storage = self.storage_deposits.get(&buyer_id).unwrap_or(0);
-        let signer_storage_required =
-            (self.get_supply_by_owner_id(buyer_id.clone()).0 + 1) as u128 * storage_amount;
 
-        assert!(
-            owner_paid_storage >= signer_storage_required,
-            "Insufficient storage paid: {}, for {} offer at {} rate of per offer",
-            owner_paid_storage,
-            signer_storage_required / storage_amount,
-            storage_amount,
-        );
+        let is_pooled = is_pooled.unwrap_or(false);
+        if is_pooled {
+            // pooled offers draw on the internal balance when they are accepted
+            if env::attached_deposit() > 0 {
+                self.internal_add_claimable_balance(&buyer_id, &near_account(), env::attached_deposit());
+            }
+            assert!(
+                self.get_claimable_balance(buyer_id.clone(), near_account()).0 >= price.0,
+                "Paras: Offer pool does not cover the price"
+            );
+        } else {
+            self.internal_collect_payment(&buyer_id, &near_account(), price.0);
+        }
 
         self.internal_add_offer(
             nft_contract_id.clone().into(),
@@ -745,20 +1976,37 @@ impl Contract {
             buyer_id.clone(),
         );
 
-        env::log_str(
-            &json!({
-                "type": "add_offer",
-                "params": {
-                    "buyer_id": buyer_id,
-                    "nft_contract_id": nft_contract_id,
-                    "token_id": token_id,
-                    "token_series_id": token_series_id,
-                    "ft_token_id": ft_token_id,
-                    "price": price,
-                }
-            })
-            .to_string(),
-        );
+        if let Some(expires_at) = expires_at {
+            let initial_storage_usage = env::storage_usage();
+            self.offer_expires_at.insert(&contract_account_id_token_id, &expires_at.0);
+            self.internal_update_storage_used(&buyer_id, initial_storage_usage);
+        }
+
+        if let Some(referrer_id) = referrer_id.as_ref() {
+            let initial_storage_usage = env::storage_usage();
+            self.offer_referrer_ids.insert(&contract_account_id_token_id, referrer_id);
+            self.internal_update_storage_used(&buyer_id, initial_storage_usage);
+        }
+
+        if is_pooled {
+            let initial_storage_usage = env::storage_usage();
+            self.pooled_offers.insert(&contract_account_id_token_id);
+            self.internal_update_storage_used(&buyer_id, initial_storage_usage);
+        }
+
+        self.assert_storage_covered(&buyer_id);
+
+        MarketEvent::AddOffer(AddOfferLog {
+            buyer_id,
+            nft_contract_id,
+            token_id,
+            token_series_id,
+            ft_token_id,
+            price,
+            expires_at,
+            is_pooled,
+        })
+        .emit();
     }
 
     fn internal_delete_offer(
@@ -767,22 +2015,31 @@ impl Contract {
         buyer_id: AccountId,
         token_id: TokenId,
     ) -> Option<OfferData> {
+        let initial_storage_usage = env::storage_usage();
         let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
         let offer_data = self.offers.remove(&contract_account_id_token_id);
+        self.offer_expires_at.remove(&contract_account_id_token_id);
+        self.offer_transaction_fee.remove(&contract_account_id_token_id);
+        self.offer_referrer_ids.remove(&contract_account_id_token_id);
+        self.pooled_offers.remove(&contract_account_id_token_id);
 
         match offer_data {
             Some(offer) => {
-                let by_owner_id = self
-                    .by_owner_id
-                    .get(&offer.buyer_id);
-                if let Some(mut by_owner_id) = by_owner_id {
-                    by_owner_id.remove(&contract_account_id_token_id);
-                    if by_owner_id.is_empty() {
-                        self.by_owner_id.remove(&offer.buyer_id);
+                let is_legacy = self.internal_remove_by_owner_id(&offer.buyer_id, &contract_account_id_token_id);
+
+                let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+                if let Some(mut offer_ids) = self.offers_by_token.get(&contract_and_token_id) {
+                    offer_ids.remove(&contract_account_id_token_id);
+                    if offer_ids.is_empty() {
+                        self.offers_by_token.remove(&contract_and_token_id);
                     } else {
-                        self.by_owner_id.insert(&offer.buyer_id, &by_owner_id);
+                        self.offers_by_token.insert(&contract_and_token_id, &offer_ids);
                     }
                 }
+
+                if !is_legacy {
+                    self.internal_update_storage_used(&offer.buyer_id, initial_storage_usage);
+                }
                 return Some(offer);
             }
             None => return None,
@@ -822,6 +2079,7 @@ impl Contract {
             "Paras: Caller not offer's buyer"
         );
 
+        let is_pooled = self.pooled_offers.contains(&contract_account_id_token_id);
         self.internal_delete_offer(
             nft_contract_id.clone().into(),
             buyer_id.clone(),
@@ -829,20 +2087,18 @@ impl Contract {
         )
         .expect("Paras: Offer not found");
 
-        self.internal_transfer_near(offer_data.buyer_id, offer_data.price);
+        if !is_pooled {
+            self.internal_transfer_near(offer_data.buyer_id, offer_data.price);
+        }
 
-        env::log_str(
-            &json!({
-                "type": "delete_offer",
-                "params": {
-                    "nft_contract_id": nft_contract_id,
-                    "buyer_id": buyer_id,
-                    "token_id": token_id,
-                    "token_series_id": token_series_id,
-                }
-            })
-            .to_string(),
-        );
+        MarketEvent::DeleteOffer(DeleteOfferLog {
+            buyer_id,
+            nft_contract_id,
+            token_id,
+            token_series_id,
+            is_expired: false,
+        })
+        .emit();
     }
 
     pub fn get_offer(
@@ -871,6 +2127,19 @@ impl Contract {
             assert_eq!(offer_data.token_series_id.as_ref().unwrap(), token);
         }
 
+        self.internal_offer_data_json(&contract_account_id_token_id, offer_data)
+    }
+
+    fn internal_offer_data_json(
+        &self,
+        contract_account_id_token_id: &ContractAccountIdTokenId,
+        offer_data: OfferData,
+    ) -> OfferDataJson {
+        let transaction_fee = self
+            .offer_transaction_fee
+            .get(contract_account_id_token_id)
+            .unwrap_or_else(|| self.get_effective_transaction_fee(offer_data.nft_contract_id.clone()) as u128);
+
         OfferDataJson {
             buyer_id: offer_data.buyer_id,
             nft_contract_id: offer_data.nft_contract_id,
@@ -878,80 +2147,172 @@ impl Contract {
             token_series_id: offer_data.token_series_id,
             ft_token_id: offer_data.ft_token_id,
             price: U128(offer_data.price),
+            expires_at: self.offer_expires_at.get(contract_account_id_token_id).map(|x| x.into()),
+            transaction_fee: transaction_fee.into(),
+            referrer_id: self.offer_referrer_ids.get(contract_account_id_token_id),
+            is_pooled: self.pooled_offers.contains(contract_account_id_token_id),
         }
     }
 
-    fn internal_update_approval_id(&mut self, approval_id: &u64, nft_contract_id: &AccountId, account_id: &AccountId, token_id: &TokenId){
-        let contract_account_id_token_id = make_triple(&nft_contract_id, &account_id, &token_id);
-        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+    // Collection offer
 
-        if let Some(mut trade_data) = self.trades.get(&contract_account_id_token_id){
-            trade_data.approval_id = approval_id.clone();
-            self.trades.insert(&contract_account_id_token_id, &trade_data);
+    #[payable]
+    pub fn add_collection_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        ft_token_id: AccountId,
+        price: U128,
+        quantity: u32,
+        expires_at: Option<U64>,
+    ) {
+        self.assert_not_paused(Operation::Offer, &nft_contract_id);
+        assert!(quantity > 0, "Paras: Quantity should be more than 0");
+
+        if let Some(expires_at) = expires_at {
+            assert!(
+                expires_at.0 > env::block_timestamp(),
+                "Paras: Expires at should be in the future"
+            );
         }
 
-        if let Some(mut market_data) = self.market.get(&contract_and_token_id){
-            market_data.approval_id = approval_id.clone();
-            self.market.insert(&contract_and_token_id, &market_data);
+        assert!(
+            price.0 < MAX_PRICE,
+            "Paras: price higher than {}",
+            MAX_PRICE
+        );
+
+        assert_eq!(
+            ft_token_id.to_string(),
+            "near",
+            "Paras: Only NEAR is supported"
+        );
+
+        assert!(
+            self.approved_nft_contract_ids.contains(&nft_contract_id),
+            "Paras: nft_contract_id is not approved"
+        );
+
+        let buyer_id = env::predecessor_account_id();
+        let collection_offer_data =
+            self.internal_delete_collection_offer(nft_contract_id.clone(), buyer_id.clone());
+
+        if let Some(collection_offer) = collection_offer_data {
+            // refund previous offer
+            self.internal_add_claimable_balance(
+                &buyer_id,
+                &near_account(),
+                collection_offer.price * collection_offer.quantity as u128,
+            );
+        }
+
+        self.internal_collect_payment(&buyer_id, &near_account(), price.0 * quantity as u128);
+
+        let initial_storage_usage = env::storage_usage();
+        let contract_account_id = make_key_collection_offer(&nft_contract_id, &buyer_id);
+        self.collection_offers.insert(
+            &contract_account_id,
+            &CollectionOfferData {
+                buyer_id: buyer_id.clone(),
+                nft_contract_id: nft_contract_id.clone(),
+                ft_token_id: ft_token_id.clone(),
+                price: price.into(),
+                quantity,
+            },
+        );
+
+        if let Some(expires_at) = expires_at {
+            self.offer_expires_at.insert(&contract_account_id, &expires_at.0);
         }
+
+        // lock the transaction fee for every token bought by the offer
+        let current_transaction_fee = self.calculate_nft_contract_transaction_fee(&nft_contract_id);
+        self.offer_transaction_fee.insert(&contract_account_id, &current_transaction_fee);
+
+        self.internal_add_by_owner_id(&buyer_id, &make_key_owner_by_id_collection_offer(contract_account_id));
+
+        self.internal_update_storage_used(&buyer_id, initial_storage_usage);
+        self.assert_storage_covered(&buyer_id);
+
+        MarketEvent::AddCollectionOffer(AddCollectionOfferLog {
+            buyer_id,
+            nft_contract_id,
+            ft_token_id,
+            price,
+            quantity,
+            expires_at,
+        })
+        .emit();
     }
 
-    fn internal_accept_offer(
+    fn internal_delete_collection_offer(
         &mut self,
         nft_contract_id: AccountId,
         buyer_id: AccountId,
-        token_id: TokenId,
-        seller_id: AccountId,
-        approval_id: u64,
-        price: u128,
-    ) -> PromiseOrValue<bool>{
-        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
-        let offer_data_raw = self.offers.get(&contract_account_id_token_id); 
+    ) -> Option<CollectionOfferData> {
+        let initial_storage_usage = env::storage_usage();
+        let contract_account_id = make_key_collection_offer(&nft_contract_id, &buyer_id);
+        let collection_offer_data = self.collection_offers.remove(&contract_account_id);
+        self.offer_expires_at.remove(&contract_account_id);
+        self.offer_transaction_fee.remove(&contract_account_id);
 
-        if offer_data_raw.is_none() {
-            self.internal_update_approval_id(&approval_id, &nft_contract_id, &seller_id, &token_id);
-            env::log_str("Paras: Offer does not exist");
-            return PromiseOrValue::Value(false);
-        }
+        if collection_offer_data.is_some() {
+            self.internal_remove_by_owner_id(&buyer_id, &make_key_owner_by_id_collection_offer(contract_account_id));
 
-        self.internal_delete_market_data(&nft_contract_id, &token_id);
+            self.internal_update_storage_used(&buyer_id, initial_storage_usage);
+        }
 
-        let offer_data = offer_data_raw.unwrap();
+        collection_offer_data
+    }
 
-        assert_eq!(offer_data.token_id.as_ref().unwrap(), &token_id);
-        assert_eq!(offer_data.price, price);
+    #[payable]
+    pub fn delete_collection_offer(&mut self, nft_contract_id: AccountId) {
+        assert_one_yocto();
+        let buyer_id = env::predecessor_account_id();
 
-        let offer_data = self
-            .internal_delete_offer(
-                nft_contract_id.clone().into(),
-                buyer_id.clone(),
-                token_id.clone(),
-            )
+        let collection_offer = self
+            .internal_delete_collection_offer(nft_contract_id.clone(), buyer_id.clone())
             .expect("Paras: Offer does not exist");
 
-        PromiseOrValue::Promise(
-            ext_contract::nft_transfer_payout(
-                offer_data.buyer_id.clone(),
-                token_id.clone(),
-                Some(approval_id),
-                Some(U128::from(offer_data.price)),
-                Some(50u32), // max length payout
-                nft_contract_id,
-                1,
-                GAS_FOR_NFT_TRANSFER,
-            )
-            .then(ext_self::resolve_offer(
-                seller_id,
-                offer_data,
-                token_id,
-                env::current_account_id(),
-                NO_DEPOSIT,
-                GAS_FOR_ROYALTIES,
-            ))
-        )
+        self.internal_transfer_near(
+            collection_offer.buyer_id,
+            collection_offer.price * collection_offer.quantity as u128,
+        );
+
+        MarketEvent::DeleteCollectionOffer(DeleteCollectionOfferLog {
+            buyer_id,
+            nft_contract_id,
+            is_expired: false,
+        })
+        .emit();
     }
 
-    fn internal_accept_offer_series(
+    pub fn get_collection_offer(
+        &self,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+    ) -> CollectionOfferDataJson {
+        let contract_account_id = make_key_collection_offer(&nft_contract_id, &buyer_id);
+        let collection_offer = self
+            .collection_offers
+            .get(&contract_account_id)
+            .expect("Paras: Offer does not exist");
+        let transaction_fee = self
+            .offer_transaction_fee
+            .get(&contract_account_id)
+            .unwrap_or_else(|| self.get_effective_transaction_fee(collection_offer.nft_contract_id.clone()) as u128);
+
+        CollectionOfferDataJson {
+            buyer_id: collection_offer.buyer_id,
+            nft_contract_id: collection_offer.nft_contract_id,
+            ft_token_id: collection_offer.ft_token_id,
+            price: U128(collection_offer.price),
+            quantity: collection_offer.quantity,
+            expires_at: self.offer_expires_at.get(&contract_account_id).map(|x| x.into()),
+            transaction_fee: transaction_fee.into(),
+        }
+    }
+
+    fn internal_accept_collection_offer(
         &mut self,
         nft_contract_id: AccountId,
         buyer_id: AccountId,
@@ -960,35 +2321,55 @@ impl Contract {
         approval_id: u64,
         price: u128,
     ) -> PromiseOrValue<bool> {
-        // Token delimiter : is specific for Paras NFT
-        let mut token_id_iter = token_id.split(":");
-        let token_series_id: String = token_id_iter.next().unwrap().parse().unwrap();
-        let contract_account_id_token_id =
-            make_triple(&nft_contract_id, &buyer_id, &token_series_id);
+        let contract_account_id = make_key_collection_offer(&nft_contract_id, &buyer_id);
+        let collection_offer_raw = self.collection_offers.get(&contract_account_id);
 
-        let offer_data_raw = self.offers.get(&contract_account_id_token_id);
-        if offer_data_raw.is_none() {
+        if collection_offer_raw.is_none() {
             self.internal_update_approval_id(&approval_id, &nft_contract_id, &seller_id, &token_id);
             env::log_str("Paras: Offer does not exist");
             return PromiseOrValue::Value(false);
         }
 
-        self.internal_delete_market_data(&nft_contract_id, &token_id);
+        if self.internal_is_offer_expired(&contract_account_id) {
+            self.internal_update_approval_id(&approval_id, &nft_contract_id, &seller_id, &token_id);
+            env::log_str("Paras: Offer has expired");
+            return PromiseOrValue::Value(false);
+        }
 
-        let offer_data = offer_data_raw.unwrap(); 
+        let mut collection_offer = collection_offer_raw.unwrap();
 
+        assert_ne!(seller_id, buyer_id, "Paras: Cannot accept your own offer");
         assert_eq!(
-            offer_data.token_series_id.as_ref().unwrap(),
-            &token_series_id
+            collection_offer.price, price,
+            "Paras: Collection offer price is {}",
+            collection_offer.price
         );
-        assert_eq!(offer_data.price, price);
 
-        self.internal_delete_offer(
-            nft_contract_id.clone().into(),
-            buyer_id.clone(),
-            token_series_id.clone(),
-        )
-        .expect("Paras: Offer does not exist");
+        self.internal_delete_market_data(&nft_contract_id, &token_id);
+
+        // read before the offer and its locked fee are removed
+        let transaction_fee = self.calculate_offer_transaction_fee(&nft_contract_id, &contract_account_id);
+
+        // one unit of the escrow is used per accepted token
+        collection_offer.quantity -= 1;
+        if collection_offer.quantity == 0 {
+            self.internal_delete_collection_offer(nft_contract_id.clone(), buyer_id.clone());
+        } else {
+            self.collection_offers.insert(&contract_account_id, &collection_offer);
+        }
+
+        let referrer_id: Option<AccountId> = None;
+        let offer_data = OfferData {
+            buyer_id: collection_offer.buyer_id,
+            nft_contract_id: nft_contract_id.clone(),
+            token_id: Some(token_id.clone()),
+            token_series_id: None,
+            ft_token_id: collection_offer.ft_token_id,
+            price: collection_offer.price,
+        };
+
+        let max_len_payout = max_len_payout(&offer_data.ft_token_id);
+        let gas_for_royalties = gas_for_royalties(&offer_data.ft_token_id);
 
         PromiseOrValue::Promise(
             ext_contract::nft_transfer_payout(
@@ -996,7 +2377,7 @@ impl Contract {
                 token_id.clone(),
                 Some(approval_id),
                 Some(U128::from(offer_data.price)),
-                Some(50u32), // max length payout
+                Some(max_len_payout),
                 nft_contract_id,
                 1,
                 GAS_FOR_NFT_TRANSFER,
@@ -1005,152 +2386,399 @@ impl Contract {
                 seller_id,
                 offer_data,
                 token_id,
+                U128(transaction_fee),
+                referrer_id,
+                false,
                 env::current_account_id(),
                 NO_DEPOSIT,
-                GAS_FOR_ROYALTIES,
+                gas_for_royalties,
             ))
         )
     }
 
-    #[private]
-    pub fn resolve_offer(
-        &mut self,
-        seller_id: AccountId,
-        offer_data: OfferData,
-        token_id: TokenId,
-    ) -> U128 {
-        let payout_option = promise_result_as_success().and_then(|value| {
-            // None means a bad payout from bad NFT contract
-            let parsed_payout = near_sdk::serde_json::from_slice::<PayoutHashMap>(&value);
-            if parsed_payout.is_err() {
-                near_sdk::serde_json::from_slice::<Payout>(&value)
-                    .ok()
-                    .and_then(|payout| {
-                        let mut remainder = offer_data.price;
-                        for &value in payout.payout.values() {
-                            remainder = remainder.checked_sub(value.0)?;
-                        }
-                        if remainder <= 100 {
-                            Some(payout.payout)
-                        } else {
-                            None
-                        }
-                    })
-            } else {
-                parsed_payout.ok().and_then(|payout| {
-                    let mut remainder = offer_data.price;
-                    for &value in payout.values() {
-                        remainder = remainder.checked_sub(value.0)?;
-                    }
-                    if remainder <= 100 {
-                        Some(payout)
-                    } else {
-                        None
-                    }
-                })
-            }
-        });
+    // Pooled offers
 
+    /// pooled offers can only be accepted while the internal balance of the buyer covers them
+    fn internal_is_offer_covered(&self, offer_key: &String, offer_data: &OfferData) -> bool {
+        !self.pooled_offers.contains(offer_key)
+            || self.get_claimable_balance(offer_data.buyer_id.clone(), near_account()).0 >= offer_data.price
+    }
+
+    // Expired offers
+
+    fn internal_is_offer_expired(&self, offer_key: &String) -> bool {
+        matches!(
+            self.offer_expires_at.get(offer_key),
+            Some(expires_at) if env::block_timestamp() >= expires_at
+        )
+    }
+
+    /// Refunds expired offers to their buyers, callable by anyone. Checks up to limit entries
+    /// and returns the index to continue from, which is past the end once every entry is checked.
+    pub fn refund_expired_offers(&mut self, from_index: Option<U64>, limit: Option<u64>) -> U64 {
+        let mut index: u64 = from_index.map(From::from).unwrap_or_default();
+        let limit = limit.unwrap_or(50);
+        let current_time = env::block_timestamp();
+
+        for _ in 0..limit {
+            if index >= self.offer_expires_at.len() {
+                break;
+            }
+            let offer_key = self.offer_expires_at.keys_as_vector().get(index).unwrap();
+            let expires_at = self.offer_expires_at.values_as_vector().get(index).unwrap();
+            // removing swaps the last entry into index, so only advance past the ones kept
+            if current_time < expires_at {
+                index += 1;
+                continue;
+            }
+
+            let offer_key = &offer_key;
+            if let Some(offer_data) = self.offers.get(offer_key) {
+                let token = offer_data
+                    .token_id
+                    .clone()
+                    .or(offer_data.token_series_id.clone())
+                    .unwrap();
+                let is_pooled = self.pooled_offers.contains(offer_key);
+                self.internal_delete_offer(
+                    offer_data.nft_contract_id.clone(),
+                    offer_data.buyer_id.clone(),
+                    token.clone(),
+                );
+                if !is_pooled {
+                    self.internal_transfer(&offer_data.ft_token_id, offer_data.buyer_id.clone(), offer_data.price);
+                }
+
+                MarketEvent::DeleteOffer(DeleteOfferLog {
+                    buyer_id: offer_data.buyer_id,
+                    nft_contract_id: offer_data.nft_contract_id,
+                    token_id: offer_data.token_id,
+                    token_series_id: offer_data.token_series_id,
+                    is_expired: true,
+                })
+                .emit();
+            } else if let Some(collection_offer) = self.collection_offers.get(offer_key) {
+                self.internal_delete_collection_offer(
+                    collection_offer.nft_contract_id.clone(),
+                    collection_offer.buyer_id.clone(),
+                );
+                self.internal_transfer(
+                    &collection_offer.ft_token_id,
+                    collection_offer.buyer_id.clone(),
+                    collection_offer.price * collection_offer.quantity as u128,
+                );
+
+                MarketEvent::DeleteCollectionOffer(DeleteCollectionOfferLog {
+                    buyer_id: collection_offer.buyer_id,
+                    nft_contract_id: collection_offer.nft_contract_id,
+                    is_expired: true,
+                })
+                .emit();
+            } else {
+                self.offer_expires_at.remove(offer_key);
+            }
+        }
+
+        index.into()
+    }
+
+    fn internal_update_approval_id(&mut self, approval_id: &u64, nft_contract_id: &AccountId, account_id: &AccountId, token_id: &TokenId){
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &account_id, &token_id);
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+
+        if let Some(mut trade_data) = self.trades.get(&contract_account_id_token_id){
+            trade_data.approval_id = approval_id.clone();
+            self.trades.insert(&contract_account_id_token_id, &trade_data);
+        }
+
+        if let Some(mut market_data) = self.market.get(&contract_and_token_id){
+            market_data.approval_id = approval_id.clone();
+            self.market.insert(&contract_and_token_id, &market_data);
+        }
+    }
+
+    fn internal_accept_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+        seller_id: AccountId,
+        approval_id: u64,
+        price: u128,
+    ) -> PromiseOrValue<bool>{
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
+        let offer_data_raw = self.offers.get(&contract_account_id_token_id); 
+
+        if offer_data_raw.is_none() {
+            self.internal_update_approval_id(&approval_id, &nft_contract_id, &seller_id, &token_id);
+            env::log_str("Paras: Offer does not exist");
+            return PromiseOrValue::Value(false);
+        }
+
+        if self.internal_is_offer_expired(&contract_account_id_token_id) {
+            self.internal_update_approval_id(&approval_id, &nft_contract_id, &seller_id, &token_id);
+            env::log_str("Paras: Offer has expired");
+            return PromiseOrValue::Value(false);
+        }
+
+        if !self.internal_is_offer_covered(&contract_account_id_token_id, offer_data_raw.as_ref().unwrap()) {
+            self.internal_update_approval_id(&approval_id, &nft_contract_id, &seller_id, &token_id);
+            env::log_str("Paras: Offer pool does not cover the offer");
+            return PromiseOrValue::Value(false);
+        }
+
+        self.internal_delete_market_data(&nft_contract_id, &token_id);
+
+        let offer_data = offer_data_raw.unwrap();
+
+        assert_eq!(offer_data.token_id.as_ref().unwrap(), &token_id);
+        assert_eq!(offer_data.price, price);
+
+        let transaction_fee =
+            self.calculate_offer_transaction_fee(&nft_contract_id, &contract_account_id_token_id);
+        let referrer_id = self.offer_referrer_ids.get(&contract_account_id_token_id);
+        let is_pooled = self.pooled_offers.contains(&contract_account_id_token_id);
+
+        let offer_data = self
+            .internal_delete_offer(
+                nft_contract_id.clone().into(),
+                buyer_id.clone(),
+                token_id.clone(),
+            )
+            .expect("Paras: Offer does not exist");
+
+        if is_pooled {
+            self.internal_withdraw_claimable_balance(&buyer_id, &near_account(), offer_data.price);
+        }
+
+        let max_len_payout = max_len_payout(&offer_data.ft_token_id);
+        let gas_for_royalties = gas_for_royalties(&offer_data.ft_token_id);
+
+        PromiseOrValue::Promise(
+            ext_contract::nft_transfer_payout(
+                offer_data.buyer_id.clone(),
+                token_id.clone(),
+                Some(approval_id),
+                Some(U128::from(offer_data.price)),
+                Some(max_len_payout),
+                nft_contract_id,
+                1,
+                GAS_FOR_NFT_TRANSFER,
+            )
+            .then(ext_self::resolve_offer(
+                seller_id,
+                offer_data,
+                token_id,
+                U128(transaction_fee),
+                referrer_id,
+                is_pooled,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                gas_for_royalties,
+            ))
+        )
+    }
+
+    fn internal_accept_offer_series(
+        &mut self,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+        seller_id: AccountId,
+        approval_id: u64,
+        price: u128,
+    ) -> PromiseOrValue<bool> {
+        // Token delimiter : is specific for Paras NFT
+        let mut token_id_iter = token_id.split(":");
+        let token_series_id: String = token_id_iter.next().unwrap().parse().unwrap();
+        let contract_account_id_token_id =
+            make_triple(&nft_contract_id, &buyer_id, &token_series_id);
+
+        let offer_data_raw = self.offers.get(&contract_account_id_token_id);
+        if offer_data_raw.is_none() {
+            self.internal_update_approval_id(&approval_id, &nft_contract_id, &seller_id, &token_id);
+            env::log_str("Paras: Offer does not exist");
+            return PromiseOrValue::Value(false);
+        }
+
+        if self.internal_is_offer_expired(&contract_account_id_token_id) {
+            self.internal_update_approval_id(&approval_id, &nft_contract_id, &seller_id, &token_id);
+            env::log_str("Paras: Offer has expired");
+            return PromiseOrValue::Value(false);
+        }
+
+        if !self.internal_is_offer_covered(&contract_account_id_token_id, offer_data_raw.as_ref().unwrap()) {
+            self.internal_update_approval_id(&approval_id, &nft_contract_id, &seller_id, &token_id);
+            env::log_str("Paras: Offer pool does not cover the offer");
+            return PromiseOrValue::Value(false);
+        }
+
+        self.internal_delete_market_data(&nft_contract_id, &token_id);
+
+        let offer_data = offer_data_raw.unwrap(); 
+
+        assert_eq!(
+            offer_data.token_series_id.as_ref().unwrap(),
+            &token_series_id
+        );
+        assert_eq!(offer_data.price, price);
+
+        let transaction_fee =
+            self.calculate_offer_transaction_fee(&nft_contract_id, &contract_account_id_token_id);
+        let referrer_id = self.offer_referrer_ids.get(&contract_account_id_token_id);
+        let is_pooled = self.pooled_offers.contains(&contract_account_id_token_id);
+
+        self.internal_delete_offer(
+            nft_contract_id.clone().into(),
+            buyer_id.clone(),
+            token_series_id.clone(),
+        )
+        .expect("Paras: Offer does not exist");
+
+        if is_pooled {
+            self.internal_withdraw_claimable_balance(&buyer_id, &near_account(), offer_data.price);
+        }
+
+        let max_len_payout = max_len_payout(&offer_data.ft_token_id);
+        let gas_for_royalties = gas_for_royalties(&offer_data.ft_token_id);
+
+        PromiseOrValue::Promise(
+            ext_contract::nft_transfer_payout(
+                offer_data.buyer_id.clone(),
+                token_id.clone(),
+                Some(approval_id),
+                Some(U128::from(offer_data.price)),
+                Some(max_len_payout),
+                nft_contract_id,
+                1,
+                GAS_FOR_NFT_TRANSFER,
+            )
+            .then(ext_self::resolve_offer(
+                seller_id,
+                offer_data,
+                token_id,
+                U128(transaction_fee),
+                referrer_id,
+                is_pooled,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                gas_for_royalties,
+            ))
+        )
+    }
+
+    #[private]
+    pub fn resolve_offer(
+        &mut self,
+        seller_id: AccountId,
+        offer_data: OfferData,
+        token_id: TokenId,
+        transaction_fee: U128,
+        referrer_id: Option<AccountId>,
+        paid_from_balance: bool,
+    ) -> U128 {
+        // None means a bad payout from bad NFT contract
+        let payout_option = promise_result_as_success().and_then(|value| parse_payout(&value, offer_data.price));
         let payout = if let Some(payout_option) = payout_option {
             payout_option
         } else {
             if !is_promise_success() {
-                if offer_data.ft_token_id == near_account() {
-                    self.internal_transfer_near(offer_data.buyer_id.clone(), u128::from(offer_data.price));
-                    env::log_str(
-                        &json!({
-                    "type": "resolve_purchase_fail",
-                    "params": {
-                        "owner_id": seller_id,
-                        "nft_contract_id": offer_data.nft_contract_id,
-                        "token_id": token_id,
-                        "token_series_id": offer_data.token_series_id,
-                        "ft_token_id": offer_data.ft_token_id,
-                        "price": offer_data.price.to_string(),
-                        "buyer_id": offer_data.buyer_id,
-                        "is_offer": true,
-                    }
-                }).to_string(),
-                    );
+                // a pooled offer goes back to the pool it was drawn from
+                if paid_from_balance {
+                    self.internal_add_claimable_balance(&offer_data.buyer_id, &offer_data.ft_token_id, offer_data.price);
+                } else {
+                    self.internal_transfer(&offer_data.ft_token_id, offer_data.buyer_id.clone(), offer_data.price);
                 }
-            } else if offer_data.ft_token_id == near_account() {
+                MarketEvent::ResolvePurchaseFail(PurchaseLog {
+                    owner_id: seller_id.clone(),
+                    buyer_id: offer_data.buyer_id.clone(),
+                    nft_contract_id: offer_data.nft_contract_id.clone(),
+                    token_id: token_id.clone(),
+                    token_series_id: offer_data.token_series_id.clone(),
+                    ft_token_id: offer_data.ft_token_id.clone(),
+                    price: offer_data.price.into(),
+                    is_offer: true,
+                })
+                .emit();
+            } else {
                 let treasury_fee =
-                    offer_data.price as u128 * self.calculate_current_transaction_fee() / (MAX_TREASURY_PERCENTAGE as u128);
+                    offer_data.price * transaction_fee.0 / (MAX_TREASURY_PERCENTAGE as u128);
 
                 let amount_after = offer_data.price.saturating_sub(treasury_fee);
                 if amount_after > 0 {
-                    self.internal_transfer_near(seller_id.clone(), amount_after);
+                    self.internal_transfer(&offer_data.ft_token_id, seller_id.clone(), amount_after);
                 }
 
                 if treasury_fee > 0 {
-                    self.internal_transfer_near(self.treasury_id.clone(), offer_data.price.min(treasury_fee));
+                    self.internal_add_treasury_fee(
+                        &offer_data.ft_token_id,
+                        offer_data.price.min(treasury_fee),
+                        referrer_id,
+                        &offer_data.buyer_id,
+                        &offer_data.nft_contract_id,
+                        &token_id,
+                    );
                 }
 
-                env::log_str(
-                    &json!({
-                    "type": "resolve_purchase",
-                    "params": {
-                        "owner_id": seller_id,
-                        "nft_contract_id": &offer_data.nft_contract_id,
-                        "token_id": &token_id,
-                        "token_series_id": offer_data.token_series_id,
-                        "ft_token_id": offer_data.ft_token_id,
-                        "price": offer_data.price.to_string(),
-                        "buyer_id": offer_data.buyer_id,
-                        "is_offer": true,
-                    }
+                MarketEvent::ResolvePurchase(PurchaseLog {
+                    owner_id: seller_id.clone(),
+                    buyer_id: offer_data.buyer_id.clone(),
+                    nft_contract_id: offer_data.nft_contract_id.clone(),
+                    token_id: token_id.clone(),
+                    token_series_id: offer_data.token_series_id.clone(),
+                    ft_token_id: offer_data.ft_token_id.clone(),
+                    price: offer_data.price.into(),
+                    is_offer: true,
                 })
-                        .to_string(),
-                );
+                .emit();
             }
             return offer_data.price.into();
         };
 
         // Payout (transfer to royalties and seller)
-        if offer_data.ft_token_id == near_account() {
-            // 5% fee for treasury
-            let treasury_fee =
-                offer_data.price as u128 * self.calculate_current_transaction_fee() / (MAX_TREASURY_PERCENTAGE as u128);
-
-            for (receiver_id, amount) in payout {
-                if receiver_id == seller_id {
-                    let amount_after = amount.0.saturating_sub(treasury_fee);
-                    if amount_after > 0 {
-                        self.internal_transfer_near(receiver_id, amount_after);
-                    }
-                    if treasury_fee > 0 {
-                        self.internal_transfer_near(self.treasury_id.clone(), amount.0.min(treasury_fee));
-                    }
-                } else {
-                    self.internal_transfer_near(receiver_id, amount.0)
+        let treasury_fee =
+            offer_data.price * transaction_fee.0 / (MAX_TREASURY_PERCENTAGE as u128);
+
+        let mut transfers = vec![];
+        for (receiver_id, amount) in payout {
+            if receiver_id == seller_id {
+                let amount_after = amount.0.saturating_sub(treasury_fee);
+                if amount_after > 0 {
+                    transfers.push((receiver_id, amount_after));
                 }
+                if treasury_fee > 0 {
+                    self.internal_add_treasury_fee(
+                        &offer_data.ft_token_id,
+                        amount.0.min(treasury_fee),
+                        referrer_id.clone(),
+                        &offer_data.buyer_id,
+                        &offer_data.nft_contract_id,
+                        &token_id,
+                    );
+                }
+            } else {
+                transfers.push((receiver_id, amount.0));
             }
+        }
+        self.internal_transfer_payout(&offer_data.ft_token_id, transfers);
+
+        MarketEvent::ResolvePurchase(PurchaseLog {
+            owner_id: seller_id.clone(),
+            buyer_id: offer_data.buyer_id.clone(),
+            nft_contract_id: offer_data.nft_contract_id.clone(),
+            token_id: token_id.clone(),
+            token_series_id: offer_data.token_series_id.clone(),
+            ft_token_id: offer_data.ft_token_id.clone(),
+            price: offer_data.price.into(),
+            is_offer: true,
+        })
+        .emit();
 
-            env::log_str(
-                &json!({
-                    "type": "resolve_purchase",
-                    "params": {
-                        "owner_id": seller_id,
-                        "nft_contract_id": &offer_data.nft_contract_id,
-                        "token_id": &token_id,
-                        "token_series_id": offer_data.token_series_id,
-                        "ft_token_id": offer_data.ft_token_id,
-                        "price": offer_data.price.to_string(),
-                        "buyer_id": offer_data.buyer_id,
-                        "is_offer": true,
-                    }
-                })
-                .to_string(),
-            );
-
-            let seller_contract_account_id_token_id =
-                make_triple(&offer_data.nft_contract_id, &seller_id, &token_id);
-            self.trades.remove(&seller_contract_account_id_token_id);
+        let seller_contract_account_id_token_id =
+            make_triple(&offer_data.nft_contract_id, &seller_id, &token_id);
+        self.internal_remove_trade_list(&seller_contract_account_id_token_id, &seller_id);
 
-            return offer_data.price.into();
-        } else {
-            U128(0)
-        }
+        offer_data.price.into()
     }
 
     // Trade
@@ -1163,6 +2791,9 @@ impl Contract {
         buyer_id: AccountId,
         buyer_token_id: Option<TokenId>,
         buyer_approval_id: u64,
+        ft_token_id: Option<AccountId>,
+        buyer_amount: Option<U128>,
+        seller_amount: Option<U128>,
     ) {
         self.internal_add_trade(
             nft_contract_id.clone().into(),
@@ -1172,23 +2803,24 @@ impl Contract {
             buyer_token_id.clone(),
             buyer_id.clone(),
             buyer_approval_id.clone(),
+            ft_token_id.clone(),
+            buyer_amount,
+            seller_amount,
         );
 
-        env::log_str(
-            &json!({
-                "type": "add_trade",
-                "params": {
-                    "buyer_id": buyer_id,
-                    "nft_contract_id": nft_contract_id,
-                    "token_id": token_id,
-                    "token_series_id": token_series_id,
-                    "buyer_nft_contract_id": buyer_nft_contract_id,
-                    "buyer_token_id": buyer_token_id,
-                    "buyer_approval_id": buyer_approval_id
-                }
-            })
-            .to_string(),
-        );
+        MarketEvent::AddTrade(AddTradeLog {
+            buyer_id,
+            nft_contract_id,
+            token_id,
+            token_series_id,
+            buyer_nft_contract_id,
+            buyer_token_id,
+            buyer_approval_id: buyer_approval_id.into(),
+            ft_token_id,
+            buyer_amount,
+            seller_amount,
+        })
+        .emit();
     }
 
     fn internal_add_trade(
@@ -1200,6 +2832,9 @@ impl Contract {
         buyer_token_id: Option<TokenId>,
         buyer_id: AccountId,
         buyer_approval_id: u64,
+        ft_token_id: Option<AccountId>,
+        buyer_amount: Option<U128>,
+        seller_amount: Option<U128>,
     ) {
         let token = if token_id.is_some() {
             token_id.as_ref().unwrap().to_string()
@@ -1220,15 +2855,6 @@ impl Contract {
                 .expect("Paras: Buyer token id is not specified"),
         );
 
-        let trade_data = TradeData {
-            buyer_amount: None,
-            seller_amount: None,
-            is_active: None,
-            ft_token_id: None,
-            nft_contract_id: nft_contract_id.into(),
-            token_id,
-            token_series_id,
-        };
         let mut buyer_trade_list = self
             .trades
             .get(&buyer_contract_account_id_token_id)
@@ -1238,6 +2864,52 @@ impl Contract {
                     trade_data: HashMap::new(),
                 }
             });
+
+        // refund the top-up of the replaced trade before escrowing the new one
+        if let Some(old_trade_data) = buyer_trade_list.trade_data.get(&contract_account_id_token_id) {
+            self.internal_refund_trade(&buyer_id, old_trade_data);
+        }
+
+        let buyer_amount = buyer_amount.map_or(0, |amount| amount.0);
+        let seller_amount = seller_amount.map_or(0, |amount| amount.0);
+        let ft_token_id = if buyer_amount > 0 || seller_amount > 0 {
+            let ft_token_id = ft_token_id.unwrap_or(near_account());
+            assert!(
+                self.approved_ft_token_ids.contains(&ft_token_id),
+                "Paras: ft_token_id not approved"
+            );
+            assert!(
+                buyer_amount < MAX_PRICE && seller_amount < MAX_PRICE,
+                "Paras: price higher than {}",
+                MAX_PRICE
+            );
+            if buyer_amount > 0 {
+                self.internal_withdraw_claimable_balance(&buyer_id, &ft_token_id, buyer_amount);
+            }
+            Some(ft_token_id.to_string())
+        } else {
+            None
+        };
+        let transaction_fee = ft_token_id
+            .as_ref()
+            .map(|_| self.calculate_nft_contract_transaction_fee(&nft_contract_id));
+
+        // the internal balance charges its own entries
+        let initial_storage_usage = env::storage_usage();
+        let trade_key = make_key_trade(&buyer_contract_account_id_token_id, &contract_account_id_token_id);
+        match transaction_fee {
+            Some(transaction_fee) => self.trade_transaction_fee.insert(&trade_key, &transaction_fee),
+            None => self.trade_transaction_fee.remove(&trade_key),
+        };
+        let trade_data = TradeData {
+            buyer_amount: if buyer_amount > 0 { Some(buyer_amount) } else { None },
+            seller_amount: if seller_amount > 0 { Some(seller_amount) } else { None },
+            is_active: None,
+            ft_token_id,
+            nft_contract_id,
+            token_id,
+            token_series_id,
+        };
         buyer_trade_list.approval_id = buyer_approval_id;
         buyer_trade_list
             .trade_data
@@ -1246,18 +2918,21 @@ impl Contract {
         self.trades
             .insert(&buyer_contract_account_id_token_id, &buyer_trade_list);
 
-        let mut token_ids = self.by_owner_id.get(&buyer_id).unwrap_or_else(|| {
+        let mut trade_ids = self.trades_by_owner_id.get(&buyer_id).unwrap_or_else(|| {
             UnorderedSet::new(
-                StorageKey::ByOwnerIdInner {
+                StorageKey::TradesByOwnerIdInner {
                     account_id_hash: hash_account_id(&buyer_id),
                 }
                 .try_to_vec()
                 .unwrap(),
             )
         });
+        trade_ids.insert(&buyer_contract_account_id_token_id);
+        self.trades_by_owner_id.insert(&buyer_id, &trade_ids);
+
+        self.internal_add_by_owner_id(&buyer_id, &make_key_owner_by_id_trade(contract_account_id_token_id));
 
-        token_ids.insert(&make_key_owner_by_id_trade(contract_account_id_token_id));
-        self.by_owner_id.insert(&buyer_id, &token_ids);
+        self.internal_update_storage_used(&buyer_id, initial_storage_usage);
     }
 
     #[payable]
@@ -1300,58 +2975,155 @@ impl Contract {
         self.internal_delete_trade(
             buyer_id.clone(),
             buyer_contract_account_id_token_id,
-            contract_account_id_token_id
+            contract_account_id_token_id,
+            true,
         )
         .expect("Paras: Trade not found");
 
-        env::log_str(
-            &json!({
-                "type": "delete_trade",
-                "params": {
-                    "nft_contract_id": nft_contract_id,
-                    "buyer_id": buyer_id,
-                    "token_id": token_id,
-                    "token_series_id": token_series_id,
-                    "buyer_nft_contract_id": buyer_nft_contract_id,
-                    "buyer_token_id": buyer_token_id
-                }
-            })
-            .to_string(),
-        );
+        MarketEvent::DeleteTrade(DeleteTradeLog {
+            buyer_id,
+            nft_contract_id,
+            token_id,
+            token_series_id,
+            buyer_nft_contract_id,
+            buyer_token_id,
+        })
+        .emit();
     }
 
+    /// Removes a trade with its by_owner_id entry, the trade list goes with its last trade.
+    /// The escrowed top-up is refunded unless it moves into the swap of an accepted trade.
     fn internal_delete_trade(
         &mut self,
         buyer_id: AccountId,
         buyer_contract_account_id_token_id: String,
         contract_account_id_token_id: String,
+        refund: bool,
     ) -> Option<TradeData> {
+        let initial_storage_usage = env::storage_usage();
         let mut trade_list = self
             .trades
             .get(&buyer_contract_account_id_token_id)
             .expect("Paras: Trade list does not exist");
 
-        let trade_data = trade_list.trade_data.remove(&contract_account_id_token_id).unwrap();
+        let trade_data = trade_list.trade_data.remove(&contract_account_id_token_id)?;
+        self.trade_transaction_fee.remove(&make_key_trade(
+            &buyer_contract_account_id_token_id,
+            &contract_account_id_token_id,
+        ));
 
-        self.trades
-            .insert(&buyer_contract_account_id_token_id, &trade_list);
+        if trade_list.trade_data.is_empty() {
+            self.internal_remove_trade_list_entry(&buyer_contract_account_id_token_id, &buyer_id);
+        } else {
+            self.trades
+                .insert(&buyer_contract_account_id_token_id, &trade_list);
+        }
 
-        let mut by_owner_id = self
-            .by_owner_id
-            .get(&buyer_id)
-            .expect("Paras: no market data by account_id");
+        let is_legacy = self.internal_remove_by_owner_id(&buyer_id, &make_key_owner_by_id_trade(contract_account_id_token_id));
 
-        by_owner_id.remove(&make_key_owner_by_id_trade(contract_account_id_token_id));
+        if !is_legacy {
+            self.internal_update_storage_used(&buyer_id, initial_storage_usage);
+        }
 
-        if by_owner_id.is_empty() {
-            self.by_owner_id.remove(&buyer_id);
-        } else {
-            self.by_owner_id.insert(&buyer_id, &by_owner_id);
+        // the refund is charged on its own
+        if refund {
+            self.internal_refund_trade(&buyer_id, &trade_data);
         }
 
         return Some(trade_data);
     }
 
+    /// Deletes every trade of the trade list, refunding their top-ups
+    fn internal_remove_trade_list(
+        &mut self,
+        contract_account_id_token_id: &ContractAccountIdTokenId,
+        account_id: &AccountId,
+    ) -> Option<TradeList> {
+        let trade_list = self.trades.get(contract_account_id_token_id)?;
+
+        for trade_key in trade_list.trade_data.keys() {
+            self.internal_delete_trade(
+                account_id.clone(),
+                contract_account_id_token_id.clone(),
+                trade_key.clone(),
+                true,
+            );
+        }
+
+        // a trade list left empty before every removal went through internal_delete_trade
+        if trade_list.trade_data.is_empty() {
+            let initial_storage_usage = env::storage_usage();
+            self.internal_remove_trade_list_entry(contract_account_id_token_id, account_id);
+            self.internal_update_storage_used(account_id, initial_storage_usage);
+        }
+
+        Some(trade_list)
+    }
+
+    fn internal_remove_trade_list_entry(
+        &mut self,
+        contract_account_id_token_id: &ContractAccountIdTokenId,
+        account_id: &AccountId,
+    ) {
+        self.trades.remove(contract_account_id_token_id);
+
+        if let Some(mut trade_ids) = self.trades_by_owner_id.get(account_id) {
+            trade_ids.remove(contract_account_id_token_id);
+            if trade_ids.is_empty() {
+                self.trades_by_owner_id.remove(account_id);
+            } else {
+                self.trades_by_owner_id.insert(account_id, &trade_ids);
+            }
+        }
+    }
+
+    /// credits the escrowed top-up of a trade back to the internal balance of its buyer
+    fn internal_refund_trade(&mut self, buyer_id: &AccountId, trade_data: &TradeData) {
+        if let Some(buyer_amount) = trade_data.buyer_amount {
+            let ft_token_id: AccountId = trade_data.ft_token_id.as_ref().unwrap().parse().unwrap();
+            self.internal_add_claimable_balance(buyer_id, &ft_token_id, buyer_amount);
+        }
+    }
+
+    fn calculate_trade_transaction_fee(
+        &mut self,
+        nft_contract_id: &AccountId,
+        buyer_contract_account_id_token_id: &str,
+        contract_account_id_token_id: &str,
+    ) -> u128 {
+        if let Some(transaction_fee) = self.trade_transaction_fee.get(&make_key_trade(
+            buyer_contract_account_id_token_id,
+            contract_account_id_token_id,
+        )) {
+            return transaction_fee;
+        }
+
+        // fallback to nft contract or default transaction fee
+        self.calculate_nft_contract_transaction_fee(nft_contract_id)
+    }
+
+    /// Collects the seller's top-up of an accepted trade from their internal balance and
+    /// returns the amounts to escrow alongside the swap with the fee locked when the trade was added
+    fn internal_collect_trade_payment(
+        &mut self,
+        seller_id: &AccountId,
+        trade_data: &TradeData,
+        transaction_fee: u128,
+    ) -> Option<TradePayment> {
+        let ft_token_id: AccountId = trade_data.ft_token_id.as_ref()?.parse().unwrap();
+        let seller_amount = trade_data.seller_amount.unwrap_or(0);
+        if seller_amount > 0 {
+            self.internal_withdraw_claimable_balance(seller_id, &ft_token_id, seller_amount);
+        }
+
+        Some(TradePayment {
+            ft_token_id,
+            buyer_amount: trade_data.buyer_amount.unwrap_or(0).into(),
+            seller_amount: seller_amount.into(),
+            transaction_fee: transaction_fee.into(),
+        })
+    }
+
     pub fn get_trade(
         &self,
         seller_nft_contract_id: AccountId,
@@ -1410,10 +3182,21 @@ impl Contract {
             .get(&buyer_contract_account_id_token_id)
             .expect("Paras: Trade list does not exist");
 
-        trade_list
-            .trade_data
-            .get(&contract_account_id_token_id)
+        let transaction_fee = self.calculate_trade_transaction_fee(
+            &nft_contract_id,
+            &buyer_contract_account_id_token_id,
+            &contract_account_id_token_id,
+        );
+        // the accepted trade's top-up moves into the swap, the rest of the list is refunded
+        let trade_data = self
+            .internal_delete_trade(
+                buyer_id.clone(),
+                buyer_contract_account_id_token_id.clone(),
+                contract_account_id_token_id,
+                false,
+            )
             .expect("Paras: Trade data does not exist");
+        let payment = self.internal_collect_trade_payment(&seller_id, &trade_data, transaction_fee);
 
         self.internal_delete_market_data(&nft_contract_id, &token_id);
         self.internal_delete_market_data(&buyer_nft_contract_id, &buyer_token_id);
@@ -1427,8 +3210,8 @@ impl Contract {
         if let Some(mut trades) = self.trades.get(&buyer_contract_account_id_token_id){
             trades.trade_data.clear();
         }
-        self.trades.remove(&seller_contract_account_id_token_id);
-        self.trades.remove(&buyer_contract_account_id_token_id);
+        self.internal_remove_trade_list(&seller_contract_account_id_token_id, &seller_id);
+        self.internal_remove_trade_list(&buyer_contract_account_id_token_id, &buyer_id);
 
         self.trade_swap_nft(
             buyer_id,
@@ -1439,6 +3222,7 @@ impl Contract {
             nft_contract_id,
             token_id,
             approval_id,
+            payment,
         )
     }
 
@@ -1467,23 +3251,33 @@ impl Contract {
             .get(&buyer_contract_account_id_token_id)
             .expect("Paras: Trade list does not exist");
 
-        let trade_data = trade_list
-            .trade_data
-            .get(&contract_account_id_token_id)
+        let transaction_fee = self.calculate_trade_transaction_fee(
+            &nft_contract_id,
+            &buyer_contract_account_id_token_id,
+            &contract_account_id_token_id,
+        );
+        let trade_data = self
+            .internal_delete_trade(
+                buyer_id.clone(),
+                buyer_contract_account_id_token_id.clone(),
+                contract_account_id_token_id,
+                false,
+            )
             .expect("Paras: Trade data does not exist");
 
         assert_eq!(
             trade_data.token_series_id.as_ref().unwrap(),
             &token_series_id
         );
+        let payment = self.internal_collect_trade_payment(&seller_id, &trade_data, transaction_fee);
 
         self.internal_delete_market_data(&nft_contract_id, &token_id);
         self.internal_delete_market_data(&buyer_nft_contract_id, &buyer_token_id);
 
         let seller_contract_account_id_token_id =
             make_triple(&nft_contract_id, &seller_id, &token_id);
-        self.trades.remove(&seller_contract_account_id_token_id);
-        self.trades.remove(&buyer_contract_account_id_token_id);
+        self.internal_remove_trade_list(&seller_contract_account_id_token_id, &seller_id);
+        self.internal_remove_trade_list(&buyer_contract_account_id_token_id, &buyer_id);
 
         self.trade_swap_nft(
             buyer_id,
@@ -1494,6 +3288,7 @@ impl Contract {
             nft_contract_id,
             token_id,
             approval_id,
+            payment,
         )
     }
 
@@ -1507,1042 +3302,4975 @@ impl Contract {
         seller_nft_contract_id: AccountId,
         seller_token_id: TokenId,
         seller_approval_id: u64,
+        payment: Option<TradePayment>,
     ) -> Promise {
         // 1. transfer buyer & seller NFT to marketplace
         // 2. verify that those NFTs is valid and has approval_id
         // 3. if those NFTs is valid then swap token to buyer & seller
         // 4. if failed then rollback the NFT to buyer or seller
+        let tokens = vec![
+            (
+                EscrowToken {
+                    nft_contract_id: buyer_nft_contract_id,
+                    token_id: buyer_token_id,
+                    owner_id: buyer_id.clone(),
+                    is_escrowed: false,
+                },
+                buyer_approval_id,
+            ),
+            (
+                EscrowToken {
+                    nft_contract_id: seller_nft_contract_id,
+                    token_id: seller_token_id,
+                    owner_id: seller_id.clone(),
+                    is_escrowed: false,
+                },
+                seller_approval_id,
+            ),
+        ];
 
-        ext_contract::nft_transfer(
-            env::current_account_id(),
-            buyer_token_id.clone(),
-            Some(buyer_approval_id),
-            None,
-            buyer_nft_contract_id.clone(),
-            1,
-            GAS_FOR_NFT_TRANSFER,
-        )
-        .then(ext_self::callback_first_trade(
-            seller_nft_contract_id.clone(),
-            seller_token_id.clone(),
-            seller_approval_id,
-            env::current_account_id(),
-            NO_DEPOSIT,
-            GAS_FOR_CALLBACK_FIRST_TRADE,
-        ))
-        .then(ext_self::callback_second_trade(
+        self.internal_start_escrow(buyer_id, seller_id, tokens, payment, None, None)
+    }
+
+    // Escrow
+
+    /// Records the swap and transfers every token to the marketplace with its approval_id,
+    /// callback_escrow then releases the tokens or rolls them back. The tokens of a purchase
+    /// are transferred with nft_transfer_payout to get the payout of their share of the price.
+    fn internal_start_escrow(
+        &mut self,
+        buyer_id: AccountId,
+        seller_id: AccountId,
+        tokens: Vec<(EscrowToken, u64)>,
+        payment: Option<TradePayment>,
+        bundle_id: Option<U64>,
+        purchase: Option<BundlePurchase>,
+    ) -> Promise {
+        let escrow_id = self.next_escrow_id;
+        self.next_escrow_id += 1;
+
+        let mut escrow_transfers: Option<Promise> = None;
+        for (index, (token, approval_id)) in tokens.iter().enumerate() {
+            let transfer = match &purchase {
+                Some(purchase) => ext_contract::nft_transfer_payout(
+                    env::current_account_id(),
+                    token.token_id.clone(),
+                    Some(*approval_id),
+                    Some(purchase.token_prices[index]),
+                    Some((max_len_payout(&purchase.ft_token_id) / tokens.len() as u32).max(1)),
+                    token.nft_contract_id.clone(),
+                    1,
+                    GAS_FOR_NFT_TRANSFER,
+                ),
+                None => ext_contract::nft_transfer(
+                    env::current_account_id(),
+                    token.token_id.clone(),
+                    Some(*approval_id),
+                    None,
+                    token.nft_contract_id.clone(),
+                    1,
+                    GAS_FOR_NFT_TRANSFER,
+                ),
+            };
+            escrow_transfers = Some(match escrow_transfers {
+                Some(escrow_transfers) => escrow_transfers.and(transfer),
+                None => transfer,
+            });
+        }
+
+        let escrow = Escrow {
+            state: EscrowState::Escrowing,
             buyer_id,
-            buyer_nft_contract_id.clone(),
-            buyer_token_id.clone(),
             seller_id,
-            seller_nft_contract_id.clone(),
-            seller_token_id.clone(),
+            tokens: tokens.into_iter().map(|(token, _)| token).collect(),
+            payment,
+            bundle_id,
+            purchase,
+        };
+
+        // releasing every token, then settling the payment with up to two transfers
+        // or the purchase with the payouts of every token
+        let mut gas_for_callback_escrow = GAS_FOR_NFT_TRANSFER.0 * escrow.tokens.len() as u64
+            + GAS_FOR_CALLBACK_ESCROW_TRANSFER.0
+            + BASE_GAS.0;
+        if escrow.payment.is_some() {
+            gas_for_callback_escrow += (GAS_FOR_FT_TRANSFER.0 + GAS_FOR_RESOLVE_FT_TRANSFER.0) * 2;
+        }
+        if let Some(purchase) = &escrow.purchase {
+            gas_for_callback_escrow += gas_for_royalties(&purchase.ft_token_id).0;
+        }
+        self.internal_update_escrow(escrow_id, &escrow);
+
+        escrow_transfers.unwrap().then(ext_self::callback_escrow(
+            escrow_id.into(),
             env::current_account_id(),
             NO_DEPOSIT,
-            GAS_FOR_CALLBACK_SECOND_TRADE,
+            Gas(gas_for_callback_escrow),
         ))
     }
 
+    /// Marks the tokens that reached the marketplace, then releases every token to the other
+    /// side, or returns the escrowed tokens to their owners when any token failed to transfer
     #[private]
-    pub fn callback_first_trade(
-        &mut self,
-        seller_nft_contract_id: AccountId,
-        seller_token_id: TokenId,
-        seller_approval_id: u64,
-    ) -> Promise {
-        if !is_promise_success() {
-            env::panic_str(&"Paras: buyer's nft failed to trade");
+    pub fn callback_escrow(&mut self, escrow_id: U64) -> bool {
+        let mut escrow = self.escrows.get(&escrow_id.0).expect("Paras: Escrow does not exist");
+        for (index, token) in escrow.tokens.iter_mut().enumerate() {
+            token.is_escrowed = matches!(env::promise_result(index as u64), PromiseResult::Successful(_));
+        }
+        let is_escrowed = escrow.tokens.iter().all(|token| token.is_escrowed);
+
+        if is_escrowed {
+            escrow.state = EscrowState::Releasing;
+            if let Some(payment) = escrow.payment.take() {
+                let seller_token = escrow
+                    .tokens
+                    .iter()
+                    .find(|token| token.owner_id == escrow.seller_id)
+                    .unwrap();
+                self.internal_settle_trade_payment(
+                    &escrow.buyer_id,
+                    &escrow.seller_id,
+                    &seller_token.nft_contract_id,
+                    &seller_token.token_id,
+                    payment,
+                );
+            }
+            if let Some(purchase) = escrow.purchase.take() {
+                self.internal_resolve_bundle_purchase(&escrow, purchase, true);
+            } else {
+                internal_emit_accept_escrow(&escrow);
+            }
         } else {
-            return ext_contract::nft_transfer(
-                env::current_account_id(),
-                seller_token_id.clone(),
-                Some(seller_approval_id),
-                None,
-                seller_nft_contract_id.clone(),
-                1,
-                GAS_FOR_NFT_TRANSFER,
-            );
+            escrow.state = EscrowState::RollingBack;
+            if let Some(payment) = escrow.payment.take() {
+                self.internal_add_claimable_balance(&escrow.buyer_id, &payment.ft_token_id, payment.buyer_amount.0);
+                self.internal_add_claimable_balance(&escrow.seller_id, &payment.ft_token_id, payment.seller_amount.0);
+            }
+            if let Some(purchase) = escrow.purchase.take() {
+                self.internal_resolve_bundle_purchase(&escrow, purchase, false);
+            }
+            env::log_str("Paras: swap failed, rollback escrowed nfts");
         }
+
+        let token_indexes = escrow.escrowed_token_indexes();
+        self.internal_transfer_escrow_tokens(escrow_id.0, escrow, token_indexes);
+
+        is_escrowed
     }
 
-    #[private]
-    pub fn callback_second_trade(
-        &mut self,
-        buyer_id: AccountId,
-        buyer_nft_contract_id: AccountId,
-        buyer_token_id: TokenId,
-        seller_id: AccountId,
-        seller_nft_contract_id: AccountId,
-        seller_token_id: TokenId,
-    ) {
-        if !is_promise_success() {
-            ext_contract::nft_transfer(
-                buyer_id,
-                buyer_token_id,
+    /// Sends the tokens at token_indexes to their destination, the escrow is completed right
+    /// away when there is nothing left to send
+    fn internal_transfer_escrow_tokens(&mut self, escrow_id: u64, escrow: Escrow, token_indexes: Vec<u32>) {
+        if token_indexes.is_empty() {
+            self.internal_complete_escrow(escrow_id, escrow);
+            return;
+        }
+
+        let mut transfers: Option<Promise> = None;
+        for index in token_indexes.iter() {
+            let token = &escrow.tokens[*index as usize];
+            let transfer = ext_contract::nft_transfer(
+                escrow.destination(token),
+                token.token_id.clone(),
                 None,
                 None,
-                buyer_nft_contract_id,
+                token.nft_contract_id.clone(),
                 1,
                 GAS_FOR_NFT_TRANSFER,
             );
-            env::log_str(&"Paras: seller's nft failed to trade, rollback buyer's nft")
-        } else {
-            self.internal_swap_nft(
-                buyer_id,
-                buyer_nft_contract_id,
-                buyer_token_id,
-                seller_id,
-                seller_nft_contract_id,
-                seller_token_id,
-            );
+            transfers = Some(match transfers {
+                Some(transfers) => transfers.and(transfer),
+                None => transfer,
+            });
         }
-    }
+        self.internal_update_escrow(escrow_id, &escrow);
 
-    fn internal_swap_nft(
-        &mut self,
-        buyer_id: AccountId,
-        buyer_nft_contract_id: AccountId,
-        buyer_token_id: TokenId,
-        seller_id: AccountId,
-        seller_nft_contract_id: AccountId,
-        seller_token_id: TokenId,
-    ) {
-        ext_contract::nft_transfer(
-            seller_id.clone(),
-            buyer_token_id.clone(),
-            None,
-            None,
-            buyer_nft_contract_id.clone(),
-            1,
-            GAS_FOR_NFT_TRANSFER,
-        )
-        .then(ext_contract::nft_transfer(
-            buyer_id.clone(),
-            seller_token_id.clone(),
-            None,
-            None,
-            seller_nft_contract_id.clone(),
-            1,
-            GAS_FOR_NFT_TRANSFER,
+        transfers.unwrap().then(ext_self::callback_escrow_transfer(
+            escrow_id.into(),
+            token_indexes,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_CALLBACK_ESCROW_TRANSFER,
         ));
-
-        env::log_str(
-            &json!({
-                "type": "accept_trade",
-                "params": {
-                    "sender_id": seller_id,
-                    "buyer_id": buyer_id,
-                    "nft_contract_id": seller_nft_contract_id,
-                    "token_id": seller_token_id,
-                    "buyer_nft_contract_id": buyer_nft_contract_id,
-                    "buyer_token_id": buyer_token_id,
-                }
-            })
-            .to_string(),
-        );
     }
 
-    // Auction bids
-    #[payable]
-    pub fn add_bid(
-        &mut self,
-        nft_contract_id: AccountId,
-        ft_token_id: AccountId,
-        token_id: TokenId,
-        amount: U128,
-    ) {
-        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
-        let mut market_data = self
-            .market
-            .get(&contract_and_token_id)
-            .expect("Paras: Token id does not exist");
-
-        assert_eq!(market_data.is_auction.unwrap(), true, "Paras: not auction");
-
-        let bidder_id = env::predecessor_account_id();
-        let current_time = env::block_timestamp();
-
-        assert!(
-            current_time >= market_data.started_at.unwrap(),
-            "Paras: Sale has not started yet"
-        );
-
-        assert!(
-            current_time <= market_data.ended_at.unwrap(),
-            "Paras: Sale has ended"
-        );
-
-        let remaining_time = market_data.ended_at.unwrap() - current_time;
-        if remaining_time <= FIVE_MINUTES {
-          let extended_ended_at = market_data.ended_at.unwrap() + FIVE_MINUTES;
-          market_data.ended_at = Some(extended_ended_at);
+    /// Marks the delivered tokens, the escrow is stuck while any escrowed token is left
+    #[private]
+    pub fn callback_escrow_transfer(&mut self, escrow_id: U64, token_indexes: Vec<u32>) -> bool {
+        let mut escrow = self.escrows.get(&escrow_id.0).expect("Paras: Escrow does not exist");
+        for (result_index, token_index) in token_indexes.iter().enumerate() {
+            if matches!(env::promise_result(result_index as u64), PromiseResult::Successful(_)) {
+                escrow.tokens[*token_index as usize].is_escrowed = false;
+            }
+        }
 
-          env::log_str(
-            &json!({
-                "type": "extend_auction",
-                "params": {
-                    "nft_contract_id": nft_contract_id,
-                    "token_id": token_id,
-                    "ended_at": extended_ended_at,
-                }
-            })
-            .to_string(),
-          );
+        if escrow.tokens.iter().any(|token| token.is_escrowed) {
+            escrow.state = match escrow.state {
+                EscrowState::Releasing | EscrowState::ReleaseFailed => EscrowState::ReleaseFailed,
+                _ => EscrowState::RollbackFailed,
+            };
+            env::log_str("Paras: escrowed nft failed to transfer, retry or claim it");
+            self.internal_update_escrow(escrow_id.0, &escrow);
+            return false;
         }
 
-        assert_ne!(market_data.owner_id, bidder_id, "Paras: Owner cannot bid their own token");
+        self.internal_complete_escrow(escrow_id.0, escrow);
+        true
+    }
 
+    /// Retries the release or rollback of a stuck escrow
+    #[payable]
+    pub fn retry_escrow(&mut self, escrow_id: U64) {
+        assert_one_yocto();
+        let mut escrow = self.escrows.get(&escrow_id.0).expect("Paras: Escrow does not exist");
+        let account_id = env::predecessor_account_id();
         assert!(
-            env::attached_deposit() >= amount.into(),
-            "Paras: attached deposit is less than amount"
+            account_id == escrow.buyer_id || account_id == escrow.seller_id,
+            "Paras: Buyer or seller only"
         );
+        assert!(escrow.is_stuck(), "Paras: Escrow is not stuck");
 
-        assert_eq!(ft_token_id.to_string(), "near", "Paras: Only support NEAR");
-
-        let new_bid = Bid {
-            bidder_id: bidder_id.clone(),
-            price: amount.into(),
+        escrow.state = if escrow.state == EscrowState::ReleaseFailed {
+            EscrowState::Releasing
+        } else {
+            EscrowState::RollingBack
         };
+        let token_indexes = escrow.escrowed_token_indexes();
+        self.internal_transfer_escrow_tokens(escrow_id.0, escrow, token_indexes);
+    }
 
-        let mut bids = market_data.bids.unwrap_or(Vec::new());
-
-        if !bids.is_empty() {
-            let current_bid = &bids[bids.len() - 1];
+    /// Transfers a token of a stuck escrow to its destination, which is the caller
+    #[payable]
+    pub fn claim_escrow_token(&mut self, escrow_id: U64, nft_contract_id: AccountId, token_id: TokenId) {
+        assert_one_yocto();
+        let escrow = self.escrows.get(&escrow_id.0).expect("Paras: Escrow does not exist");
+        assert!(escrow.is_stuck(), "Paras: Escrow is not stuck");
+
+        let index = escrow
+            .tokens
+            .iter()
+            .position(|token| {
+                token.is_escrowed && token.nft_contract_id == nft_contract_id && token.token_id == token_id
+            })
+            .expect("Paras: Token is not escrowed");
+        let destination = escrow.destination(&escrow.tokens[index]);
+        assert_eq!(
+            env::predecessor_account_id(),
+            destination,
+            "Paras: Only {} can claim the token",
+            destination
+        );
 
-            assert!(
-              amount.0 >= current_bid.price.0 + (current_bid.price.0 * 5 / 100),
-              "Paras: Can't pay less than or equal to current bid price + 5% : {:?}",
-              current_bid.price.0 + (current_bid.price.0 * 5 / 100)
-            );
+        self.internal_transfer_escrow_tokens(escrow_id.0, escrow, vec![index as u32]);
+    }
 
-            assert!(
-                amount.0 >= market_data.price,
-                "Paras: Can't pay less than starting price: {:?}",
-                U128(market_data.price)
-            );
+    fn internal_update_escrow(&mut self, escrow_id: u64, escrow: &Escrow) {
+        self.escrows.insert(&escrow_id, escrow);
 
-            // Retain all elements except account_id
-            bids.retain(|bid| {
-              if bid.bidder_id == bidder_id {
-                // refund
-                self.internal_transfer_near(bid.bidder_id.clone(), bid.price.0);
-              }
+        MarketEvent::UpdateEscrow(EscrowLog {
+            escrow_id: escrow_id.into(),
+            state: escrow.state,
+        })
+        .emit();
+    }
 
-              bid.bidder_id != bidder_id
-            });
-        } else {
-            assert!(
-                amount.0 >= market_data.price,
-                "Paras: Can't pay less than starting price: {:?}",
-                market_data.price
-            );
-        }
+    fn internal_complete_escrow(&mut self, escrow_id: u64, escrow: Escrow) {
+        self.escrows.remove(&escrow_id);
 
-        bids.push(new_bid);
-        market_data.bids = Some(bids);
-        self.market.insert(&contract_and_token_id, &market_data);
+        let state = match escrow.state {
+            EscrowState::Releasing | EscrowState::ReleaseFailed => EscrowState::Released,
+            _ => EscrowState::RolledBack,
+        };
+        MarketEvent::UpdateEscrow(EscrowLog {
+            escrow_id: escrow_id.into(),
+            state,
+        })
+        .emit();
+    }
 
-        // Remove first element if bids.length >= 100
-        let updated_bids = market_data.bids.unwrap_or(Vec::new());
-        if updated_bids.len() >= 100 {
-          self.internal_cancel_bid(nft_contract_id.clone(), token_id.clone(), updated_bids[0].bidder_id.clone())
+    pub fn get_escrow(&self, escrow_id: U64) -> EscrowJson {
+        EscrowJson {
+            escrow_id,
+            escrow: self.escrows.get(&escrow_id.0).expect("Paras: Escrow does not exist"),
         }
+    }
 
-        env::log_str(
-            &json!({
-                "type": "add_bid",
-                "params": {
-                    "bidder_id": bidder_id,
-                    "nft_contract_id": nft_contract_id,
-                    "token_id": token_id,
-                    "ft_token_id": ft_token_id,
-                    "amount": amount,
-                }
+    /// Escrows in progress or stuck, optionally filtered by state
+    pub fn get_escrows(
+        &self,
+        state: Option<EscrowState>,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<EscrowJson> {
+        self.escrows
+            .iter()
+            .filter(|(_, escrow)| state.is_none() || state == Some(escrow.state))
+            .skip(from_index.map(From::from).unwrap_or(0u64) as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .map(|(escrow_id, escrow)| EscrowJson {
+                escrow_id: escrow_id.into(),
+                escrow,
             })
-            .to_string(),
-        );
+            .collect()
     }
 
-    fn internal_cancel_bid(&mut self, nft_contract_id: AccountId, token_id: TokenId, account_id: AccountId) {
-      let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
-      let mut market_data = self
-        .market
-        .get(&contract_and_token_id)
-        .expect("Paras: Token id does not exist");
-
-      let mut bids = market_data.bids.unwrap();
-
-      assert!(
-        !bids.is_empty(),
-        "Paras: Bids data does not exist"
-      );
-
-      // Retain all elements except account_id
-      bids.retain(|bid| {
-        if bid.bidder_id == account_id {
-          // refund
-            self.internal_transfer_near(bid.bidder_id.clone(), bid.price.0);
+    fn internal_settle_trade_payment(
+        &mut self,
+        buyer_id: &AccountId,
+        seller_id: &AccountId,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+        payment: TradePayment,
+    ) {
+        // the fee is charged on the top-up of each side
+        let buyer_treasury_fee =
+            payment.buyer_amount.0 * payment.transaction_fee.0 / (MAX_TREASURY_PERCENTAGE as u128);
+        let seller_treasury_fee =
+            payment.seller_amount.0 * payment.transaction_fee.0 / (MAX_TREASURY_PERCENTAGE as u128);
+        let buyer_amount_after = payment.buyer_amount.0 - buyer_treasury_fee;
+        let seller_amount_after = payment.seller_amount.0 - seller_treasury_fee;
+        if buyer_amount_after > 0 {
+            self.internal_transfer(&payment.ft_token_id, seller_id.clone(), buyer_amount_after);
+        }
+        if seller_amount_after > 0 {
+            self.internal_transfer(&payment.ft_token_id, buyer_id.clone(), seller_amount_after);
+        }
+        let treasury_fee = buyer_treasury_fee + seller_treasury_fee;
+        if treasury_fee > 0 {
+            self.internal_add_treasury_fee(
+                &payment.ft_token_id,
+                treasury_fee,
+                None,
+                buyer_id,
+                nft_contract_id,
+                token_id,
+            );
         }
-
-        bid.bidder_id != account_id
-      });
-
-      market_data.bids = Some(bids);
-      self.market.insert(&contract_and_token_id, &market_data);
-
-      env::log_str(
-        &json!({
-          "type": "cancel_bid",
-          "params": {
-            "bidder_id": account_id, "nft_contract_id": nft_contract_id, "token_id": token_id
-          }
-        })
-        .to_string(),
-      );
     }
 
-    #[payable]
-    pub fn cancel_bid(&mut self, nft_contract_id: AccountId, token_id: TokenId, account_id: AccountId) {
-      assert_one_yocto();
-      let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
-      let market_data = self
-        .market
-        .get(&contract_and_token_id)
-        .expect("Paras: Token id does not exist");
-
-      let bids = market_data.bids.unwrap();
+    /// Pays for a listing that accepts an NFT with that NFT, plus the listing price from the
+    /// internal balance of the buyer, settled through the trade swap
+    fn internal_buy_with_nft(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        buyer_id: AccountId,
+        buyer_nft_contract_id: AccountId,
+        buyer_token_id: TokenId,
+        buyer_approval_id: u64,
+    ) -> Promise {
+        self.assert_not_paused(Operation::Buy, &nft_contract_id);
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let market_data = self
+            .internal_get_market_data(&contract_and_token_id)
+            .expect("Paras: Market data does not exist");
 
-      assert!(
-        !bids.is_empty(),
-        "Paras: Bids data does not exist"
-      );
+        assert_ne!(
+            buyer_id, market_data.owner_id,
+            "Paras: Cannot buy your own sale"
+        );
+        assert!(
+            market_data.accept_nft_contract_id.as_deref() == Some(buyer_nft_contract_id.as_str())
+                && market_data.accept_token_id.as_ref() == Some(&buyer_token_id),
+            "Paras: Listing does not accept this NFT"
+        );
 
-      for x in 0..bids.len() {
-        if bids[x].bidder_id == account_id {
-          assert!(
-            [bids[x].bidder_id.clone(), self.owner_id.clone()]
-              .contains(&env::predecessor_account_id()),
-              "Paras: Bidder or owner only"
-          );
+        let price = market_data.price;
+        let transaction_fee = self.calculate_market_data_transaction_fee(&nft_contract_id, &token_id);
+        if price > 0 {
+            self.internal_withdraw_claimable_balance(&buyer_id, &market_data.ft_token_id, price);
         }
-      }
 
-      self.internal_cancel_bid(nft_contract_id, token_id, account_id);
-    }
+        self.internal_delete_market_data(&nft_contract_id, &token_id);
+        self.internal_delete_market_data(&buyer_nft_contract_id, &buyer_token_id);
 
-    #[payable]
-    pub fn accept_bid(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
-        let predecessor_account_id = env::predecessor_account_id();
-        if predecessor_account_id != self.owner_id {
-            assert_one_yocto();
-        }
-        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
-        let mut market_data = self
-            .market
-            .get(&contract_and_token_id)
-            .expect("Paras: Token id does not exist");
+        let seller_contract_account_id_token_id =
+            make_triple(&nft_contract_id, &market_data.owner_id, &token_id);
+        let buyer_contract_account_id_token_id =
+            make_triple(&buyer_nft_contract_id, &buyer_id, &buyer_token_id);
+        self.internal_remove_trade_list(&seller_contract_account_id_token_id, &market_data.owner_id);
+        self.internal_remove_trade_list(&buyer_contract_account_id_token_id, &buyer_id);
+
+        let payment = if price > 0 {
+            Some(TradePayment {
+                ft_token_id: market_data.ft_token_id,
+                buyer_amount: price.into(),
+                seller_amount: U128(0),
+                transaction_fee: transaction_fee.into(),
+            })
+        } else {
+            None
+        };
 
-        assert_eq!(market_data.is_auction.unwrap(), true, "Paras: not auction");
-        let current_time: u64 = env::block_timestamp();
+        self.trade_swap_nft(
+            buyer_id,
+            buyer_nft_contract_id,
+            buyer_token_id,
+            buyer_approval_id,
+            market_data.owner_id,
+            nft_contract_id,
+            token_id,
+            market_data.approval_id,
+            payment,
+        )
+    }
 
+    // Bundle trades
+
+    /// Proposes to swap buyer_tokens for seller_tokens, each side then approves its tokens
+    /// with the approve_bundle_trade market type and the seller accepts the bundle
+    #[payable]
+    pub fn add_bundle_trade(
+        &mut self,
+        seller_id: AccountId,
+        buyer_tokens: Vec<BundleToken>,
+        seller_tokens: Vec<BundleToken>,
+    ) -> U64 {
+        assert_one_yocto();
+        let buyer_id = env::predecessor_account_id();
+        assert_ne!(buyer_id, seller_id, "Paras: Cannot trade with yourself");
+        assert!(
+            !buyer_tokens.is_empty() && !seller_tokens.is_empty(),
+            "Paras: Bundle trade needs tokens on both sides"
+        );
         assert!(
-            [market_data.owner_id.clone(), self.owner_id.clone()]
-            .contains(&predecessor_account_id),
-            "Paras: Seller or owner only"
+            buyer_tokens.len() + seller_tokens.len() <= MAX_BUNDLE_TOKENS,
+            "Paras: Bundle trade is limited to {} tokens",
+            MAX_BUNDLE_TOKENS
         );
 
-        if predecessor_account_id == self.owner_id && market_data.ended_at.is_some() {
-          assert!(
-            current_time >= market_data.ended_at.unwrap(),
-            "Paras: Auction has not ended yet"
-          );
+        let mut bundle_token_keys = HashSet::new();
+        for token in buyer_tokens.iter().chain(seller_tokens.iter()) {
+            assert!(
+                self.approved_nft_contract_ids.contains(&token.nft_contract_id),
+                "Paras: nft_contract_id is not approved"
+            );
+            self.assert_not_paused(Operation::Trade, &token.nft_contract_id);
+            assert!(
+                bundle_token_keys.insert(format!("{}{}{}", token.nft_contract_id, DELIMETER, token.token_id)),
+                "Paras: Duplicate token in bundle trade"
+            );
         }
 
-        let mut bids = market_data.bids.unwrap();
+        let initial_storage_usage = env::storage_usage();
+        let bundle_id = self.next_bundle_trade_id;
+        self.next_bundle_trade_id += 1;
 
-        assert!(!bids.is_empty(), "Paras: Cannot accept bid with empty bid");
+        let bundle_trade = BundleTrade {
+            buyer_id: buyer_id.clone(),
+            seller_id,
+            buyer_tokens,
+            seller_tokens,
+        };
+        self.bundle_trades.insert(&bundle_id, &bundle_trade);
+        self.internal_update_storage_used(&buyer_id, initial_storage_usage);
+        self.assert_storage_covered(&buyer_id);
 
-        let selected_bid = bids.remove(bids.len() - 1);
+        MarketEvent::AddBundleTrade(BundleTradeLog {
+            bundle_id: bundle_id.into(),
+            bundle_trade,
+        })
+        .emit();
 
-        // refund all except selected bids
-        for bid in &bids {
-          // refund
-            self.internal_transfer_near(bid.bidder_id.clone(), bid.price.0);
-        }
-        bids.clear();
+        bundle_id.into()
+    }
 
-        market_data.bids = Some(bids);
-        self.market.insert(&contract_and_token_id, &market_data);
+    /// Records the approval of a bundle token, called from nft_on_approve by its owner
+    fn internal_approve_bundle_trade(
+        &mut self,
+        bundle_id: u64,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: u64,
+    ) {
+        let bundle_trade = self
+            .bundle_trades
+            .get(&bundle_id)
+            .expect("Paras: Bundle trade does not exist");
 
-        self.internal_process_purchase(
-            market_data.nft_contract_id,
-            token_id,
-            selected_bid.bidder_id.clone(),
-            selected_bid.price.clone().0,
+        assert!(
+            owner_id == bundle_trade.buyer_id || owner_id == bundle_trade.seller_id,
+            "Paras: Owner is not part of the bundle trade"
+        );
+        let tokens = if owner_id == bundle_trade.buyer_id {
+            &bundle_trade.buyer_tokens
+        } else {
+            &bundle_trade.seller_tokens
+        };
+        assert!(
+            tokens
+                .iter()
+                .any(|token| token.nft_contract_id == nft_contract_id && token.token_id == token_id),
+            "Paras: Token is not part of the bundle trade"
         );
+
+        let initial_storage_usage = env::storage_usage();
+        self.bundle_trade_approvals
+            .insert(&make_bundle_token_key(bundle_id, &nft_contract_id, &token_id), &approval_id);
+        self.internal_update_storage_used(&owner_id, initial_storage_usage);
     }
 
     #[payable]
-    pub fn end_auction(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
-      let predecessor_account_id = env::predecessor_account_id();
-      if predecessor_account_id != self.owner_id {
-          assert_one_yocto();
-      }
-
-      let current_time = env::block_timestamp();
-      let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, &token_id);
-      let mut market_data = self
-          .market
-          .get(&contract_and_token_id)
-          .expect("Paras: Market data does not exist");
-
-      assert_eq!(market_data.is_auction.unwrap(), true, "Paras: not auction");
-      assert!(
-        [market_data.owner_id.clone(), self.owner_id.clone()]
-          .contains(&predecessor_account_id),
-        "Paras: Seller or owner only"
-      );
-
-      if predecessor_account_id == self.owner_id && market_data.ended_at.is_some() {
+    pub fn delete_bundle_trade(&mut self, bundle_id: U64) {
+        assert_one_yocto();
+        let bundle_trade = self
+            .bundle_trades
+            .get(&bundle_id.0)
+            .expect("Paras: Bundle trade does not exist");
+        let account_id = env::predecessor_account_id();
         assert!(
-          current_time >= market_data.ended_at.unwrap(),
-          "Paras: Auction has not ended yet (for owner)"
+            account_id == bundle_trade.buyer_id || account_id == bundle_trade.seller_id,
+            "Paras: Buyer or seller only"
         );
-      }
 
-      let mut bids = market_data.bids.unwrap();
+        self.internal_remove_bundle_trade(bundle_id.0);
 
-      if bids.is_empty() {
-        self.internal_delete_market_data(&nft_contract_id, &token_id);
+        MarketEvent::DeleteBundleTrade(BundleTradeLog {
+            bundle_id,
+            bundle_trade,
+        })
+        .emit();
+    }
 
-        env::log_str(
-            &json!({
-                "type": "delete_market_data",
-                "params": {
-                    "owner_id": market_data.owner_id,
-                    "nft_contract_id": nft_contract_id,
-                    "token_id": token_id,
-                }
-            })
-            .to_string(),
+    /// Escrows every token of the bundle and swaps them once all of them are escrowed,
+    /// see callback_escrow
+    #[payable]
+    pub fn accept_bundle_trade(&mut self, bundle_id: U64) -> Promise {
+        assert_one_yocto();
+        let bundle_trade = self
+            .bundle_trades
+            .get(&bundle_id.0)
+            .expect("Paras: Bundle trade does not exist");
+        assert_eq!(
+            env::predecessor_account_id(),
+            bundle_trade.seller_id,
+            "Paras: Seller only"
         );
-      } else {
-        let selected_bid = bids.remove(bids.len() - 1);
 
-        // refund all except selected bids
-        for bid in &bids {
-            self.internal_transfer_near(bid.bidder_id.clone(), bid.price.0);
+        let tokens = bundle_trade
+            .buyer_tokens
+            .iter()
+            .map(|token| (token, &bundle_trade.buyer_id))
+            .chain(bundle_trade.seller_tokens.iter().map(|token| (token, &bundle_trade.seller_id)));
+        let mut escrow_tokens = vec![];
+        for (token, owner_id) in tokens {
+            self.assert_not_paused(Operation::Trade, &token.nft_contract_id);
+            let approval_id = self
+                .bundle_trade_approvals
+                .get(&make_bundle_token_key(bundle_id.0, &token.nft_contract_id, &token.token_id))
+                .expect("Paras: Bundle trade is not fully approved");
+            self.internal_delete_market_data(&token.nft_contract_id, &token.token_id);
+
+            escrow_tokens.push((
+                EscrowToken {
+                    nft_contract_id: token.nft_contract_id.clone(),
+                    token_id: token.token_id.clone(),
+                    owner_id: owner_id.clone(),
+                    is_escrowed: false,
+                },
+                approval_id,
+            ));
         }
 
-        bids.clear();
-
-        market_data.bids = Some(bids);
-        self.market.insert(&contract_and_token_id, &market_data);
+        self.internal_remove_bundle_trade(bundle_id.0);
 
-        self.internal_process_purchase(
-            nft_contract_id,
-            token_id,
-            selected_bid.bidder_id.clone(),
-            selected_bid.price.clone().0
-        );
-      }
+        self.internal_start_escrow(
+            bundle_trade.buyer_id,
+            bundle_trade.seller_id,
+            escrow_tokens,
+            None,
+            Some(bundle_id),
+            None,
+        )
     }
 
-    fn internal_add_market_data(
-        &mut self,
-        owner_id: AccountId,
-        approval_id: u64,
-        nft_contract_id: AccountId,
-        token_id: TokenId,
-        ft_token_id: AccountId,
-        price: U128,
-        mut started_at: Option<U64>,
-        ended_at: Option<U64>,
-        end_price: Option<U128>,
-        is_auction: Option<bool>,
-    ) {
-        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
-
-        let bids: Option<Bids> = match is_auction {
-            Some(u) => {
-                if u {
-                    Some(Vec::new())
-                } else {
-                    None
-                }
-            }
-            None => None,
-        };
-
-        let current_time: u64 = env::block_timestamp();
+    fn internal_remove_bundle_trade(&mut self, bundle_id: u64) -> BundleTrade {
+        let initial_storage_usage = env::storage_usage();
+        let bundle_trade = self
+            .bundle_trades
+            .remove(&bundle_id)
+            .expect("Paras: Bundle trade does not exist");
+        self.internal_update_storage_used(&bundle_trade.buyer_id, initial_storage_usage);
+
+        let tokens = bundle_trade
+            .buyer_tokens
+            .iter()
+            .map(|token| (token, &bundle_trade.buyer_id))
+            .chain(bundle_trade.seller_tokens.iter().map(|token| (token, &bundle_trade.seller_id)));
+        for (token, owner_id) in tokens {
+            let initial_storage_usage = env::storage_usage();
+            self.bundle_trade_approvals
+                .remove(&make_bundle_token_key(bundle_id, &token.nft_contract_id, &token.token_id));
+            self.internal_update_storage_used(owner_id, initial_storage_usage);
+        }
 
-        if let Some(is_auction) = is_auction {
-          if let Some(started_at) = started_at {
-              assert!(started_at.0 >= current_time);
+        bundle_trade
+    }
 
-              if let Some(ended_at) = ended_at {
-                assert!(started_at.0 < ended_at.0);
-              }
-          }
+    pub fn get_bundle_trade(&self, bundle_id: U64) -> BundleTradeJson {
+        let bundle_trade = self
+            .bundle_trades
+            .get(&bundle_id.0)
+            .expect("Paras: Bundle trade does not exist");
 
-            if is_auction == true {
-                if started_at.is_none() {
-                    started_at = Some(U64(current_time));
-                }
+        self.internal_bundle_trade_json(bundle_id.0, bundle_trade)
+    }
 
-                assert!(ended_at.is_some(), "Paras: Ended at is none");
+    pub fn get_bundle_trades(&self, from_index: Option<U64>, limit: Option<u64>) -> Vec<BundleTradeJson> {
+        self.bundle_trades
+            .iter()
+            .skip(from_index.map(From::from).unwrap_or(0u64) as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .map(|(bundle_id, bundle_trade)| self.internal_bundle_trade_json(bundle_id, bundle_trade))
+            .collect()
+    }
 
-                if let Some(ended_at) = ended_at {
-                  assert!(ended_at.0 >= current_time);
-                }
-            }
+    fn internal_bundle_trade_json(&self, bundle_id: u64, bundle_trade: BundleTrade) -> BundleTradeJson {
+        BundleTradeJson {
+            bundle_id: bundle_id.into(),
+            buyer_id: bundle_trade.buyer_id,
+            seller_id: bundle_trade.seller_id,
+            buyer_tokens: bundle_tokens_json(&self.bundle_trade_approvals, bundle_id, bundle_trade.buyer_tokens),
+            seller_tokens: bundle_tokens_json(&self.bundle_trade_approvals, bundle_id, bundle_trade.seller_tokens),
         }
+    }
 
+    // Bundle listings
+
+    /// Lists tokens as a single lot, the owner then approves each token with the
+    /// approve_bundle_listing market type
+    #[payable]
+    pub fn add_bundle_listing(
+        &mut self,
+        tokens: Vec<BundleToken>,
+        ft_token_id: AccountId,
+        price: U128,
+    ) -> U64 {
+        assert_one_yocto();
+        let owner_id = env::predecessor_account_id();
+        assert!(!tokens.is_empty(), "Paras: Bundle listing has no tokens");
+        assert!(
+            tokens.len() <= max_bundle_listing_tokens(&ft_token_id),
+            "Paras: Bundle listing is limited to {} tokens",
+            max_bundle_listing_tokens(&ft_token_id)
+        );
+        assert!(
+            self.approved_ft_token_ids.contains(&ft_token_id),
+            "Paras: ft_token_id not approved"
+        );
         assert!(
             price.0 < MAX_PRICE,
             "Paras: price higher than {}",
             MAX_PRICE
         );
 
-        self.market.insert(
-            &contract_and_token_id,
-            &MarketData {
-                owner_id: owner_id.clone().into(),
-                approval_id,
-                nft_contract_id: nft_contract_id.clone().into(),
-                token_id: token_id.clone(),
-                ft_token_id: ft_token_id.clone(),
-                price: price.into(),
-                bids,
-                started_at: match started_at {
-                    Some(x) => Some(x.0),
-                    None => None,
-                },
-                ended_at: match ended_at {
-                    Some(x) => Some(x.0),
-                    None => None,
-                },
-                end_price: match end_price {
-                    Some(x) => Some(x.0),
-                    None => None,
-                },
-                accept_nft_contract_id: None,
-                accept_token_id: None,
-                is_auction,
-            },
-        );
-
-        let mut token_ids = self.by_owner_id.get(&owner_id).unwrap_or_else(|| {
-            UnorderedSet::new(
-                StorageKey::ByOwnerIdInner {
-                    account_id_hash: hash_account_id(&owner_id),
-                }
-                .try_to_vec()
-                .unwrap(),
-            )
-        });
-
-        token_ids.insert(&contract_and_token_id);
+        let mut bundle_token_keys = HashSet::new();
+        for token in tokens.iter() {
+            assert!(
+                self.approved_nft_contract_ids.contains(&token.nft_contract_id),
+                "Paras: nft_contract_id is not approved"
+            );
+            self.assert_not_paused(Operation::Listing, &token.nft_contract_id);
+            assert!(
+                bundle_token_keys.insert(format!("{}{}{}", token.nft_contract_id, DELIMETER, token.token_id)),
+                "Paras: Duplicate token in bundle listing"
+            );
+        }
 
-        self.by_owner_id.insert(&owner_id, &token_ids);
+        let transaction_fees = tokens
+            .iter()
+            .map(|token| self.calculate_nft_contract_transaction_fee(&token.nft_contract_id).into())
+            .collect();
 
-        // update offer trade approval_id
-        let owner_contract_account_id_token_id =
-            make_triple(&nft_contract_id, &owner_id, &token_id);
-        let trade_data = self.trades.get(&owner_contract_account_id_token_id);
-        if let Some(mut trade_list) = trade_data {
-            trade_list.approval_id = approval_id;
-            self.trades
-                .insert(&owner_contract_account_id_token_id, &trade_list);
-        }
+        let initial_storage_usage = env::storage_usage();
+        let bundle_id = self.next_bundle_listing_id;
+        self.next_bundle_listing_id += 1;
 
+        let bundle_listing = BundleListing {
+            owner_id: owner_id.clone(),
+            tokens,
+            ft_token_id,
+            price,
+            transaction_fees,
+        };
+        self.bundle_listings.insert(&bundle_id, &bundle_listing);
+        self.internal_update_storage_used(&owner_id, initial_storage_usage);
+        self.assert_storage_covered(&owner_id);
 
-        // set market data transaction fee
-        let current_transaction_fee = self.calculate_current_transaction_fee();
-        self.market_data_transaction_fee.transaction_fee.insert(&contract_and_token_id, &current_transaction_fee);
+        MarketEvent::AddBundleListing(BundleListingLog {
+            bundle_id: bundle_id.into(),
+            bundle_listing,
+        })
+        .emit();
 
-        env::log_str(
-            &json!({
-                "type": "add_market_data",
-                "params": {
-                    "owner_id": owner_id,
-                    "approval_id": approval_id,
-                    "nft_contract_id": nft_contract_id,
-                    "token_id": token_id,
-                    "ft_token_id": ft_token_id,
-                    "price": price,
-                    "started_at": started_at,
-                    "ended_at": ended_at,
-                    "end_price": end_price,
-                    "is_auction": is_auction,
-                    "transaction_fee": current_transaction_fee.to_string(),
-                }
-            })
-            .to_string(),
-        );
+        bundle_id.into()
     }
 
-    fn internal_delete_market_data(
+    /// Records the approval of a bundle listing token, called from nft_on_approve by its owner
+    fn internal_approve_bundle_listing(
         &mut self,
-        nft_contract_id: &AccountId,
-        token_id: &TokenId,
-    ) -> Option<MarketData> {
-        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
-
-        let market_data: Option<MarketData> =
-            if let Some(market_data) = self.old_market.get(&contract_and_token_id) {
-                self.old_market.remove(&contract_and_token_id);
-                Some(MarketData {
-                    owner_id: market_data.owner_id,
-                    approval_id: market_data.approval_id,
-                    nft_contract_id: market_data.nft_contract_id,
-                    token_id: market_data.token_id,
-                    ft_token_id: market_data.ft_token_id,
-                    price: market_data.price,
-                    bids: None,
-                    started_at: None,
-                    ended_at: None,
-                    end_price: None,
-                    accept_nft_contract_id: None,
-                    accept_token_id: None,
-                    is_auction: None,
-                })
-            } else if let Some(market_data) = self.market.get(&contract_and_token_id) {
-                self.market.remove(&contract_and_token_id);
-
-                if let Some(ref bids) = market_data.bids {
-                    for bid in bids {
-                        self.internal_transfer_near(bid.bidder_id.clone(), bid.price.0);
-                    }
-                };
-
-                Some(market_data)
-            } else {
-                None
-            };
+        bundle_id: u64,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: u64,
+    ) {
+        let bundle_listing = self
+            .bundle_listings
+            .get(&bundle_id)
+            .expect("Paras: Bundle listing does not exist");
+        assert_eq!(
+            owner_id, bundle_listing.owner_id,
+            "Paras: Owner is not the bundle listing owner"
+        );
+        assert!(
+            bundle_listing
+                .tokens
+                .iter()
+                .any(|token| token.nft_contract_id == nft_contract_id && token.token_id == token_id),
+            "Paras: Token is not part of the bundle listing"
+        );
 
-        market_data.map(|market_data| {
-            let by_owner_id = self
-                .by_owner_id
-                .get(&market_data.owner_id);
-            if let Some(mut by_owner_id) = by_owner_id {
-                by_owner_id.remove(&contract_and_token_id);
-                if by_owner_id.is_empty() {
-                self.by_owner_id.remove(&market_data.owner_id);
-                } else {
-                self.by_owner_id.insert(&market_data.owner_id, &by_owner_id);
-                }
-            }
-            market_data
-        })
+        let initial_storage_usage = env::storage_usage();
+        self.bundle_listing_approvals
+            .insert(&make_bundle_token_key(bundle_id, &nft_contract_id, &token_id), &approval_id);
+        self.internal_update_storage_used(&owner_id, initial_storage_usage);
     }
 
     #[payable]
-    pub fn delete_market_data(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
-        let predecessor_account_id = env::predecessor_account_id();
-        if predecessor_account_id != self.owner_id {
-            assert_one_yocto();
-        }
-
-        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
-        let current_time: u64 = env::block_timestamp();
-
-        let market_data: Option<MarketData> =
-            if let Some(market_data) = self.old_market.get(&contract_and_token_id) {
-                Some(MarketData {
-                    owner_id: market_data.owner_id,
-                    approval_id: market_data.approval_id,
-                    nft_contract_id: market_data.nft_contract_id,
-                    token_id: market_data.token_id,
-                    ft_token_id: market_data.ft_token_id,
-                    price: market_data.price,
-                    bids: None,
-                    started_at: None,
-                    ended_at: None,
-                    end_price: None,
-                    accept_nft_contract_id: None,
-                    accept_token_id: None,
-                    is_auction: None,
-                })
-            } else if let Some(market_data) = self.market.get(&contract_and_token_id) {
-                Some(market_data)
-            } else {
-                None
-            };
-
-        let market_data: MarketData = market_data.expect("Paras: Market data does not exist");
-
-        assert!(
-            [market_data.owner_id.clone(), self.owner_id.clone()]
-                .contains(&predecessor_account_id),
-            "Paras: Seller or owner only"
+    pub fn delete_bundle_listing(&mut self, bundle_id: U64) {
+        assert_one_yocto();
+        let bundle_listing = self
+            .bundle_listings
+            .get(&bundle_id.0)
+            .expect("Paras: Bundle listing does not exist");
+        assert_eq!(
+            env::predecessor_account_id(),
+            bundle_listing.owner_id,
+            "Paras: Seller only"
         );
 
-        if market_data.is_auction.is_some() && predecessor_account_id == self.owner_id {
-          assert!(
-            current_time >= market_data.ended_at.unwrap(),
-            "Paras: Auction has not ended yet"
-          );
-        }
-
-        self.internal_delete_market_data(&nft_contract_id, &token_id);
+        self.internal_remove_bundle_listing(bundle_id.0);
 
-        env::log_str(
-            &json!({
-                "type": "delete_market_data",
-                "params": {
-                    "owner_id": market_data.owner_id,
-                    "nft_contract_id": nft_contract_id,
-                    "token_id": token_id,
-                }
-            })
-            .to_string(),
-        );
+        MarketEvent::DeleteBundleListing(BundleListingLog {
+            bundle_id,
+            bundle_listing,
+        })
+        .emit();
     }
 
-    // Storage
-
+    /// Buys every token of the bundle, paid with the attached deposit and the internal balance.
+    /// The tokens are escrowed with nft_transfer_payout and only released to the buyer and paid
+    /// out once all of them are escrowed, otherwise they are returned and the price is refunded.
     #[payable]
-    pub fn storage_deposit(&mut self, account_id: Option<AccountId>) {
-        let storage_account_id = account_id
-            .map(|a| a.into())
-            .unwrap_or_else(env::predecessor_account_id);
-        let deposit = env::attached_deposit();
-        assert!(
-            deposit >= STORAGE_ADD_MARKET_DATA,
-            "Requires minimum deposit of {}",
-            STORAGE_ADD_MARKET_DATA
+    pub fn buy_bundle(&mut self, bundle_id: U64, referrer_id: Option<AccountId>) -> Promise {
+        let bundle_listing = self
+            .bundle_listings
+            .get(&bundle_id.0)
+            .expect("Paras: Bundle listing does not exist");
+
+        let buyer_id = env::predecessor_account_id();
+        assert_ne!(
+            buyer_id, bundle_listing.owner_id,
+            "Paras: Cannot buy your own sale"
         );
+        assert_valid_referrer(&referrer_id, &buyer_id);
 
-        let mut balance: u128 = self.storage_deposits.get(&storage_account_id).unwrap_or(0);
-        balance += deposit;
-        self.storage_deposits.insert(&storage_account_id, &balance);
-    }
+        // ft listings are paid from the internal balance
+        if bundle_listing.ft_token_id != near_account() {
+            assert_eq!(
+                env::attached_deposit(),
+                0,
+                "Paras: Deposit {} to the internal balance to buy this bundle",
+                bundle_listing.ft_token_id
+            );
+        }
 
-    #[payable]
-    pub fn storage_withdraw(&mut self) {
-        assert_one_yocto();
-        let owner_id = env::predecessor_account_id();
-        let mut amount = self.storage_deposits.remove(&owner_id).unwrap_or(0);
-        let market_data_owner = self.by_owner_id.get(&owner_id);
-        let len = market_data_owner.map(|s| s.len()).unwrap_or_default();
-        let diff = u128::from(len) * STORAGE_ADD_MARKET_DATA;
-        amount -= diff;
-        if amount > 0 {
-            self.internal_transfer_near(owner_id.clone(), amount);
+        let mut escrow_tokens = vec![];
+        for token in bundle_listing.tokens.iter() {
+            self.assert_not_paused(Operation::Buy, &token.nft_contract_id);
+            let approval_id = self
+                .bundle_listing_approvals
+                .get(&make_bundle_token_key(bundle_id.0, &token.nft_contract_id, &token.token_id))
+                .expect("Paras: Bundle listing is not fully approved");
+
+            escrow_tokens.push((
+                EscrowToken {
+                    nft_contract_id: token.nft_contract_id.clone(),
+                    token_id: token.token_id.clone(),
+                    owner_id: bundle_listing.owner_id.clone(),
+                    is_escrowed: false,
+                },
+                approval_id,
+            ));
         }
-        if diff > 0 {
-            self.storage_deposits.insert(&owner_id, &diff);
+
+        let paid_from_balance =
+            self.internal_collect_payment(&buyer_id, &bundle_listing.ft_token_id, bundle_listing.price.0);
+        self.internal_remove_bundle_listing(bundle_id.0);
+        for token in bundle_listing.tokens.iter() {
+            self.internal_delete_market_data(&token.nft_contract_id, &token.token_id);
         }
-    }
 
-    pub fn storage_minimum_balance(&self) -> U128 {
-        U128(STORAGE_ADD_MARKET_DATA)
-    }
+        let purchase = BundlePurchase {
+            token_prices: (0..bundle_listing.tokens.len())
+                .map(|index| bundle_listing.token_price(index).into())
+                .collect(),
+            ft_token_id: bundle_listing.ft_token_id,
+            price: bundle_listing.price,
+            transaction_fees: bundle_listing.transaction_fees,
+            referrer_id,
+            paid_from_balance,
+        };
 
-    pub fn storage_balance_of(&self, account_id: AccountId) -> U128 {
-        self.storage_deposits.get(&account_id).unwrap_or(0).into()
+        self.internal_start_escrow(
+            buyer_id,
+            bundle_listing.owner_id,
+            escrow_tokens,
+            None,
+            Some(bundle_id),
+            Some(purchase),
+        )
     }
 
-    // View
-
-    pub fn get_market_data(&self, nft_contract_id: AccountId, token_id: TokenId) -> MarketDataJson {
-        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
-        let market_data: Option<MarketData> =
-            if let Some(market_data) = self.old_market.get(&contract_and_token_id) {
-                Some(MarketData {
-                    owner_id: market_data.owner_id,
-                    approval_id: market_data.approval_id,
-                    nft_contract_id: market_data.nft_contract_id,
-                    token_id: market_data.token_id,
-                    ft_token_id: market_data.ft_token_id,
-                    price: market_data.price,
-                    bids: None,
-                    started_at: None,
-                    ended_at: None,
-                    end_price: None,
-                    accept_nft_contract_id: None,
-                    accept_token_id: None,
-                    is_auction: None,
-                })
-            } else if let Some(market_data) = self.market.get(&contract_and_token_id) {
-                Some(market_data)
-            } else {
-                None
-            };
+    /// Pays out every token with its payout once all of them are escrowed, or refunds the whole
+    /// price to the buyer when any of them failed to escrow
+    fn internal_resolve_bundle_purchase(&mut self, escrow: &Escrow, purchase: BundlePurchase, is_escrowed: bool) {
+        let ft_token_id = purchase.ft_token_id;
+        let buyer_id = escrow.buyer_id.clone();
+        let owner_id = escrow.seller_id.clone();
+        let mut sold_tokens = vec![];
+        let mut refund = 0;
+
+        if is_escrowed {
+            let mut payouts = vec![];
+            for (index, token) in escrow.tokens.iter().enumerate() {
+                let token_price = purchase.token_prices[index].0;
+
+                // a bad payout from the nft contract pays the whole share to the owner
+                let payout = match env::promise_result(index as u64) {
+                    PromiseResult::Successful(value) => parse_payout(&value, token_price),
+                    _ => None,
+                }
+                .unwrap_or_else(|| {
+                    let mut payout = PayoutHashMap::new();
+                    payout.insert(owner_id.clone(), token_price.into());
+                    payout
+                });
+
+                let treasury_fee = token_price * purchase.transaction_fees[index].0
+                    / (MAX_TREASURY_PERCENTAGE as u128);
+                for (receiver_id, amount) in payout {
+                    if receiver_id == owner_id {
+                        let amount_after = amount.0.saturating_sub(treasury_fee);
+                        if amount_after > 0 {
+                            payouts.push((receiver_id, amount_after));
+                        }
 
-        let market_data = market_data.expect("Paras: Market data does not exist");
+                        if treasury_fee > 0 {
+                            self.internal_add_treasury_fee(
+                                &ft_token_id,
+                                amount.0.min(treasury_fee),
+                                purchase.referrer_id.clone(),
+                                &buyer_id,
+                                &token.nft_contract_id,
+                                &token.token_id,
+                            );
+                        }
+                    } else {
+                        payouts.push((receiver_id, amount.0));
+                    }
+                }
 
-        let price = market_data.price;
+                let seller_contract_account_id_token_id =
+                    make_triple(&token.nft_contract_id, &owner_id, &token.token_id);
+                self.internal_remove_trade_list(&seller_contract_account_id_token_id, &owner_id);
+                sold_tokens.push(BundleToken {
+                    nft_contract_id: token.nft_contract_id.clone(),
+                    token_id: token.token_id.clone(),
+                });
+            }
+            self.internal_transfer_payout(&ft_token_id, payouts);
+        } else {
+            refund = purchase.price.0;
+            if purchase.paid_from_balance || ft_token_id != near_account() {
+                self.internal_add_claimable_balance(&buyer_id, &ft_token_id, refund);
+            } else {
+                self.internal_transfer_near(buyer_id.clone(), refund);
+            }
+        }
 
-        let current_transaction_fee = self.get_market_data_transaction_fee(&market_data.nft_contract_id, &market_data.token_id);
+        MarketEvent::ResolveBundlePurchase(BundlePurchaseLog {
+            bundle_id: escrow.bundle_id.unwrap(),
+            owner_id,
+            buyer_id,
+            tokens: sold_tokens,
+            ft_token_id,
+            price: purchase.price,
+            refund: refund.into(),
+        })
+        .emit();
+    }
 
-        MarketDataJson {
-            owner_id: market_data.owner_id,
-            approval_id: market_data.approval_id.into(),
-            nft_contract_id: market_data.nft_contract_id,
-            token_id: market_data.token_id,
-            ft_token_id: market_data.ft_token_id, // "near" for NEAR token
-            price: price.into(),
-            bids: market_data.bids,
-            started_at: market_data.started_at.map(|x| x.into()),
-            ended_at: market_data.ended_at.map(|x| x.into()),
-            end_price: market_data.end_price.map(|x| x.into()),
-            is_auction: market_data.is_auction,
-            transaction_fee: current_transaction_fee.into()
+    fn internal_remove_bundle_listing(&mut self, bundle_id: u64) -> BundleListing {
+        let initial_storage_usage = env::storage_usage();
+        let bundle_listing = self
+            .bundle_listings
+            .remove(&bundle_id)
+            .expect("Paras: Bundle listing does not exist");
+        for token in bundle_listing.tokens.iter() {
+            self.bundle_listing_approvals
+                .remove(&make_bundle_token_key(bundle_id, &token.nft_contract_id, &token.token_id));
         }
-    }
+        self.internal_update_storage_used(&bundle_listing.owner_id, initial_storage_usage);
 
-    pub fn approved_nft_contract_ids(&self) -> Vec<AccountId> {
-        self.approved_nft_contract_ids.to_vec()
+        bundle_listing
     }
 
-    pub fn get_owner(&self) -> AccountId {
-        self.owner_id.clone()
-    }
+    pub fn get_bundle_listing(&self, bundle_id: U64) -> BundleListingJson {
+        let bundle_listing = self
+            .bundle_listings
+            .get(&bundle_id.0)
+            .expect("Paras: Bundle listing does not exist");
 
-    pub fn get_treasury(&self) -> AccountId {
-        self.treasury_id.clone()
+        self.internal_bundle_listing_json(bundle_id.0, bundle_listing)
     }
 
-    pub fn get_supply_by_owner_id(&self, account_id: AccountId) -> U64 {
-        self.by_owner_id
-            .get(&account_id)
-            .map_or(0, |by_owner_id| by_owner_id.len())
-            .into()
+    pub fn get_bundle_listings(&self, from_index: Option<U64>, limit: Option<u64>) -> Vec<BundleListingJson> {
+        self.bundle_listings
+            .iter()
+            .skip(from_index.map(From::from).unwrap_or(0u64) as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .map(|(bundle_id, bundle_listing)| self.internal_bundle_listing_json(bundle_id, bundle_listing))
+            .collect()
     }
 
-    // private fn
-
-    fn assert_owner(&self) {
-        assert_eq!(
-            env::predecessor_account_id(),
-            self.owner_id,
-            "Paras: Owner only"
-        )
+    fn internal_bundle_listing_json(&self, bundle_id: u64, bundle_listing: BundleListing) -> BundleListingJson {
+        BundleListingJson {
+            bundle_id: bundle_id.into(),
+            owner_id: bundle_listing.owner_id,
+            tokens: bundle_tokens_json(&self.bundle_listing_approvals, bundle_id, bundle_listing.tokens),
+            ft_token_id: bundle_listing.ft_token_id,
+            price: bundle_listing.price,
+            transaction_fees: bundle_listing.transaction_fees,
+        }
     }
 
-    fn internal_transfer_near(&self, account_id: AccountId, amount: Balance){
-        let balance = env::account_balance();
-        if balance < amount {
+    // Auction bids
+    #[payable]
+    pub fn add_bid(
+        &mut self,
+        nft_contract_id: AccountId,
+        ft_token_id: AccountId,
+        token_id: TokenId,
+        amount: U128,
+        referrer_id: Option<AccountId>,
+    ) {
+        self.assert_not_paused(Operation::Bid, &nft_contract_id);
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let mut market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .expect("Paras: Token id does not exist");
+
+        assert_eq!(market_data.is_auction.unwrap(), true, "Paras: not auction");
+
+        let bidder_id = env::predecessor_account_id();
+        let current_time = env::block_timestamp();
+
+        assert!(
+            current_time >= market_data.started_at.unwrap(),
+            "Paras: Sale has not started yet"
+        );
+
+        assert!(
+            current_time <= market_data.ended_at.unwrap(),
+            "Paras: Sale has ended"
+        );
+
+        let remaining_time = market_data.ended_at.unwrap() - current_time;
+        if remaining_time <= FIVE_MINUTES {
+          let extended_ended_at = market_data.ended_at.unwrap() + FIVE_MINUTES;
+          market_data.ended_at = Some(extended_ended_at);
+
+          MarketEvent::ExtendAuction(ExtendAuctionLog {
+              nft_contract_id: nft_contract_id.clone(),
+              token_id: token_id.clone(),
+              ended_at: extended_ended_at.into(),
+          })
+          .emit();
+        }
+
+        assert_ne!(market_data.owner_id, bidder_id, "Paras: Owner cannot bid their own token");
+        assert_valid_referrer(&referrer_id, &bidder_id);
+
+        assert_eq!(ft_token_id.to_string(), "near", "Paras: Only support NEAR");
+
+        let new_bid = Bid {
+            bidder_id: bidder_id.clone(),
+            price: amount.into(),
+        };
+
+        let mut bids = market_data.bids.unwrap_or(Vec::new());
+
+        if !bids.is_empty() {
+            let current_bid = &bids[bids.len() - 1];
+
+            assert!(
+              amount.0 >= current_bid.price.0 + (current_bid.price.0 * 5 / 100),
+              "Paras: Can't pay less than or equal to current bid price + 5% : {:?}",
+              current_bid.price.0 + (current_bid.price.0 * 5 / 100)
+            );
+
+            assert!(
+                amount.0 >= market_data.price,
+                "Paras: Can't pay less than starting price: {:?}",
+                U128(market_data.price)
+            );
+
+            // Retain all elements except account_id
+            bids.retain(|bid| {
+              if bid.bidder_id == bidder_id {
+                self.internal_refund_bid(bid);
+              }
+
+              bid.bidder_id != bidder_id
+            });
+        } else {
+            assert!(
+                amount.0 >= market_data.price,
+                "Paras: Can't pay less than starting price: {:?}",
+                market_data.price
+            );
+        }
+
+        self.internal_collect_payment(&bidder_id, &near_account(), amount.0);
+
+        bids.push(new_bid);
+        market_data.bids = Some(bids);
+
+        let initial_storage_usage = env::storage_usage();
+        self.market.insert(&contract_and_token_id, &market_data);
+        let bid_key = make_triple(&nft_contract_id, &bidder_id, &token_id);
+        match referrer_id.as_ref() {
+            Some(referrer_id) => self.bid_referrer_ids.insert(&bid_key, referrer_id),
+            None => self.bid_referrer_ids.remove(&bid_key),
+        };
+        self.internal_update_storage_used(&bidder_id, initial_storage_usage);
+        self.assert_storage_covered(&bidder_id);
+
+        // Remove first element if bids.length >= 100
+        let updated_bids = market_data.bids.unwrap_or(Vec::new());
+        if updated_bids.len() >= 100 {
+          self.internal_cancel_bid(nft_contract_id.clone(), token_id.clone(), updated_bids[0].bidder_id.clone())
+        }
+
+        MarketEvent::AddBid(BidLog {
+            bidder_id,
+            nft_contract_id,
+            token_id,
+            ft_token_id,
+            amount,
+        })
+        .emit();
+    }
+
+    fn internal_cancel_bid(&mut self, nft_contract_id: AccountId, token_id: TokenId, account_id: AccountId) {
+      let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+      let mut market_data = self
+        .market
+        .get(&contract_and_token_id)
+        .expect("Paras: Token id does not exist");
+
+      let mut bids = market_data.bids.unwrap();
+
+      assert!(
+        !bids.is_empty(),
+        "Paras: Bids data does not exist"
+      );
+
+      // Retain all elements except account_id
+      bids.retain(|bid| {
+        if bid.bidder_id == account_id {
+            self.internal_refund_bid(bid);
+        }
+
+        bid.bidder_id != account_id
+      });
+
+      market_data.bids = Some(bids);
+
+      let initial_storage_usage = env::storage_usage();
+      self.market.insert(&contract_and_token_id, &market_data);
+      self.bid_referrer_ids
+        .remove(&make_triple(&nft_contract_id, &account_id, &token_id));
+      self.internal_update_storage_used(&account_id, initial_storage_usage);
+
+      MarketEvent::CancelBid(CancelBidLog {
+          bidder_id: account_id,
+          nft_contract_id,
+          token_id,
+      })
+      .emit();
+    }
+
+    #[payable]
+    pub fn cancel_bid(&mut self, nft_contract_id: AccountId, token_id: TokenId, account_id: AccountId) {
+      assert_one_yocto();
+      let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+      let market_data = self
+        .market
+        .get(&contract_and_token_id)
+        .expect("Paras: Token id does not exist");
+
+      let bids = market_data.bids.unwrap();
+
+      assert!(
+        !bids.is_empty(),
+        "Paras: Bids data does not exist"
+      );
+
+      let predecessor_account_id = env::predecessor_account_id();
+      for x in 0..bids.len() {
+        if bids[x].bidder_id == account_id {
+          assert!(
+            bids[x].bidder_id == predecessor_account_id
+              || self.internal_has_role(&predecessor_account_id, Role::Moderator),
+              "Paras: Bidder or moderator only"
+          );
+        }
+      }
+
+      self.internal_cancel_bid(nft_contract_id, token_id, account_id);
+    }
+
+    #[payable]
+    pub fn accept_bid(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
+        let predecessor_account_id = env::predecessor_account_id();
+        let is_moderator = self.internal_has_role(&predecessor_account_id, Role::Moderator);
+        if predecessor_account_id != self.owner_id {
+            assert_one_yocto();
+        }
+        self.assert_not_paused(Operation::Bid, &nft_contract_id);
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let mut market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .expect("Paras: Token id does not exist");
+
+        assert_eq!(market_data.is_auction.unwrap(), true, "Paras: not auction");
+        let current_time: u64 = env::block_timestamp();
+
+        assert!(
+            market_data.owner_id == predecessor_account_id || is_moderator,
+            "Paras: Seller or moderator only"
+        );
+
+        if is_moderator && market_data.ended_at.is_some() {
+          assert!(
+            current_time >= market_data.ended_at.unwrap(),
+            "Paras: Auction has not ended yet"
+          );
+        }
+
+        let mut bids = market_data.bids.unwrap();
+
+        assert!(!bids.is_empty(), "Paras: Cannot accept bid with empty bid");
+
+        let referrer_id = self.bid_referrer_ids.get(&make_triple(
+            &nft_contract_id,
+            &bids[bids.len() - 1].bidder_id,
+            &token_id,
+        ));
+        self.internal_release_bids_storage(&nft_contract_id, &token_id, &bids);
+        let selected_bid = bids.remove(bids.len() - 1);
+
+        // refund all except selected bids
+        for bid in &bids {
+            self.internal_refund_bid(bid);
+        }
+        bids.clear();
+
+        market_data.bids = Some(bids);
+        self.market.insert(&contract_and_token_id, &market_data);
+
+        self.internal_process_purchase(
+            market_data.nft_contract_id,
+            token_id,
+            selected_bid.bidder_id.clone(),
+            selected_bid.price.clone().0,
+            referrer_id,
+            false,
+            0,
+        );
+    }
+
+    #[payable]
+    pub fn end_auction(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
+      let predecessor_account_id = env::predecessor_account_id();
+      let is_moderator = self.internal_has_role(&predecessor_account_id, Role::Moderator);
+      if predecessor_account_id != self.owner_id {
+          assert_one_yocto();
+      }
+
+      let current_time = env::block_timestamp();
+      let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, &token_id);
+      let mut market_data = self
+          .market
+          .get(&contract_and_token_id)
+          .expect("Paras: Market data does not exist");
+
+      assert_eq!(market_data.is_auction.unwrap(), true, "Paras: not auction");
+      assert!(
+        market_data.owner_id == predecessor_account_id || is_moderator,
+        "Paras: Seller or moderator only"
+      );
+
+      if is_moderator && market_data.ended_at.is_some() {
+        assert!(
+          current_time >= market_data.ended_at.unwrap(),
+          "Paras: Auction has not ended yet (for moderator)"
+        );
+      }
+
+      let mut bids = market_data.bids.unwrap();
+
+      if bids.is_empty() {
+        self.internal_delete_market_data(&nft_contract_id, &token_id);
+
+        MarketEvent::DeleteMarketData(DeleteMarketDataLog {
+            owner_id: market_data.owner_id,
+            nft_contract_id,
+            token_id,
+        })
+        .emit();
+      } else {
+        self.assert_not_paused(Operation::Bid, &nft_contract_id);
+        let referrer_id = self.bid_referrer_ids.get(&make_triple(
+            &nft_contract_id,
+            &bids[bids.len() - 1].bidder_id,
+            &token_id,
+        ));
+        self.internal_release_bids_storage(&nft_contract_id, &token_id, &bids);
+        let selected_bid = bids.remove(bids.len() - 1);
+
+        // refund all except selected bids
+        for bid in &bids {
+            self.internal_refund_bid(bid);
+        }
+
+        bids.clear();
+
+        market_data.bids = Some(bids);
+        self.market.insert(&contract_and_token_id, &market_data);
+
+        self.internal_process_purchase(
+            nft_contract_id,
+            token_id,
+            selected_bid.bidder_id.clone(),
+            selected_bid.price.0,
+            referrer_id,
+            false,
+            0,
+        );
+      }
+    }
+
+    fn internal_add_market_data(
+        &mut self,
+        owner_id: AccountId,
+        approval_id: u64,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: AccountId,
+        price: U128,
+        mut started_at: Option<U64>,
+        ended_at: Option<U64>,
+        end_price: Option<U128>,
+        is_auction: Option<bool>,
+        accept_nft_contract_id: Option<AccountId>,
+        accept_token_id: Option<TokenId>,
+    ) {
+        let initial_storage_usage = env::storage_usage();
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+
+        let bids: Option<Bids> = match is_auction {
+            Some(u) => {
+                if u {
+                    Some(Vec::new())
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
+
+        let current_time: u64 = env::block_timestamp();
+
+        if let Some(is_auction) = is_auction {
+          if let Some(started_at) = started_at {
+              assert!(started_at.0 >= current_time);
+
+              if let Some(ended_at) = ended_at {
+                assert!(started_at.0 < ended_at.0);
+              }
+          }
+
+            if is_auction == true {
+                if started_at.is_none() {
+                    started_at = Some(U64(current_time));
+                }
+
+                assert!(ended_at.is_some(), "Paras: Ended at is none");
+
+                if let Some(ended_at) = ended_at {
+                  assert!(ended_at.0 >= current_time);
+                }
+            }
+        }
+
+        if let Some(end_price) = end_price {
+            assert!(
+                !is_auction.unwrap_or(false),
+                "Paras: End price is for dutch auction only"
+            );
+            assert!(
+                end_price.0 < price.0,
+                "Paras: End price should be lower than price"
+            );
+            assert!(ended_at.is_some(), "Paras: Ended at is none");
+
+            if started_at.is_none() {
+                started_at = Some(U64(current_time));
+            }
+
+            assert!(
+                started_at.unwrap().0 < ended_at.unwrap().0,
+                "Paras: Started at should be before ended at"
+            );
+        }
+
+        assert!(
+            price.0 < MAX_PRICE,
+            "Paras: price higher than {}",
+            MAX_PRICE
+        );
+
+        if let Some(accept_nft_contract_id) = accept_nft_contract_id.as_ref() {
+            assert!(accept_token_id.is_some(), "Paras: Accept token id is not specified");
+            assert!(
+                !is_auction.unwrap_or(false) && end_price.is_none(),
+                "Paras: NFT payment is for fixed price sales only"
+            );
+            assert!(
+                self.approved_nft_contract_ids.contains(accept_nft_contract_id),
+                "Paras: accept_nft_contract_id is not approved"
+            );
+        }
+
+        self.market.insert(
+            &contract_and_token_id,
+            &MarketData {
+                owner_id: owner_id.clone().into(),
+                approval_id,
+                nft_contract_id: nft_contract_id.clone().into(),
+                token_id: token_id.clone(),
+                ft_token_id: ft_token_id.clone(),
+                price: price.into(),
+                bids,
+                started_at: match started_at {
+                    Some(x) => Some(x.0),
+                    None => None,
+                },
+                ended_at: match ended_at {
+                    Some(x) => Some(x.0),
+                    None => None,
+                },
+                end_price: match end_price {
+                    Some(x) => Some(x.0),
+                    None => None,
+                },
+                accept_nft_contract_id: accept_nft_contract_id.as_ref().map(|x| x.to_string()),
+                accept_token_id: accept_token_id.clone(),
+                is_auction,
+            },
+        );
+
+        self.internal_add_by_owner_id(&owner_id, &contract_and_token_id);
+
+        self.internal_index_market_data(&nft_contract_id, &contract_and_token_id);
+
+        // update offer trade approval_id
+        let owner_contract_account_id_token_id =
+            make_triple(&nft_contract_id, &owner_id, &token_id);
+        let trade_data = self.trades.get(&owner_contract_account_id_token_id);
+        if let Some(mut trade_list) = trade_data {
+            trade_list.approval_id = approval_id;
+            self.trades
+                .insert(&owner_contract_account_id_token_id, &trade_list);
+        }
+
+
+        // set market data transaction fee
+        let current_transaction_fee = self.calculate_nft_contract_transaction_fee(&nft_contract_id);
+        self.market_data_transaction_fee.transaction_fee.insert(&contract_and_token_id, &current_transaction_fee);
+
+        self.internal_update_storage_used(&owner_id, initial_storage_usage);
+
+        MarketEvent::AddMarketData(AddMarketDataLog {
+            owner_id,
+            approval_id: approval_id.into(),
+            nft_contract_id,
+            token_id,
+            ft_token_id,
+            price,
+            started_at,
+            ended_at,
+            end_price,
+            is_auction,
+            accept_nft_contract_id,
+            accept_token_id,
+            transaction_fee: current_transaction_fee.into(),
+        })
+        .emit();
+    }
+
+    fn internal_delete_market_data(
+        &mut self,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+    ) -> Option<MarketData> {
+        let initial_storage_usage = env::storage_usage();
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+
+        let market_data: Option<MarketData> =
+            if let Some(market_data) = self.old_market.get(&contract_and_token_id) {
+                self.old_market.remove(&contract_and_token_id);
+                Some(MarketData {
+                    owner_id: market_data.owner_id,
+                    approval_id: market_data.approval_id,
+                    nft_contract_id: market_data.nft_contract_id,
+                    token_id: market_data.token_id,
+                    ft_token_id: market_data.ft_token_id,
+                    price: market_data.price,
+                    bids: None,
+                    started_at: None,
+                    ended_at: None,
+                    end_price: None,
+                    accept_nft_contract_id: None,
+                    accept_token_id: None,
+                    is_auction: None,
+                })
+            } else if let Some(market_data) = self.market.get(&contract_and_token_id) {
+                self.market.remove(&contract_and_token_id);
+
+                if let Some(ref bids) = market_data.bids {
+                    self.internal_release_bids_storage(&market_data.nft_contract_id, &market_data.token_id, bids);
+                };
+
+                Some(market_data)
+            } else {
+                None
+            };
+
+        market_data.map(|market_data| {
+            let is_legacy = self.internal_remove_by_owner_id(&market_data.owner_id, &contract_and_token_id);
+            if let Some(mut by_nft_contract_id) = self.by_nft_contract_id.get(&market_data.nft_contract_id) {
+                by_nft_contract_id.remove(&contract_and_token_id);
+                if by_nft_contract_id.is_empty() {
+                    self.by_nft_contract_id.remove(&market_data.nft_contract_id);
+                } else {
+                    self.by_nft_contract_id.insert(&market_data.nft_contract_id, &by_nft_contract_id);
+                }
+            }
+
+            // the fee locked by the listing goes with it
+            self.market_data_transaction_fee.transaction_fee.remove(&contract_and_token_id);
+
+            // bids were already released to their bidders
+            if !is_legacy {
+                let bids_storage_usage = market_data.bids.as_ref().map_or(0, |bids| bids_storage_usage(bids));
+                self.internal_update_storage_used(
+                    &market_data.owner_id,
+                    initial_storage_usage - bids_storage_usage,
+                );
+            }
+
+            // the refunds are charged to the bidders
+            if let Some(ref bids) = market_data.bids {
+                for bid in bids {
+                    self.internal_refund_bid(bid);
+                }
+            }
+
+            market_data
+        })
+    }
+
+    #[payable]
+    pub fn delete_market_data(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
+        let predecessor_account_id = env::predecessor_account_id();
+        let is_moderator = self.internal_has_role(&predecessor_account_id, Role::Moderator);
+        if predecessor_account_id != self.owner_id {
+            assert_one_yocto();
+        }
+
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let current_time: u64 = env::block_timestamp();
+
+        let market_data: Option<MarketData> =
+            if let Some(market_data) = self.old_market.get(&contract_and_token_id) {
+                Some(MarketData {
+                    owner_id: market_data.owner_id,
+                    approval_id: market_data.approval_id,
+                    nft_contract_id: market_data.nft_contract_id,
+                    token_id: market_data.token_id,
+                    ft_token_id: market_data.ft_token_id,
+                    price: market_data.price,
+                    bids: None,
+                    started_at: None,
+                    ended_at: None,
+                    end_price: None,
+                    accept_nft_contract_id: None,
+                    accept_token_id: None,
+                    is_auction: None,
+                })
+            } else if let Some(market_data) = self.market.get(&contract_and_token_id) {
+                Some(market_data)
+            } else {
+                None
+            };
+
+        let market_data: MarketData = market_data.expect("Paras: Market data does not exist");
+
+        assert!(
+            market_data.owner_id == predecessor_account_id || is_moderator,
+            "Paras: Seller or moderator only"
+        );
+
+        if market_data.is_auction.is_some() && is_moderator {
+          assert!(
+            current_time >= market_data.ended_at.unwrap(),
+            "Paras: Auction has not ended yet"
+          );
+        }
+
+        self.internal_delete_market_data(&nft_contract_id, &token_id);
+
+        MarketEvent::DeleteMarketData(DeleteMarketDataLog {
+            owner_id: market_data.owner_id,
+            nft_contract_id,
+            token_id,
+        })
+        .emit();
+    }
+
+    // Storage
+
+    pub fn storage_minimum_balance(&self) -> U128 {
+        U128(STORAGE_ADD_MARKET_DATA)
+    }
+
+    /// bytes currently paid for by account_id
+    pub fn get_storage_used(&self, account_id: AccountId) -> U64 {
+        self.storage_used.get(&account_id).unwrap_or(0).into()
+    }
+
+    fn internal_storage_required(&self, account_id: &AccountId) -> Balance {
+        // entries made before migrate keep the flat per-entry deposit they were made with
+        let legacy_entries = self
+            .legacy_by_owner_id
+            .get(account_id)
+            .map_or(0, |legacy_by_owner_id| legacy_by_owner_id.len());
+
+        self.storage_used.get(account_id).unwrap_or(0) as Balance * env::storage_byte_cost()
+            + legacy_entries as Balance * STORAGE_ADD_MARKET_DATA
+    }
+
+    fn internal_add_by_owner_id(&mut self, account_id: &AccountId, key: &String) {
+        // an entry replaced after migrate is charged like a new one
+        self.internal_remove_legacy_by_owner_id(account_id, key);
+
+        let mut keys = self.by_owner_id.get(account_id).unwrap_or_else(|| {
+            UnorderedSet::new(
+                StorageKey::ByOwnerIdInnerV2 {
+                    account_id_hash: hash_account_id(account_id),
+                }
+                .try_to_vec()
+                .unwrap(),
+            )
+        });
+        keys.insert(key);
+        self.by_owner_id.insert(account_id, &keys);
+    }
+
+    /// Removes key from the owner index, returns true if it was made before migrate and
+    /// so its bytes were never charged to storage_used
+    fn internal_remove_by_owner_id(&mut self, account_id: &AccountId, key: &String) -> bool {
+        if self.internal_remove_legacy_by_owner_id(account_id, key) {
+            return true;
+        }
+
+        if let Some(mut keys) = self.by_owner_id.get(account_id) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.by_owner_id.remove(account_id);
+            } else {
+                self.by_owner_id.insert(account_id, &keys);
+            }
+        }
+        false
+    }
+
+    fn internal_remove_legacy_by_owner_id(&mut self, account_id: &AccountId, key: &String) -> bool {
+        let mut keys = match self.legacy_by_owner_id.get(account_id) {
+            Some(keys) => keys,
+            None => return false,
+        };
+        if !keys.remove(key) {
+            return false;
+        }
+        if keys.is_empty() {
+            self.legacy_by_owner_id.remove(account_id);
+        } else {
+            self.legacy_by_owner_id.insert(account_id, &keys);
+        }
+        true
+    }
+
+    /// Entries of account_id made before migrate followed by the ones made after
+    fn internal_by_owner_id_keys(&self, account_id: &AccountId, from_index: u64, limit: u64) -> Vec<String> {
+        let legacy_keys = self.legacy_by_owner_id.get(account_id);
+        let keys = self.by_owner_id.get(account_id);
+
+        legacy_keys
+            .iter()
+            .flat_map(|legacy_keys| legacy_keys.iter())
+            .chain(keys.iter().flat_map(|keys| keys.iter()))
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    fn internal_by_owner_id_len(&self, account_id: &AccountId) -> u64 {
+        self.legacy_by_owner_id.get(account_id).map_or(0, |keys| keys.len())
+            + self.by_owner_id.get(account_id).map_or(0, |keys| keys.len())
+    }
+
+    fn internal_is_storage_covered(&self, account_id: &AccountId) -> bool {
+        self.storage_deposits.get(account_id).unwrap_or(0) >= self.internal_storage_required(account_id)
+    }
+
+    fn assert_storage_covered(&self, account_id: &AccountId) {
+        let owner_paid_storage = self.storage_deposits.get(account_id).unwrap_or(0);
+        let storage_required = self.internal_storage_required(account_id);
+
+        assert!(
+            owner_paid_storage >= storage_required,
+            "Paras: Insufficient storage paid: {}, required {} for {} bytes",
+            owner_paid_storage,
+            storage_required,
+            self.storage_used.get(account_id).unwrap_or(0),
+        );
+    }
+
+    /// Charges account_id for the bytes written since initial_storage_usage,
+    /// or releases the bytes freed since then
+    fn internal_update_storage_used(&mut self, account_id: &AccountId, initial_storage_usage: StorageUsage) {
+        let storage_used = match self.storage_used.get(account_id) {
+            Some(storage_used) => storage_used,
+            None => {
+                // the entry itself is paid by the account as well
+                self.storage_used.insert(account_id, &0);
+                0
+            }
+        };
+
+        let current_storage_usage = env::storage_usage();
+        let storage_used = if current_storage_usage >= initial_storage_usage {
+            storage_used + (current_storage_usage - initial_storage_usage)
+        } else {
+            storage_used.saturating_sub(initial_storage_usage - current_storage_usage)
+        };
+
+        self.storage_used.insert(account_id, &storage_used);
+    }
+
+    fn internal_release_bids_storage(&mut self, nft_contract_id: &AccountId, token_id: &TokenId, bids: &Bids) {
+        for bid in bids {
+            let initial_storage_usage = env::storage_usage();
+            self.bid_referrer_ids
+                .remove(&make_triple(nft_contract_id, &bid.bidder_id, token_id));
+            let released_storage_usage = initial_storage_usage - env::storage_usage();
+
+            if let Some(storage_used) = self.storage_used.get(&bid.bidder_id) {
+                let bid_storage_usage = bids_storage_usage(std::slice::from_ref(bid)) + released_storage_usage;
+                self.storage_used
+                    .insert(&bid.bidder_id, &storage_used.saturating_sub(bid_storage_usage));
+            }
+        }
+    }
+
+    /// pays referral_fee_share of the treasury fee to the referrer and accrues the rest
+    /// in the treasury ledger until withdraw_treasury
+    fn internal_add_treasury_fee(
+        &mut self,
+        ft_token_id: &AccountId,
+        treasury_fee: u128,
+        referrer_id: Option<AccountId>,
+        buyer_id: &AccountId,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+    ) {
+        let referral_fee = match referrer_id.as_ref() {
+            Some(_) => treasury_fee * self.referral_fee_share as u128 / (MAX_TREASURY_PERCENTAGE as u128),
+            None => 0,
+        };
+
+        if referral_fee > 0 {
+            let referrer_id = referrer_id.unwrap();
+            self.internal_transfer(ft_token_id, referrer_id.clone(), referral_fee);
+
+            MarketEvent::ReferralFee(ReferralFeeLog {
+                referrer_id,
+                buyer_id: buyer_id.clone(),
+                nft_contract_id: nft_contract_id.clone(),
+                token_id: token_id.clone(),
+                ft_token_id: ft_token_id.clone(),
+                amount: referral_fee.into(),
+            })
+            .emit();
+        }
+
+        if treasury_fee > referral_fee {
+            let balance = self.treasury_balances.get(ft_token_id).unwrap_or(0);
+            self.treasury_balances
+                .insert(ft_token_id, &(balance + treasury_fee - referral_fee));
+        }
+    }
+
+    // Treasury
+
+    /// Sends the accrued treasury fees of ft_token_id to the fee beneficiaries
+    #[payable]
+    pub fn withdraw_treasury(&mut self, ft_token_id: AccountId, amount: Option<U128>) -> U128 {
+        assert_one_yocto();
+        self.assert_role(Role::Admin);
+
+        let balance = self.treasury_balances.get(&ft_token_id).unwrap_or(0);
+        let amount = amount.map_or(balance, |amount| amount.0);
+        assert!(amount > 0, "Paras: Nothing to withdraw");
+        assert!(
+            amount <= balance,
+            "Paras: Amount exceeds the treasury balance: {}",
+            balance
+        );
+
+        if amount == balance {
+            self.treasury_balances.remove(&ft_token_id);
+        } else {
+            self.treasury_balances.insert(&ft_token_id, &(balance - amount));
+        }
+
+        let mut remaining_amount = amount;
+        let fee_beneficiaries = self.get_fee_beneficiaries();
+        for (i, beneficiary) in fee_beneficiaries.iter().enumerate() {
+            // the last beneficiary also receives the rounding remainder
+            let beneficiary_amount = if i == fee_beneficiaries.len() - 1 {
+                remaining_amount
+            } else {
+                amount * beneficiary.weight as u128 / (MAX_TREASURY_PERCENTAGE as u128)
+            };
+            remaining_amount -= beneficiary_amount;
+
+            if beneficiary_amount > 0 {
+                self.internal_transfer(&ft_token_id, beneficiary.account_id.clone(), beneficiary_amount);
+
+                MarketEvent::WithdrawTreasury(TransferLog {
+                    account_id: beneficiary.account_id.clone(),
+                    ft_token_id: ft_token_id.clone(),
+                    amount: U128(beneficiary_amount),
+                })
+                .emit();
+            }
+        }
+
+        U128(amount)
+    }
+
+    pub fn get_treasury_balance(&self, ft_token_id: AccountId) -> U128 {
+        self.treasury_balances.get(&ft_token_id).unwrap_or(0).into()
+    }
+
+    pub fn get_treasury_balances(&self) -> Vec<(AccountId, U128)> {
+        self.treasury_balances
+            .iter()
+            .map(|(ft_token_id, balance)| (ft_token_id, U128(balance)))
+            .collect()
+    }
+
+    // Claimable balances
+
+    /// Called after every near and ft transfer, ft_token_id is "near" for NEAR
+    #[private]
+    pub fn resolve_ft_transfer(
+        &mut self,
+        ft_token_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        if is_promise_success() {
+            return amount;
+        }
+
+        // keep the failed transfer claimable instead of stranding it in the contract
+        self.internal_add_failed_transfer_balance(&receiver_id, &ft_token_id, amount.0);
+
+        MarketEvent::ResolveTransferFail(TransferLog {
+            account_id: receiver_id,
+            ft_token_id,
+            amount,
+        })
+        .emit();
+        U128(0)
+    }
+
+    /// Called after the near payouts of a sale, credits every failed one to its receiver
+    #[private]
+    pub fn resolve_near_payouts(&mut self, payouts: Vec<(AccountId, U128)>) {
+        for (index, (receiver_id, amount)) in payouts.into_iter().enumerate() {
+            if let PromiseResult::Failed = env::promise_result(index as u64) {
+                self.internal_add_failed_transfer_balance(&receiver_id, &near_account(), amount.0);
+
+                MarketEvent::ResolveTransferFail(TransferLog {
+                    account_id: receiver_id,
+                    ft_token_id: near_account(),
+                    amount,
+                })
+                .emit();
+            }
+        }
+    }
+
+    /// Withdraws amount, or the whole internal balance, of ft_token_id
+    #[payable]
+    pub fn claim(&mut self, ft_token_id: AccountId, amount: Option<U128>) -> U128 {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let balance = self
+            .claimable_balances
+            .get(&make_claimable_key(&account_id, &ft_token_id))
+            .expect("Paras: Nothing to claim");
+        let amount = amount.map_or(balance, |amount| amount.0);
+
+        self.internal_withdraw_claimable_balance(&account_id, &ft_token_id, amount);
+
+        self.internal_transfer(&ft_token_id, account_id.clone(), amount);
+
+        MarketEvent::Claim(TransferLog {
+            account_id: account_id.clone(),
+            ft_token_id: ft_token_id.clone(),
+            amount: U128(amount),
+        })
+        .emit();
+        U128(amount)
+    }
+
+    /// Adds the attached deposit to the internal NEAR balance of the caller
+    #[payable]
+    pub fn deposit(&mut self) -> U128 {
+        let account_id = env::predecessor_account_id();
+        self.internal_deposit(&account_id, &near_account(), env::attached_deposit())
+    }
+
+    fn internal_deposit(&mut self, account_id: &AccountId, ft_token_id: &AccountId, amount: Balance) -> U128 {
+        assert!(amount > 0, "Paras: Deposit should be more than 0");
+
+        assert!(
+            self.internal_credit_claimable_balance(account_id, ft_token_id, amount),
+            "Paras: Insufficient storage paid for the internal balance"
+        );
+
+        MarketEvent::Deposit(TransferLog {
+            account_id: account_id.clone(),
+            ft_token_id: ft_token_id.clone(),
+            amount: U128(amount),
+        })
+        .emit();
+
+        self.get_claimable_balance(account_id.clone(), ft_token_id.clone())
+    }
+
+    /// Credits amount to the internal balance of account_id, or transfers it when the storage
+    /// deposit of account_id does not cover a new entry
+    fn internal_add_claimable_balance(&mut self, account_id: &AccountId, ft_token_id: &AccountId, amount: Balance) {
+        if !self.internal_credit_claimable_balance(account_id, ft_token_id, amount) {
+            self.internal_transfer(ft_token_id, account_id.clone(), amount);
+        }
+    }
+
+    /// Keeps a failed transfer claimable, transferring it again would fail the same way.
+    /// The contract pays for a new entry that the storage deposit of account_id does not cover.
+    fn internal_add_failed_transfer_balance(&mut self, account_id: &AccountId, ft_token_id: &AccountId, amount: Balance) {
+        if !self.internal_credit_claimable_balance(account_id, ft_token_id, amount) {
+            let key = make_claimable_key(account_id, ft_token_id);
+            self.claimable_balances.insert(&key, &amount);
+            self.sponsored_claimable_balances.insert(&key);
+        }
+    }
+
+    /// Credits an existing entry, or a new one charged to account_id when its storage deposit
+    /// covers it. Returns false and leaves the storage untouched otherwise.
+    fn internal_credit_claimable_balance(&mut self, account_id: &AccountId, ft_token_id: &AccountId, amount: Balance) -> bool {
+        let key = make_claimable_key(account_id, ft_token_id);
+        if let Some(balance) = self.claimable_balances.get(&key) {
+            self.claimable_balances.insert(&key, &(balance + amount));
+            return true;
+        }
+
+        let storage_used = self.storage_used.get(account_id);
+        let initial_storage_usage = env::storage_usage();
+        self.claimable_balances.insert(&key, &amount);
+        self.internal_update_storage_used(account_id, initial_storage_usage);
+        if self.internal_is_storage_covered(account_id) {
+            return true;
+        }
+
+        self.claimable_balances.remove(&key);
+        match storage_used {
+            Some(storage_used) => self.storage_used.insert(account_id, &storage_used),
+            None => self.storage_used.remove(account_id),
+        };
+        false
+    }
+
+    fn internal_withdraw_claimable_balance(&mut self, account_id: &AccountId, ft_token_id: &AccountId, amount: Balance) {
+        let key = make_claimable_key(account_id, ft_token_id);
+        let balance = self.claimable_balances.get(&key).unwrap_or(0);
+        assert!(
+            amount <= balance,
+            "Paras: Insufficient {} balance: {}, required {}",
+            ft_token_id,
+            balance,
+            amount
+        );
+
+        if amount == balance {
+            let initial_storage_usage = env::storage_usage();
+            self.claimable_balances.remove(&key);
+            // an entry paid by the contract was never charged to account_id
+            if !self.sponsored_claimable_balances.remove(&key) {
+                self.internal_update_storage_used(account_id, initial_storage_usage);
+            }
+        } else {
+            self.claimable_balances.insert(&key, &(balance - amount));
+        }
+    }
+
+    // bids are refunded to the internal balance, settling an auction can refund up to 99 bids
+    fn internal_refund_bid(&mut self, bid: &Bid) {
+        self.internal_add_claimable_balance(&bid.bidder_id, &near_account(), bid.price.0);
+
+        MarketEvent::RefundBid(TransferLog {
+            account_id: bid.bidder_id.clone(),
+            ft_token_id: near_account(),
+            amount: bid.price,
+        })
+        .emit();
+    }
+
+    // View
+
+    pub fn get_claimable_balance(&self, account_id: AccountId, ft_token_id: AccountId) -> U128 {
+        self.claimable_balances
+            .get(&make_claimable_key(&account_id, &ft_token_id))
+            .unwrap_or(0)
+            .into()
+    }
+
+    pub fn get_market_data(&self, nft_contract_id: AccountId, token_id: TokenId) -> MarketDataJson {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let market_data: Option<MarketData> =
+            if let Some(market_data) = self.old_market.get(&contract_and_token_id) {
+                Some(MarketData {
+                    owner_id: market_data.owner_id,
+                    approval_id: market_data.approval_id,
+                    nft_contract_id: market_data.nft_contract_id,
+                    token_id: market_data.token_id,
+                    ft_token_id: market_data.ft_token_id,
+                    price: market_data.price,
+                    bids: None,
+                    started_at: None,
+                    ended_at: None,
+                    end_price: None,
+                    accept_nft_contract_id: None,
+                    accept_token_id: None,
+                    is_auction: None,
+                })
+            } else if let Some(market_data) = self.market.get(&contract_and_token_id) {
+                Some(market_data)
+            } else {
+                None
+            };
+
+        let market_data = market_data.expect("Paras: Market data does not exist");
+
+        self.internal_market_data_json(market_data)
+    }
+
+    pub fn get_market_data_by_owner_id(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<MarketDataJson> {
+        // by_owner_id also keeps offers and trades of the account, a page can hold fewer listings than limit
+        self.internal_by_owner_id_keys(&account_id, from_index.map(From::from).unwrap_or(0u64), limit.unwrap_or(50))
+            .into_iter()
+            .filter_map(|contract_and_token_id| self.internal_get_market_data(&contract_and_token_id))
+            .map(|market_data| self.internal_market_data_json(market_data))
+            .collect()
+    }
+
+    pub fn get_market_data_by_nft_contract_id(
+        &self,
+        nft_contract_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<MarketDataJson> {
+        let by_nft_contract_id = match self.by_nft_contract_id.get(&nft_contract_id) {
+            Some(by_nft_contract_id) => by_nft_contract_id,
+            None => return vec![],
+        };
+
+        by_nft_contract_id
+            .iter()
+            .skip(from_index.map(From::from).unwrap_or(0u64) as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .filter_map(|contract_and_token_id| self.internal_get_market_data(&contract_and_token_id))
+            .map(|market_data| self.internal_market_data_json(market_data))
+            .collect()
+    }
+
+    pub fn get_supply_by_nft_contract_id(&self, nft_contract_id: AccountId) -> U64 {
+        self.by_nft_contract_id
+            .get(&nft_contract_id)
+            .map_or(0, |by_nft_contract_id| by_nft_contract_id.len())
+            .into()
+    }
+
+    pub fn get_offers_by_buyer_id(
+        &self,
+        buyer_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<OfferDataJson> {
+        self.internal_by_owner_id_keys(&buyer_id, from_index.map(From::from).unwrap_or(0u64), limit.unwrap_or(50))
+            .into_iter()
+            .filter_map(|contract_account_id_token_id| {
+                self.offers
+                    .get(&contract_account_id_token_id)
+                    .map(|offer_data| self.internal_offer_data_json(&contract_account_id_token_id, offer_data))
+            })
+            .collect()
+    }
+
+    /// token_id is either a token_id or a Paras token_series_id
+    pub fn get_offers_by_token(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<OfferDataJson> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let offer_ids = match self.offers_by_token.get(&contract_and_token_id) {
+            Some(offer_ids) => offer_ids,
+            None => return vec![],
+        };
+
+        offer_ids
+            .iter()
+            .skip(from_index.map(From::from).unwrap_or(0u64) as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .filter_map(|contract_account_id_token_id| {
+                self.offers
+                    .get(&contract_account_id_token_id)
+                    .map(|offer_data| self.internal_offer_data_json(&contract_account_id_token_id, offer_data))
+            })
+            .collect()
+    }
+
+    pub fn get_supply_offers_by_token(&self, nft_contract_id: AccountId, token_id: TokenId) -> U64 {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.offers_by_token
+            .get(&contract_and_token_id)
+            .map_or(0, |offer_ids| offer_ids.len())
+            .into()
+    }
+
+    pub fn get_trades_by_owner_id(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<TradeListJson> {
+        let trade_ids = match self.trades_by_owner_id.get(&account_id) {
+            Some(trade_ids) => trade_ids,
+            None => return vec![],
+        };
+
+        trade_ids
+            .iter()
+            .skip(from_index.map(From::from).unwrap_or(0u64) as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .filter_map(|buyer_contract_account_id_token_id| {
+                let trade_list = self.trades.get(&buyer_contract_account_id_token_id)?;
+                // nft_contract_id||account_id||token_id
+                let mut key_iter = buyer_contract_account_id_token_id.splitn(3, DELIMETER);
+                let buyer_nft_contract_id = key_iter.next()?.parse().ok()?;
+                let buyer_id = key_iter.next()?.parse().ok()?;
+                let buyer_token_id = key_iter.next()?.to_string();
+
+                Some(TradeListJson {
+                    buyer_id,
+                    buyer_nft_contract_id,
+                    buyer_token_id,
+                    approval_id: trade_list.approval_id.into(),
+                    trade_data: trade_list.trade_data.into_values().collect(),
+                })
+            })
+            .collect()
+    }
+
+    fn internal_market_data_json(&self, market_data: MarketData) -> MarketDataJson {
+        let price = market_data.price;
+
+        let current_transaction_fee = self.get_market_data_transaction_fee(&market_data.nft_contract_id, &market_data.token_id);
+
+        MarketDataJson {
+            owner_id: market_data.owner_id,
+            approval_id: market_data.approval_id.into(),
+            nft_contract_id: market_data.nft_contract_id,
+            token_id: market_data.token_id,
+            ft_token_id: market_data.ft_token_id, // "near" for NEAR token
+            price: price.into(),
+            bids: market_data.bids,
+            started_at: market_data.started_at.map(|x| x.into()),
+            ended_at: market_data.ended_at.map(|x| x.into()),
+            end_price: market_data.end_price.map(|x| x.into()),
+            accept_nft_contract_id: market_data.accept_nft_contract_id.map(|x| x.parse().unwrap()),
+            accept_token_id: market_data.accept_token_id,
+            is_auction: market_data.is_auction,
+            transaction_fee: current_transaction_fee.into()
+        }
+    }
+
+    pub fn get_current_price(&self, nft_contract_id: AccountId, token_id: TokenId) -> U128 {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let market_data = self
+            .internal_get_market_data(&contract_and_token_id)
+            .expect("Paras: Market data does not exist");
+
+        calculate_current_price(&market_data, env::block_timestamp()).into()
+    }
+
+    pub fn approved_ft_token_ids(&self) -> Vec<AccountId> {
+        self.approved_ft_token_ids.to_vec()
+    }
+
+    pub fn approved_nft_contract_ids(&self) -> Vec<AccountId> {
+        self.approved_nft_contract_ids.to_vec()
+    }
+
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    pub fn get_pending_owner(&self) -> Option<AccountId> {
+        self.pending_owner_id.clone()
+    }
+
+    pub fn get_treasury(&self) -> AccountId {
+        self.treasury_id.clone()
+    }
+
+    pub fn get_supply_by_owner_id(&self, account_id: AccountId) -> U64 {
+        self.internal_by_owner_id_len(&account_id).into()
+    }
+
+    // private fn
+
+    fn internal_get_market_data(&self, contract_and_token_id: &ContractAndTokenId) -> Option<MarketData> {
+        if let Some(market_data) = self.old_market.get(contract_and_token_id) {
+            Some(MarketData {
+                owner_id: market_data.owner_id,
+                approval_id: market_data.approval_id,
+                nft_contract_id: market_data.nft_contract_id,
+                token_id: market_data.token_id,
+                ft_token_id: market_data.ft_token_id,
+                price: market_data.price,
+                bids: None,
+                started_at: None,
+                ended_at: None,
+                end_price: None,
+                accept_nft_contract_id: None,
+                accept_token_id: None,
+                is_auction: None,
+            })
+        } else {
+            self.market.get(contract_and_token_id)
+        }
+    }
+
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "Paras: Owner only"
+        )
+    }
+
+    fn internal_has_role(&self, account_id: &AccountId, role: Role) -> bool {
+        *account_id == self.owner_id
+            || matches!(self.roles.get(account_id), Some(roles) if roles.contains(&role))
+    }
+
+    fn assert_role(&self, role: Role) {
+        assert!(
+            self.internal_has_role(&env::predecessor_account_id(), role),
+            "Paras: Requires {:?} role",
+            role
+        )
+    }
+
+    fn assert_not_paused(&self, operation: Operation, nft_contract_id: &AccountId) {
+        assert!(
+            !self.paused_operations.contains(&operation),
+            "Paras: {:?} is paused",
+            operation
+        );
+        assert!(
+            !self.paused_nft_contract_ids.contains(nft_contract_id),
+            "Paras: {} is paused",
+            nft_contract_id
+        );
+    }
+
+    /// admin role is managed by the owner, other roles by the owner or an admin
+    fn assert_role_manager(&self, role: Role) {
+        if role == Role::Admin {
+            self.assert_owner();
+        } else {
+            self.assert_role(Role::Admin);
+        }
+    }
+
+    fn internal_transfer_near(&self, account_id: AccountId, amount: Balance){
+        let balance = env::account_balance();
+        if balance < amount {
             env::panic_str(&format!("Paras: Not enough balance to transfer near, balance: {}, amount: {}", balance, amount));
         }
-        Promise::new(account_id).transfer(amount);
+        Promise::new(account_id.clone()).transfer(amount).then(ext_self::resolve_ft_transfer(
+            near_account(),
+            account_id,
+            U128(amount),
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_FT_TRANSFER,
+        ));
+    }
+
+    fn internal_transfer_ft(&self, ft_token_id: AccountId, account_id: AccountId, amount: Balance) -> Promise {
+        ext_contract::ft_transfer(
+            account_id.clone(),
+            U128(amount),
+            None,
+            ft_token_id.clone(),
+            1,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_self::resolve_ft_transfer(
+            ft_token_id,
+            account_id,
+            U128(amount),
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_FT_TRANSFER,
+        ))
+    }
+
+    /// Near payouts are sent together with a single callback, a payout can have up to MAX_LEN_PAYOUT of them
+    fn internal_transfer_payout(&self, ft_token_id: &AccountId, transfers: Vec<(AccountId, Balance)>) {
+        if ft_token_id != &near_account() {
+            for (receiver_id, amount) in transfers {
+                self.internal_transfer_ft(ft_token_id.clone(), receiver_id, amount);
+            }
+            return;
+        }
+
+        let payouts_promise = transfers
+            .iter()
+            .map(|(receiver_id, amount)| Promise::new(receiver_id.clone()).transfer(*amount))
+            .reduce(|payouts_promise, transfer_promise| payouts_promise.and(transfer_promise));
+        if let Some(payouts_promise) = payouts_promise {
+            payouts_promise.then(ext_self::resolve_near_payouts(
+                transfers.into_iter().map(|(receiver_id, amount)| (receiver_id, U128(amount))).collect(),
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_RESOLVE_NEAR_PAYOUTS,
+            ));
+        }
+    }
+
+    fn internal_transfer(&self, ft_token_id: &AccountId, account_id: AccountId, amount: Balance) {
+        if ft_token_id == &near_account() {
+            self.internal_transfer_near(account_id, amount);
+        } else {
+            self.internal_transfer_ft(ft_token_id.clone(), account_id, amount);
+        }
+    }
+}
+
+pub fn hash_account_id(account_id: &AccountId) -> CryptoHash {
+    let mut hash = CryptoHash::default();
+    hash.copy_from_slice(&env::sha256(account_id.as_bytes()));
+    hash
+}
+
+pub fn hash_contract_account_id_token_id(
+    contract_account_id_token_id: &ContractAccountIdTokenId,
+) -> CryptoHash {
+    let mut hash = CryptoHash::default();
+    hash.copy_from_slice(&env::sha256(contract_account_id_token_id.as_bytes()));
+    hash
+}
+
+/// bytes taken by bids inside a stored MarketData
+fn bids_storage_usage(bids: &[Bid]) -> StorageUsage {
+    bids.iter()
+        .map(|bid| bid.try_to_vec().unwrap().len() as StorageUsage)
+        .sum()
+}
+
+/// older listings could store end_price without a schedule, those are sold at a fixed price
+fn is_dutch_auction(market_data: &MarketData) -> bool {
+    !market_data.is_auction.unwrap_or(false)
+        && market_data.started_at.is_some()
+        && market_data.ended_at.is_some()
+        && matches!(market_data.end_price, Some(end_price) if end_price < market_data.price)
+}
+
+/// dutch auction price decreases linearly from price to end_price between started_at and ended_at
+pub fn calculate_current_price(market_data: &MarketData, current_time: Timestamp) -> u128 {
+    if !is_dutch_auction(market_data) {
+        return market_data.price;
+    }
+
+    let end_price = market_data.end_price.unwrap();
+    let started_at = market_data.started_at.unwrap();
+    let ended_at = market_data.ended_at.unwrap();
+
+    if current_time <= started_at {
+        market_data.price
+    } else if current_time >= ended_at {
+        end_price
+    } else {
+        // split the mul-div so that a yocto amount never multiplies a nanosecond duration
+        let price_range = market_data.price - end_price;
+        let elapsed = (current_time - started_at) as u128;
+        let duration = (ended_at - started_at) as u128;
+        let price_drop =
+            price_range / duration * elapsed + price_range % duration * elapsed / duration;
+        market_data.price - price_drop
+    }
+}
+
+pub fn to_sec(timestamp: Timestamp) -> TimestampSec {
+    (timestamp / 10u64.pow(9)) as u32
+}
+
+#[ext_contract(ext_self)]
+trait ExtSelf {
+    fn resolve_purchase(
+        &mut self,
+        buyer_id: AccountId,
+        market_data: MarketData,
+        price: U128,
+        transaction_fee: U128,
+        referrer_id: Option<AccountId>,
+        paid_from_balance: bool,
+        overpaid_amount: U128,
+    ) -> Promise;
+
+    fn resolve_offer(
+        &mut self,
+        seller_id: AccountId,
+        offer_data: OfferData,
+        token_id: TokenId,
+        transaction_fee: U128,
+        referrer_id: Option<AccountId>,
+        paid_from_balance: bool,
+    ) -> Promise;
+
+    fn callback_escrow(&mut self, escrow_id: U64) -> Promise;
+
+    fn callback_escrow_transfer(&mut self, escrow_id: U64, token_indexes: Vec<u32>) -> Promise;
+
+    fn resolve_ft_transfer(
+        &mut self,
+        ft_token_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> Promise;
+
+    fn resolve_near_payouts(&mut self, payouts: Vec<(AccountId, U128)>) -> Promise;
+}
+
+fn add_accounts(accounts: Option<Vec<AccountId>>, set: &mut UnorderedSet<AccountId>) {
+    accounts.map(|ids| {
+        ids.iter().for_each(|id| {
+            set.insert(id);
+        })
+    });
+}
+
+fn remove_accounts(accounts: Option<Vec<AccountId>>, set: &mut UnorderedSet<AccountId>) {
+    accounts.map(|ids| {
+        ids.iter().for_each(|id| {
+            set.remove(id);
+        })
+    });
+}
+
+fn make_triple(nft_contract_id: &AccountId, buyer_id: &AccountId, token: &str) -> String {
+    format!(
+        "{}{}{}{}{}",
+        nft_contract_id, DELIMETER, buyer_id, DELIMETER, token
+    )
+}
+
+/// AcceptTrade for a single swap, AcceptBundleTrade for a bundle
+fn internal_emit_accept_escrow(escrow: &Escrow) {
+    if let Some(bundle_id) = escrow.bundle_id {
+        let bundle_tokens = |owner_id: &AccountId| -> Vec<BundleToken> {
+            escrow
+                .tokens
+                .iter()
+                .filter(|token| &token.owner_id == owner_id)
+                .map(|token| BundleToken {
+                    nft_contract_id: token.nft_contract_id.clone(),
+                    token_id: token.token_id.clone(),
+                })
+                .collect()
+        };
+        MarketEvent::AcceptBundleTrade(BundleTradeLog {
+            bundle_id,
+            bundle_trade: BundleTrade {
+                buyer_id: escrow.buyer_id.clone(),
+                seller_id: escrow.seller_id.clone(),
+                buyer_tokens: bundle_tokens(&escrow.buyer_id),
+                seller_tokens: bundle_tokens(&escrow.seller_id),
+            },
+        })
+        .emit();
+    } else {
+        MarketEvent::AcceptTrade(AcceptTradeLog {
+            seller_id: escrow.seller_id.clone(),
+            buyer_id: escrow.buyer_id.clone(),
+            nft_contract_id: escrow.tokens[1].nft_contract_id.clone(),
+            token_id: escrow.tokens[1].token_id.clone(),
+            buyer_nft_contract_id: escrow.tokens[0].nft_contract_id.clone(),
+            buyer_token_id: escrow.tokens[0].token_id.clone(),
+        })
+        .emit();
+    }
+}
+
+fn bundle_tokens_json(
+    approvals: &LookupMap<String, u64>,
+    bundle_id: u64,
+    tokens: Vec<BundleToken>,
+) -> Vec<BundleTokenJson> {
+    tokens
+        .into_iter()
+        .map(|token| BundleTokenJson {
+            approval_id: approvals
+                .get(&make_bundle_token_key(bundle_id, &token.nft_contract_id, &token.token_id))
+                .map(|approval_id| approval_id.into()),
+            nft_contract_id: token.nft_contract_id,
+            token_id: token.token_id,
+        })
+        .collect()
+}
+
+fn make_bundle_token_key(bundle_id: u64, nft_contract_id: &AccountId, token_id: &str) -> String {
+    format!(
+        "{}{}{}{}{}",
+        bundle_id, DELIMETER, nft_contract_id, DELIMETER, token_id
+    )
+}
+
+fn make_claimable_key(account_id: &AccountId, ft_token_id: &AccountId) -> String {
+    format!("{}{}{}", account_id, DELIMETER, ft_token_id)
+}
+
+fn make_key_collection_offer(nft_contract_id: &AccountId, buyer_id: &AccountId) -> String {
+    format!("{}{}{}", nft_contract_id, DELIMETER, buyer_id)
+}
+
+fn make_key_owner_by_id_collection_offer(contract_account_id: String) -> String {
+    format!("{}{}collection_offer", contract_account_id, DELIMETER)
+}
+
+fn make_key_owner_by_id_trade(contract_account_id_token_id: String) -> String {
+    format!("{}{}trade", contract_account_id_token_id, DELIMETER)
+}
+
+fn make_key_trade(
+    buyer_contract_account_id_token_id: &str,
+    contract_account_id_token_id: &str,
+) -> String {
+    format!(
+        "{}{}{}",
+        buyer_contract_account_id_token_id, DELIMETER, contract_account_id_token_id
+    )
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn setup_contract() -> (VMContextBuilder, Contract) {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let contract = Contract::new(
+            accounts(0),
+            accounts(1),
+            None,
+            Some(vec![accounts(2)]),
+            Some(vec![accounts(2)]),
+            500,
+        );
+        (context, contract)
+    }
+
+    fn execute_admin_action_after_delay(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        action_id: U64,
+    ) {
+        testing_env!(context
+            .block_timestamp(DEFAULT_ADMIN_ACTION_DELAY)
+            .attached_deposit(1)
+            .build());
+        contract.execute_admin_action(action_id);
+    }
+
+    #[test]
+    fn test_migrate() {
+        let (mut context, _) = setup_contract();
+
+        let contract_and_token_id = format!("{}{}{}", accounts(2), DELIMETER, "1:1");
+        let mut market_data_transaction_fee = MarketDataTransactionFee {
+            transaction_fee: UnorderedMap::new(StorageKey::MarketDataTransactionFee),
+        };
+        market_data_transaction_fee
+            .transaction_fee
+            .insert(&contract_and_token_id, &300);
+
+        let prev = ContractV3 {
+            owner_id: accounts(0),
+            treasury_id: accounts(1),
+            old_market: UnorderedMap::new(StorageKey::Market),
+            market: UnorderedMap::new(StorageKey::MarketV2),
+            approved_ft_token_ids: UnorderedSet::new(StorageKey::FTTokenIds),
+            approved_nft_contract_ids: UnorderedSet::new(StorageKey::NFTContractIds),
+            storage_deposits: LookupMap::new(StorageKey::StorageDeposits),
+            by_owner_id: LookupMap::new(StorageKey::ByOwnerId),
+            offers: UnorderedMap::new(StorageKey::Offers),
+            paras_nft_contracts: UnorderedSet::new(StorageKey::ParasNFTContractIds),
+            transaction_fee: TransactionFee {
+                next_fee: None,
+                start_time: None,
+                current_fee: 500,
+            },
+            trades: UnorderedMap::new(StorageKey::Trade),
+            market_data_transaction_fee,
+        };
+        env::state_write(&prev);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let contract = Contract::migrate();
+        assert_eq!(contract.get_treasury(), accounts(1));
+        assert_eq!(contract.get_transaction_fee().current_fee, 500);
+        assert_eq!(
+            contract
+                .market_data_transaction_fee
+                .transaction_fee
+                .get(&contract_and_token_id),
+            Some(300)
+        );
+    }
+
+    #[test]
+    fn test_migrate_indexes() {
+        let (mut context, mut contract) = setup_contract();
+
+        // entries stored before the indexes existed
+        let contract_and_token_id = format!("{}{}{}", accounts(2), DELIMETER, "1:1");
+        contract.market.insert(
+            &contract_and_token_id,
+            &MarketData {
+                owner_id: accounts(3),
+                approval_id: 1,
+                nft_contract_id: accounts(2),
+                token_id: "1:1".to_string(),
+                ft_token_id: near_account(),
+                price: 10u128.pow(24),
+                bids: None,
+                started_at: None,
+                ended_at: None,
+                end_price: None,
+                accept_nft_contract_id: None,
+                accept_token_id: None,
+                is_auction: None,
+            },
+        );
+        contract.offers.insert(
+            &make_triple(&accounts(2), &accounts(1), "1:1"),
+            &OfferData {
+                buyer_id: accounts(1),
+                nft_contract_id: accounts(2),
+                token_id: Some("1:1".to_string()),
+                token_series_id: None,
+                ft_token_id: near_account(),
+                price: 10u128.pow(24),
+            },
+        );
+        contract.trades.insert(
+            &make_triple(&accounts(2), &accounts(1), "2:1"),
+            &TradeList {
+                approval_id: 1,
+                trade_data: HashMap::new(),
+            },
+        );
+        assert!(contract.get_market_data_by_nft_contract_id(accounts(2), None, None).is_empty());
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut from_index = U64(0);
+        loop {
+            let next_index = contract.migrate_indexes(Some(from_index), Some(1));
+            if next_index == from_index {
+                break;
+            }
+            from_index = next_index;
+        }
+        assert_eq!(from_index, U64(3));
+
+        assert_eq!(contract.get_market_data_by_nft_contract_id(accounts(2), None, None).len(), 1);
+        assert_eq!(contract.get_supply_offers_by_token(accounts(2), "1:1".to_string()), U64(1));
+        assert_eq!(contract.get_trades_by_owner_id(accounts(1), None, None).len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Owner only")]
+    fn test_invalid_migrate_indexes_owner() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.migrate_indexes(None, None);
+    }
+
+    #[test]
+    fn test_new() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let contract = Contract::new(
+            accounts(0),
+            accounts(1),
+            None,
+            Some(vec![accounts(2)]),
+            Some(vec![accounts(2)]),
+            500,
+        );
+        testing_env!(context.is_view(true).build());
+        assert_eq!(contract.get_owner(), accounts(0));
+        assert_eq!(contract.get_treasury(), accounts(1));
+        assert_eq!(contract.approved_nft_contract_ids(), vec![accounts(2)]);
+        assert_eq!(contract.transaction_fee.current_fee, 500);
+    }
+
+    #[test]
+    fn test_set_treasury() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        let action_id = contract.set_treasury(accounts(5));
+        assert_eq!(contract.get_treasury(), accounts(1));
+        assert_eq!(contract.get_admin_actions().len(), 1);
+
+        execute_admin_action_after_delay(&mut context, &mut contract, action_id);
+        let new_treasury: AccountId = contract.get_treasury();
+        assert_eq!(new_treasury, accounts(5));
+        assert!(contract.get_admin_actions().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Admin action is executable at 86400000000000")]
+    fn test_invalid_execute_admin_action_before_delay() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        let action_id = contract.set_treasury(accounts(5));
+        contract.execute_admin_action(action_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Admin action does not exist")]
+    fn test_cancel_admin_action() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        let action_id = contract.set_treasury(accounts(5));
+        contract.cancel_admin_action(action_id);
+        assert!(contract.get_admin_actions().is_empty());
+
+        execute_admin_action_after_delay(&mut context, &mut contract, action_id);
+    }
+
+    #[test]
+    fn test_set_admin_action_delay() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        let action_id = contract.set_admin_action_delay(U64(MIN_ADMIN_ACTION_DELAY));
+        execute_admin_action_after_delay(&mut context, &mut contract, action_id);
+        assert_eq!(contract.get_admin_action_delay().0, MIN_ADMIN_ACTION_DELAY);
+
+        let action_id = contract.set_treasury(accounts(5));
+        assert_eq!(
+            contract.get_admin_actions()[0].executable_at.0,
+            DEFAULT_ADMIN_ACTION_DELAY + MIN_ADMIN_ACTION_DELAY
+        );
+
+        testing_env!(context
+            .block_timestamp(DEFAULT_ADMIN_ACTION_DELAY + MIN_ADMIN_ACTION_DELAY)
+            .build());
+        contract.execute_admin_action(action_id);
+        assert_eq!(contract.get_treasury(), accounts(5));
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Admin action delay is less than")]
+    fn test_invalid_set_admin_action_delay_below_minimum() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_admin_action_delay(U64(FIVE_MINUTES));
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: start_time is before the admin action can be executed")]
+    fn test_invalid_set_transaction_fee_start_time_within_delay() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_transaction_fee(1000, Some(to_sec(DEFAULT_ADMIN_ACTION_DELAY)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Requires Admin role")]
+    fn test_invalid_set_treasury() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_treasury(accounts(5));
+    }
+
+    #[test]
+    fn test_transfer_ownership() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.transfer_ownership(accounts(5));
+        assert_eq!(contract.get_owner(), accounts(0));
+        assert_eq!(contract.get_pending_owner(), Some(accounts(5)));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(1)
+            .build());
+
+        contract.accept_ownership();
+        let new_owner: AccountId = contract.get_owner();
+        assert_eq!(new_owner, accounts(5));
+        assert_eq!(contract.get_pending_owner(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Pending owner only")]
+    fn test_invalid_accept_ownership() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.transfer_ownership(accounts(5));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+
+        contract.accept_ownership();
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Owner only")]
+    fn test_invalid_transfer_ownership() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(1)
+            .build());
+
+        contract.transfer_ownership(accounts(5));
+    }
+
+    #[test]
+    fn test_grant_and_revoke_role() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.grant_role(accounts(3), Role::Admin);
+        assert_eq!(contract.get_roles(accounts(3)), vec![Role::Admin]);
+
+        // admin grants operational roles
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+
+        contract.grant_role(accounts(4), Role::FeeManager);
+        assert_eq!(contract.get_role_members(Role::FeeManager), vec![accounts(4)]);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+
+        let action_id = contract.set_transaction_fee(100, None);
+        execute_admin_action_after_delay(&mut context, &mut contract, action_id);
+        assert_eq!(contract.get_transaction_fee().current_fee, 100);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+
+        contract.revoke_role(accounts(4), Role::FeeManager);
+        assert!(contract.get_roles(accounts(4)).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Requires FeeManager role")]
+    fn test_invalid_set_transaction_fee_after_revoke() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.grant_role(accounts(4), Role::FeeManager);
+        contract.revoke_role(accounts(4), Role::FeeManager);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_transaction_fee(100, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Owner only")]
+    fn test_invalid_admin_grant_admin() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.grant_role(accounts(3), Role::Admin);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+
+        contract.grant_role(accounts(4), Role::Admin);
+    }
+
+    #[test]
+    fn test_moderator_delete_market_data() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.grant_role(accounts(4), Role::Moderator);
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+
+        contract.delete_market_data(accounts(2), "1:1".to_string());
+        assert_eq!(contract.get_supply_by_owner_id(accounts(3)), U64(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_invalid_moderator_delete_market_data_without_yocto() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.grant_role(accounts(4), Role::Moderator);
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(0)
+            .build());
+
+        contract.delete_market_data(accounts(2), "1:1".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Buy is paused")]
+    fn test_invalid_buy_when_paused() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.grant_role(accounts(4), Role::Pauser);
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_operation_paused(Operation::Buy, true);
+        assert_eq!(contract.get_paused_operations(), vec![Operation::Buy]);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(10u128.pow(24))
+            .build());
+
+        contract.buy(accounts(2), "1:1".to_string(), None, None, None);
+    }
+
+    #[test]
+    fn test_paused_nft_contract_keeps_refunds_live() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.internal_add_offer(
+            accounts(2),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(one_near),
+            accounts(0),
+        );
+
+        contract.set_nft_contract_paused(accounts(2), true);
+        assert_eq!(contract.get_paused_nft_contract_ids(), vec![accounts(2)]);
+
+        contract.delete_offer(accounts(2), Some("1:1".to_string()), None);
+        assert_eq!(contract.get_supply_by_owner_id(accounts(0)), U64(0));
+
+        contract.set_nft_contract_paused(accounts(2), false);
+        assert!(contract.get_paused_nft_contract_ids().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: charlie is paused")]
+    fn test_invalid_add_offer_when_nft_contract_paused() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_nft_contract_paused(accounts(2), true);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(10u128.pow(24))
+            .build());
+
+        contract.add_offer(
+            accounts(2),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(10u128.pow(24)),
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_event_log_format() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.transfer_ownership(accounts(5));
+
+        let logs = get_logs();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(
+            logs[0],
+            format!(
+                r#"EVENT_JSON:{{"standard":"paras_marketplace","version":"1.0.0","event":"propose_ownership","data":{{"old_owner_id":"{}","new_owner_id":"{}"}}}}"#,
+                accounts(0),
+                accounts(5)
+            )
+        );
+    }
+
+    #[test]
+    fn test_offer_and_purchase_event_log_format() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(one_near)
+            .build());
+        contract.storage_deposit(None, None);
+        contract.add_offer(accounts(2), Some("1:1".to_string()), None, near_account(), U128(one_near), None, None, None);
+        assert_eq!(
+            get_logs(),
+            vec![format!(
+                r#"EVENT_JSON:{{"standard":"paras_marketplace","version":"1.0.0","event":"add_offer","data":{{"buyer_id":"{}","nft_contract_id":"{}","token_id":"1:1","token_series_id":null,"ft_token_id":"near","price":"{}","expires_at":null,"is_pooled":false}}}}"#,
+                accounts(1),
+                accounts(2),
+                one_near
+            )]
+        );
+
+        let market_data = || MarketData {
+            owner_id: accounts(3),
+            approval_id: 1,
+            nft_contract_id: accounts(2),
+            token_id: "1:2".to_string(),
+            ft_token_id: near_account(),
+            price: one_near,
+            bids: None,
+            started_at: None,
+            ended_at: None,
+            end_price: None,
+            accept_nft_contract_id: None,
+            accept_token_id: None,
+            is_auction: None,
+        };
+        let purchase_log = |event: &str| {
+            format!(
+                r#"EVENT_JSON:{{"standard":"paras_marketplace","version":"1.0.0","event":"{}","data":{{"owner_id":"{}","buyer_id":"{}","nft_contract_id":"{}","token_id":"1:2","token_series_id":null,"ft_token_id":"near","price":"{}","is_offer":false}}}}"#,
+                event,
+                accounts(3),
+                accounts(1),
+                accounts(2),
+                one_near
+            )
+        };
+
+        let mut payout = PayoutHashMap::new();
+        payout.insert(accounts(3), U128(one_near));
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(near_sdk::serde_json::to_vec(&payout).unwrap())]
+        );
+        contract.resolve_purchase(accounts(1), market_data(), U128(one_near), U128(500), None, false, U128(0));
+        assert!(get_logs().contains(&purchase_log("resolve_purchase")));
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.resolve_purchase(accounts(1), market_data(), U128(one_near), U128(500), None, false, U128(0));
+        assert_eq!(get_logs(), vec![purchase_log("resolve_purchase_fail")]);
+    }
+
+    #[test]
+    fn test_trade_and_escrow_event_log_format() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.add_trade(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(1),
+            accounts(2),
+            Some("1:2".to_string()),
+            1,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(
+            get_logs(),
+            vec![format!(
+                r#"EVENT_JSON:{{"standard":"paras_marketplace","version":"1.0.0","event":"add_trade","data":{{"buyer_id":"{}","nft_contract_id":"{}","token_id":"1:1","token_series_id":null,"buyer_nft_contract_id":"{}","buyer_token_id":"1:2","buyer_approval_id":"1","ft_token_id":null,"buyer_amount":null,"seller_amount":null}}}}"#,
+                accounts(2),
+                accounts(3),
+                accounts(1)
+            )]
+        );
+
+        let escrow_log = |state: &str| {
+            format!(
+                r#"EVENT_JSON:{{"standard":"paras_marketplace","version":"1.0.0","event":"update_escrow","data":{{"escrow_id":"0","state":"{}"}}}}"#,
+                state
+            )
+        };
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.internal_accept_trade(
+            accounts(3),
+            accounts(2),
+            "1:1".to_string(),
+            accounts(4),
+            1,
+            accounts(1),
+            "1:2".to_string(),
+        );
+        assert_eq!(get_logs(), vec![escrow_log("escrowing")]);
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(vec![]), PromiseResult::Successful(vec![])]
+        );
+        contract.callback_escrow(U64(0));
+        assert_eq!(
+            get_logs(),
+            vec![
+                format!(
+                    r#"EVENT_JSON:{{"standard":"paras_marketplace","version":"1.0.0","event":"accept_trade","data":{{"seller_id":"{}","buyer_id":"{}","nft_contract_id":"{}","token_id":"1:1","buyer_nft_contract_id":"{}","buyer_token_id":"1:2"}}}}"#,
+                    accounts(4),
+                    accounts(2),
+                    accounts(3),
+                    accounts(1)
+                ),
+                escrow_log("releasing"),
+            ]
+        );
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(vec![]), PromiseResult::Successful(vec![])]
+        );
+        contract.callback_escrow_transfer(U64(0), vec![0, 1]);
+        assert_eq!(get_logs(), vec![escrow_log("released")]);
+    }
+
+    #[test]
+    fn test_add_approved_nft_contract_ids() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.add_approved_nft_contract_ids(vec![accounts(5)]);
+        let approved_nfts = contract.approved_nft_contract_ids();
+        assert_eq!(approved_nfts, vec![accounts(2), accounts(5)]);
+    }
+
+    #[test]
+    fn test_remove_approved_nft_contract_ids() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.add_approved_nft_contract_ids(vec![accounts(5)]);
+        contract.remove_approved_nft_contract_ids(vec![accounts(5)]);
+        let approved_nfts = contract.approved_nft_contract_ids();
+        assert_eq!(approved_nfts, vec![accounts(2)]);
+    }
+
+    #[test]
+    fn test_internal_add_market_data() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        let market = contract.get_market_data(accounts(2), "1:1".to_string());
+        assert_eq!(market.owner_id, accounts(3));
+        assert_eq!(market.approval_id, U64::from(1));
+        assert_eq!(market.ft_token_id, near_account());
+        assert_eq!(market.nft_contract_id, accounts(2));
+        assert_eq!(market.owner_id, accounts(3));
+        assert_eq!(market.token_id, "1:1".to_string());
+        assert_eq!(market.price, U128::from(1 * 10u128.pow(24)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: price higher than 1000000000000000000000000000000000")]
+    fn test_invalid_price_higher_than_max_price() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1_000_000_000 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Market data does not exist")]
+    fn test_delete_market_data() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+
+        contract.delete_market_data(accounts(2), "1:1".to_string());
+
+        contract.get_market_data(accounts(2), "1:1".to_string());
+    }
+
+    #[test]
+    fn test_storage_deposit() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .build());
+
+        contract.storage_deposit(None, None);
+
+        let storage_balance = contract.storage_balance_of(accounts(0)).unwrap().total.0;
+        assert_eq!(STORAGE_ADD_MARKET_DATA, storage_balance);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.storage_withdraw(None);
+
+        let storage_balance = contract.storage_balance_of(accounts(0)).unwrap().total.0;
+        assert_eq!(0, storage_balance);
+    }
+
+    #[test]
+    fn test_storage_deposit_registration_only() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA * 2)
+            .build());
+
+        let storage_balance = contract.storage_deposit(None, Some(true));
+        assert_eq!(storage_balance.total.0, STORAGE_ADD_MARKET_DATA);
+        assert_eq!(
+            contract.storage_balance_bounds().min.0,
+            STORAGE_ADD_MARKET_DATA
+        );
+        assert!(contract.storage_balance_of(accounts(1)).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Amount exceeds the available storage balance")]
+    fn test_invalid_storage_withdraw_locked_balance() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA * 2)
+            .build());
+
+        contract.storage_deposit(None, None);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.add_offer(
+            accounts(2),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(one_near),
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.storage_withdraw(Some(U128(STORAGE_ADD_MARKET_DATA * 2)));
+    }
+
+    #[test]
+    fn test_storage_unregister() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA * 2)
+            .build());
+
+        contract.storage_deposit(None, None);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.add_offer(
+            accounts(2),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(one_near),
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        assert!(contract.storage_unregister(Some(true)));
+        assert!(contract.storage_balance_of(accounts(0)).is_none());
+        assert_eq!(contract.get_supply_by_owner_id(accounts(0)), U64(0));
+        assert_eq!(contract.get_storage_used(accounts(0)), U64(0));
+        assert!(!contract.storage_unregister(None));
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Can't unregister an account with active listings")]
+    fn test_invalid_storage_unregister_with_active_offer() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA * 2)
+            .build());
+
+        contract.storage_deposit(None, None);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.add_offer(
+            accounts(2),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(one_near),
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.storage_unregister(None);
+    }
+
+    #[test]
+    fn test_storage_used_is_charged_and_released() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA * 5)
+            .build());
+
+        contract.storage_deposit(None, None);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.add_offer(
+            accounts(2),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(one_near),
+            None,
+            None,
+            None,
+        );
+
+        let storage_used = contract.get_storage_used(accounts(0)).0;
+        assert!(storage_used > 0);
+
+        // a longer token id takes more bytes
+        contract.add_offer(
+            accounts(2),
+            Some("1:1".repeat(64)),
+            None,
+            near_account(),
+            U128(one_near),
+            None,
+            None,
+            None,
+        );
+        assert!(contract.get_storage_used(accounts(0)).0 - storage_used > storage_used);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.delete_offer(accounts(2), Some("1:1".repeat(64)), None);
+        assert_eq!(contract.get_storage_used(accounts(0)).0, storage_used);
+
+        contract.storage_withdraw(None);
+        assert_eq!(
+            contract.storage_balance_of(accounts(0)).unwrap().total.0,
+            storage_used as u128 * env::storage_byte_cost()
+        );
+    }
+
+    #[test]
+    fn test_storage_unregister_after_sale() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .build());
+        contract.storage_deposit(None, None);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128(one_near),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert!(contract.get_storage_used(accounts(3)).0 > 0);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(one_near)
+            .build());
+        contract.buy(accounts(2), "1:1".to_string(), None, None, None);
+
+        let mut payout = PayoutHashMap::new();
+        payout.insert(accounts(3), U128(one_near));
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(near_sdk::serde_json::to_vec(&payout).unwrap())]
+        );
+        contract.resolve_purchase(
+            accounts(1),
+            MarketData {
+                owner_id: accounts(3),
+                approval_id: 1,
+                nft_contract_id: accounts(2),
+                token_id: "1:1".to_string(),
+                ft_token_id: near_account(),
+                price: one_near,
+                bids: None,
+                started_at: None,
+                ended_at: None,
+                end_price: None,
+                accept_nft_contract_id: None,
+                accept_token_id: None,
+                is_auction: None,
+            },
+            U128(one_near),
+            U128(500),
+            None,
+            false,
+            U128(0),
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        assert!(contract.storage_unregister(None));
+    }
+
+    #[test]
+    fn test_migrated_entries_keep_flat_storage_deposit() {
+        let (mut context, _) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+        let contract_and_token_id = format!("{}{}{}", accounts(2), DELIMETER, "1:1");
+
+        let mut market = UnorderedMap::new(StorageKey::MarketV2);
+        market.insert(
+            &contract_and_token_id,
+            &MarketData {
+                owner_id: accounts(3),
+                approval_id: 1,
+                nft_contract_id: accounts(2),
+                token_id: "1:1".to_string(),
+                ft_token_id: near_account(),
+                price: one_near,
+                bids: None,
+                started_at: None,
+                ended_at: None,
+                end_price: None,
+                accept_nft_contract_id: None,
+                accept_token_id: None,
+                is_auction: None,
+            },
+        );
+        let mut token_ids = UnorderedSet::new(
+            StorageKey::ByOwnerIdInner {
+                account_id_hash: hash_account_id(&accounts(3)),
+            }
+            .try_to_vec()
+            .unwrap(),
+        );
+        token_ids.insert(&contract_and_token_id);
+        let mut by_owner_id = LookupMap::new(StorageKey::ByOwnerId);
+        by_owner_id.insert(&accounts(3), &token_ids);
+        let mut storage_deposits = LookupMap::new(StorageKey::StorageDeposits);
+        storage_deposits.insert(&accounts(3), &(STORAGE_ADD_MARKET_DATA * 2));
+
+        let prev = ContractV3 {
+            owner_id: accounts(0),
+            treasury_id: accounts(1),
+            old_market: UnorderedMap::new(StorageKey::Market),
+            market,
+            approved_ft_token_ids: UnorderedSet::new(StorageKey::FTTokenIds),
+            approved_nft_contract_ids: UnorderedSet::new(StorageKey::NFTContractIds),
+            storage_deposits,
+            by_owner_id,
+            offers: UnorderedMap::new(StorageKey::Offers),
+            paras_nft_contracts: UnorderedSet::new(StorageKey::ParasNFTContractIds),
+            transaction_fee: TransactionFee {
+                next_fee: None,
+                start_time: None,
+                current_fee: 500,
+            },
+            trades: UnorderedMap::new(StorageKey::Trade),
+            market_data_transaction_fee: MarketDataTransactionFee {
+                transaction_fee: UnorderedMap::new(StorageKey::MarketDataTransactionFee),
+            },
+        };
+        env::state_write(&prev);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::migrate();
+        assert_eq!(contract.get_supply_by_owner_id(accounts(3)), U64(1));
+        assert_eq!(contract.get_market_data_by_owner_id(accounts(3), None, None).len(), 1);
+        assert_eq!(
+            contract.storage_balance_of(accounts(3)).unwrap().available,
+            U128(STORAGE_ADD_MARKET_DATA)
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        contract.delete_market_data(accounts(2), "1:1".to_string());
+        assert_eq!(
+            contract.storage_balance_of(accounts(3)).unwrap().available,
+            U128(STORAGE_ADD_MARKET_DATA * 2)
+        );
+        assert!(contract.storage_unregister(None));
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Insufficient storage paid")]
+    fn test_invalid_add_offer_insufficient_storage() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.add_offer(
+            accounts(2),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(one_near),
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_add_offer() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.internal_add_offer(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(one_near),
+            accounts(0),
+        );
+
+        let offer_data =
+            contract.get_offer(accounts(3), accounts(0), Some("1:1".to_string()), None);
+
+        assert_eq!(offer_data.buyer_id, accounts(0));
+        assert_eq!(offer_data.price, U128(one_near));
+    }
+
+    #[test]
+    fn test_offer_transaction_fee_locked() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        let start_time: Timestamp = DEFAULT_ADMIN_ACTION_DELAY * 2;
+        let action_id = contract.set_transaction_fee(1000, Some(to_sec(start_time)));
+        execute_admin_action_after_delay(&mut context, &mut contract, action_id);
+
+        contract.internal_add_offer(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(one_near),
+            accounts(0),
+        );
+
+        testing_env!(context.block_timestamp(start_time).build());
+        assert_eq!(contract.calculate_current_transaction_fee(), 1000);
+
+        let offer_data =
+            contract.get_offer(accounts(3), accounts(0), Some("1:1".to_string()), None);
+        assert_eq!(offer_data.transaction_fee, U128(500));
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Offer does not exist")]
+    fn test_delete_offer() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.internal_add_offer(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(one_near),
+            accounts(0),
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.delete_offer(accounts(3), Some("1:1".to_string()), None);
+
+        contract.get_offer(accounts(3), accounts(1), Some("1:1".to_string()), None);
+    }
+
+    #[test]
+    fn test_add_collection_offer() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(one_near * 3)
+            .build());
+
+        contract.storage_deposit(None, None);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(one_near * 3)
+            .build());
+
+        contract.add_collection_offer(accounts(2), near_account(), U128(one_near), 3, None);
+
+        let collection_offer = contract.get_collection_offer(accounts(2), accounts(0));
+        assert_eq!(collection_offer.price, U128(one_near));
+        assert_eq!(collection_offer.quantity, 3);
+
+        contract.internal_accept_collection_offer(
+            accounts(2),
+            accounts(0),
+            "1:1".to_string(),
+            accounts(3),
+            1,
+            one_near,
+        );
+
+        let collection_offer = contract.get_collection_offer(accounts(2), accounts(0));
+        assert_eq!(collection_offer.quantity, 2);
+        assert_eq!(contract.get_supply_by_owner_id(accounts(0)), U64(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Offer does not exist")]
+    fn test_accept_collection_offer_until_filled() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.storage_deposit(None, None);
+        contract.add_collection_offer(accounts(2), near_account(), U128(one_near), 1, None);
+
+        contract.internal_accept_collection_offer(
+            accounts(2),
+            accounts(0),
+            "1:1".to_string(),
+            accounts(3),
+            1,
+            one_near,
+        );
+
+        assert_eq!(contract.get_supply_by_owner_id(accounts(0)), U64(0));
+        contract.get_collection_offer(accounts(2), accounts(0));
+    }
+
+    #[test]
+    fn test_collection_offer_transaction_fee_locked() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        let start_time: Timestamp = DEFAULT_ADMIN_ACTION_DELAY * 2;
+        let action_id = contract.set_transaction_fee(1000, Some(to_sec(start_time)));
+        execute_admin_action_after_delay(&mut context, &mut contract, action_id);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(one_near)
+            .build());
+        contract.storage_deposit(None, None);
+        contract.add_collection_offer(accounts(2), near_account(), U128(one_near), 1, None);
+
+        testing_env!(context.block_timestamp(start_time).build());
+        assert_eq!(contract.calculate_current_transaction_fee(), 1000);
+
+        let collection_offer = contract.get_collection_offer(accounts(2), accounts(0));
+        assert_eq!(collection_offer.transaction_fee, U128(500));
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Collection offer price is 1000000000000000000000000")]
+    fn test_invalid_accept_collection_offer_price() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.storage_deposit(None, None);
+        contract.add_collection_offer(accounts(2), near_account(), U128(one_near), 1, None);
+
+        contract.internal_accept_collection_offer(
+            accounts(2),
+            accounts(0),
+            "1:1".to_string(),
+            accounts(3),
+            1,
+            one_near / 2,
+        );
+    }
+
+    #[test]
+    fn test_refund_expired_offers() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+        let expires_at: u64 = 1_000_000_000;
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA * 3)
+            .build());
+
+        contract.storage_deposit(None, None);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.add_offer(
+            accounts(2),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(one_near),
+            Some(U64(expires_at)),
+            None,
+            None,
+        );
+
+        contract.add_offer(
+            accounts(2),
+            Some("1:2".to_string()),
+            None,
+            near_account(),
+            U128(one_near),
+            None,
+            None,
+            None,
+        );
+
+        let offer_data =
+            contract.get_offer(accounts(2), accounts(0), Some("1:1".to_string()), None);
+        assert_eq!(offer_data.expires_at, Some(U64(expires_at)));
+
+        // not expired yet
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        assert_eq!(contract.refund_expired_offers(None, None), U64(1));
+        assert_eq!(contract.offer_expires_at.len(), 1);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .block_timestamp(expires_at)
+            .build());
+        assert_eq!(contract.refund_expired_offers(None, None), U64(0));
+        assert_eq!(contract.get_supply_by_owner_id(accounts(0)), U64(1));
+        assert_eq!(contract.offer_expires_at.len(), 0);
+    }
+
+    #[test]
+    fn test_refund_expired_offers_paged() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+        let expires_at: u64 = 1_000_000_000;
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA * 10)
+            .build());
+        contract.storage_deposit(None, None);
+
+        // unexpired offers at the front, expired ones interleaved after them
+        testing_env!(context.attached_deposit(one_near).build());
+        for i in 0..7 {
+            contract.add_offer(
+                accounts(2),
+                Some(format!("1:{}", i)),
+                None,
+                near_account(),
+                U128(one_near),
+                Some(U64(if i < 2 || i % 2 == 0 { expires_at * 2 } else { expires_at })),
+                None,
+                None,
+            );
+        }
+
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .block_timestamp(expires_at)
+            .build());
+        let mut from_index = U64(0);
+        loop {
+            let next_index = contract.refund_expired_offers(Some(from_index), Some(2));
+            if next_index.0 >= contract.offer_expires_at.len() {
+                break;
+            }
+            from_index = next_index;
+        }
+
+        // 1:3 and 1:5 expired
+        assert_eq!(contract.offer_expires_at.len(), 5);
+        assert!(contract
+            .offer_expires_at
+            .values()
+            .all(|offer_expires_at| offer_expires_at == expires_at * 2));
+        assert_eq!(contract.get_supply_by_owner_id(accounts(0)), U64(5));
+    }
+
+    #[test]
+    fn test_accept_expired_offer() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+        let expires_at: u64 = 1_000_000_000;
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA * 2)
+            .build());
+
+        contract.storage_deposit(None, None);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.add_offer(
+            accounts(2),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(one_near),
+            Some(U64(expires_at)),
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .block_timestamp(expires_at + 1)
+            .build());
+
+        let accepted = contract.internal_accept_offer(
+            accounts(2),
+            accounts(0),
+            "1:1".to_string(),
+            accounts(3),
+            1,
+            one_near,
+        );
+        assert!(matches!(accepted, PromiseOrValue::Value(false)));
+    }
+
+    #[test]
+    fn test_enumerate_market_data() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        for token_id in ["1:1", "1:2", "1:3"] {
+            contract.internal_add_market_data(
+                accounts(3),
+                1,
+                accounts(2),
+                token_id.to_string(),
+                near_account(),
+                U128::from(10u128.pow(24)),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            );
+        }
+
+        contract.internal_add_offer(
+            accounts(2),
+            Some("1:4".to_string()),
+            None,
+            near_account(),
+            U128(10u128.pow(24)),
+            accounts(3),
+        );
+
+        assert_eq!(contract.get_supply_by_nft_contract_id(accounts(2)), U64(3));
+        assert_eq!(contract.get_market_data_by_owner_id(accounts(3), None, None).len(), 3);
+
+        let market_data = contract.get_market_data_by_nft_contract_id(accounts(2), Some(U64(1)), Some(1));
+        assert_eq!(market_data.len(), 1);
+        assert_eq!(market_data[0].token_id, "1:2".to_string());
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+
+        contract.delete_market_data(accounts(2), "1:1".to_string());
+        assert_eq!(contract.get_supply_by_nft_contract_id(accounts(2)), U64(2));
+        assert_eq!(contract.get_market_data_by_owner_id(accounts(3), None, None).len(), 2);
     }
-}
 
-pub fn hash_account_id(account_id: &AccountId) -> CryptoHash {
-    let mut hash = CryptoHash::default();
-    hash.copy_from_slice(&env::sha256(account_id.as_bytes()));
-    hash
-}
+    #[test]
+    fn test_enumerate_offers() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        for buyer_id in [accounts(0), accounts(1)] {
+            contract.internal_add_offer(
+                accounts(2),
+                Some("1:1".to_string()),
+                None,
+                near_account(),
+                U128(one_near),
+                buyer_id,
+            );
+        }
+
+        assert_eq!(contract.get_supply_offers_by_token(accounts(2), "1:1".to_string()), U64(2));
+        assert_eq!(contract.get_offers_by_token(accounts(2), "1:1".to_string(), None, None).len(), 2);
+        assert_eq!(contract.get_offers_by_buyer_id(accounts(1), None, None).len(), 1);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.delete_offer(accounts(2), Some("1:1".to_string()), None);
+        let offers = contract.get_offers_by_token(accounts(2), "1:1".to_string(), None, None);
+        assert_eq!(offers.len(), 1);
+        assert_eq!(offers[0].buyer_id, accounts(1));
+    }
+
+    #[test]
+    fn test_enumerate_trades() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_trade(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(1),
+            Some("1:2".to_string()),
+            accounts(2),
+            1,
+            None,
+            None,
+            None,
+        );
+
+        let trades = contract.get_trades_by_owner_id(accounts(2), None, None);
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].buyer_nft_contract_id, accounts(1));
+        assert_eq!(trades[0].buyer_token_id, "1:2".to_string());
+        assert_eq!(trades[0].trade_data.len(), 1);
+    }
+
+    #[test]
+    fn test_add_trade() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.internal_add_trade(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(1),
+            Some("1:2".to_string()),
+            accounts(2),
+            1,
+            None,
+            None,
+            None,
+        );
+
+        let trade_data = contract.get_trade(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(2),
+            accounts(1),
+            "1:2".to_string(),
+        );
+
+        assert_eq!(trade_data.token_id.unwrap().to_string(), "1:1");
+        assert_eq!(trade_data.nft_contract_id, accounts(3));
+    }
+
+    #[test]
+    fn test_trade_with_top_up() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        for account_id in [accounts(2), accounts(4)] {
+            testing_env!(context
+                .predecessor_account_id(account_id.clone())
+                .attached_deposit(STORAGE_ADD_MARKET_DATA * 3)
+                .build());
+            contract.storage_deposit(None, None);
+            testing_env!(context.attached_deposit(one_near).build());
+            contract.deposit();
+        }
+
+        // "my NFT + 1 NEAR for your NFT + 1 NEAR", replacing a trade refunds its top-up
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(0)
+            .build());
+        for buyer_amount in [one_near / 2, one_near] {
+            contract.internal_add_trade(
+                accounts(3),
+                Some("1:1".to_string()),
+                None,
+                accounts(1),
+                Some("1:2".to_string()),
+                accounts(2),
+                1,
+                None,
+                Some(U128(buyer_amount)),
+                Some(U128(one_near)),
+            );
+        }
+        assert_eq!(contract.get_claimable_balance(accounts(2), near_account()), U128(0));
+
+        let trade_data = contract.get_trade(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(2),
+            accounts(1),
+            "1:2".to_string(),
+        );
+        assert_eq!(trade_data.buyer_amount, Some(one_near));
+        assert_eq!(trade_data.seller_amount, Some(one_near));
+        assert_eq!(trade_data.ft_token_id, Some(NEAR.to_string()));
+
+        contract.internal_accept_trade(
+            accounts(3),
+            accounts(2),
+            "1:1".to_string(),
+            accounts(4),
+            1,
+            accounts(1),
+            "1:2".to_string(),
+        );
+        assert_eq!(contract.get_claimable_balance(accounts(4), near_account()), U128(0));
+
+        // the accepted trade leaves no index entries behind
+        assert_eq!(contract.get_supply_by_owner_id(accounts(2)), U64(0));
+        assert!(contract.get_trades_by_owner_id(accounts(2), None, None).is_empty());
+
+        // both top-ups are refunded when a leg of the swap fails
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(vec![]), PromiseResult::Failed]
+        );
+        assert!(!contract.callback_escrow(U64(0)));
+        assert_eq!(contract.get_claimable_balance(accounts(2), near_account()), U128(one_near));
+        assert_eq!(contract.get_claimable_balance(accounts(4), near_account()), U128(one_near));
+    }
+
+    #[test]
+    fn test_trade_top_up_transaction_fee_locked() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        for account_id in [accounts(2), accounts(4)] {
+            testing_env!(context
+                .predecessor_account_id(account_id.clone())
+                .attached_deposit(STORAGE_ADD_MARKET_DATA * 3)
+                .build());
+            contract.storage_deposit(None, None);
+            testing_env!(context.attached_deposit(one_near).build());
+            contract.deposit();
+        }
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(0)
+            .build());
+        contract.internal_add_trade(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(1),
+            Some("1:2".to_string()),
+            accounts(2),
+            1,
+            None,
+            Some(U128(one_near)),
+            Some(U128(one_near)),
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        let start_time: Timestamp = DEFAULT_ADMIN_ACTION_DELAY * 2;
+        let action_id = contract.set_transaction_fee(1000, Some(to_sec(start_time)));
+        execute_admin_action_after_delay(&mut context, &mut contract, action_id);
+        testing_env!(context.block_timestamp(start_time).build());
+        assert_eq!(contract.calculate_current_transaction_fee(), 1000);
+
+        contract.internal_accept_trade(
+            accounts(3),
+            accounts(2),
+            "1:1".to_string(),
+            accounts(4),
+            1,
+            accounts(1),
+            "1:2".to_string(),
+        );
+
+        // both top-ups pay the fee locked when the trade was added
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(vec![]), PromiseResult::Successful(vec![])]
+        );
+        assert!(contract.callback_escrow(U64(0)));
+        assert_eq!(
+            contract.get_treasury_balance(near_account()),
+            U128(one_near * 2 * 500 / MAX_TREASURY_PERCENTAGE as u128)
+        );
+    }
+
+    fn add_bundle_trade(context: &mut VMContextBuilder, contract: &mut Contract) -> U64 {
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .build());
+        contract.storage_deposit(None, None);
+
+        testing_env!(context.attached_deposit(1).build());
+        contract.add_bundle_trade(
+            accounts(3),
+            vec![
+                BundleToken {
+                    nft_contract_id: accounts(2),
+                    token_id: "1:1".to_string(),
+                },
+                BundleToken {
+                    nft_contract_id: accounts(2),
+                    token_id: "1:2".to_string(),
+                },
+            ],
+            vec![BundleToken {
+                nft_contract_id: accounts(2),
+                token_id: "2:1".to_string(),
+            }],
+        )
+    }
+
+    #[test]
+    fn test_bundle_trade() {
+        let (mut context, mut contract) = setup_contract();
+
+        let bundle_id = add_bundle_trade(&mut context, &mut contract);
+
+        contract.internal_approve_bundle_trade(bundle_id.0, accounts(2), "1:1".to_string(), accounts(1), 1);
+        contract.internal_approve_bundle_trade(bundle_id.0, accounts(2), "1:2".to_string(), accounts(1), 2);
+        contract.internal_approve_bundle_trade(bundle_id.0, accounts(2), "2:1".to_string(), accounts(3), 3);
+
+        let bundle_trade = contract.get_bundle_trade(bundle_id);
+        assert_eq!(bundle_trade.buyer_tokens[1].approval_id, Some(U64(2)));
+        assert_eq!(bundle_trade.seller_tokens[0].approval_id, Some(U64(3)));
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.accept_bundle_trade(bundle_id);
+        assert!(contract.get_bundle_trades(None, None).is_empty());
+        assert_eq!(contract.get_escrow(U64(0)).escrow.state, EscrowState::Escrowing);
+
+        // the second buyer token failed to escrow, the other two are returned
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![
+                PromiseResult::Successful(vec![]),
+                PromiseResult::Failed,
+                PromiseResult::Successful(vec![]),
+            ]
+        );
+        assert!(!contract.callback_escrow(U64(0)));
+        assert!(get_logs().contains(&"Paras: swap failed, rollback escrowed nfts".to_string()));
+        assert_eq!(contract.get_escrow(U64(0)).escrow.state, EscrowState::RollingBack);
+    }
+
+    fn rollback_failed_escrow(context: &mut VMContextBuilder, contract: &mut Contract) {
+        let bundle_id = add_bundle_trade(context, contract);
+        contract.internal_approve_bundle_trade(bundle_id.0, accounts(2), "1:1".to_string(), accounts(1), 1);
+        contract.internal_approve_bundle_trade(bundle_id.0, accounts(2), "1:2".to_string(), accounts(1), 2);
+        contract.internal_approve_bundle_trade(bundle_id.0, accounts(2), "2:1".to_string(), accounts(3), 3);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.accept_bundle_trade(bundle_id);
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![
+                PromiseResult::Successful(vec![]),
+                PromiseResult::Failed,
+                PromiseResult::Successful(vec![]),
+            ]
+        );
+        contract.callback_escrow(U64(0));
+
+        // returning the seller token fails
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(vec![]), PromiseResult::Failed]
+        );
+        assert!(!contract.callback_escrow_transfer(U64(0), vec![0, 2]));
+    }
+
+    #[test]
+    fn test_claim_stuck_escrow_token() {
+        let (mut context, mut contract) = setup_contract();
+
+        rollback_failed_escrow(&mut context, &mut contract);
+
+        let escrows = contract.get_escrows(Some(EscrowState::RollbackFailed), None, None);
+        assert_eq!(escrows.len(), 1);
+        assert!(!escrows[0].escrow.tokens[0].is_escrowed);
+        assert!(escrows[0].escrow.tokens[2].is_escrowed);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        contract.claim_escrow_token(U64(0), accounts(2), "2:1".to_string());
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        assert!(contract.callback_escrow_transfer(U64(0), vec![2]));
+        assert!(contract.get_escrows(None, None, None).is_empty());
+    }
+
+    #[test]
+    fn test_retry_stuck_escrow() {
+        let (mut context, mut contract) = setup_contract();
+
+        rollback_failed_escrow(&mut context, &mut contract);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.retry_escrow(U64(0));
+        assert_eq!(contract.get_escrow(U64(0)).escrow.state, EscrowState::RollingBack);
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Only danny can claim the token")]
+    fn test_invalid_claim_escrow_token_destination() {
+        let (mut context, mut contract) = setup_contract();
+
+        rollback_failed_escrow(&mut context, &mut contract);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.claim_escrow_token(U64(0), accounts(2), "2:1".to_string());
+    }
 
-pub fn hash_contract_account_id_token_id(
-    contract_account_id_token_id: &ContractAccountIdTokenId,
-) -> CryptoHash {
-    let mut hash = CryptoHash::default();
-    hash.copy_from_slice(&env::sha256(contract_account_id_token_id.as_bytes()));
-    hash
-}
+    #[test]
+    #[should_panic(expected = "Paras: Bundle trade is not fully approved")]
+    fn test_invalid_accept_bundle_trade_not_approved() {
+        let (mut context, mut contract) = setup_contract();
 
-pub fn to_sec(timestamp: Timestamp) -> TimestampSec {
-    (timestamp / 10u64.pow(9)) as u32
-}
+        let bundle_id = add_bundle_trade(&mut context, &mut contract);
+        contract.internal_approve_bundle_trade(bundle_id.0, accounts(2), "2:1".to_string(), accounts(3), 1);
 
-#[ext_contract(ext_self)]
-trait ExtSelf {
-    fn resolve_purchase(
-        &mut self,
-        buyer_id: AccountId,
-        market_data: MarketData,
-        price: U128,
-    ) -> Promise;
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.accept_bundle_trade(bundle_id);
+    }
 
-    fn resolve_offer(
-        &mut self,
-        seller_id: AccountId,
-        offer_data: OfferData,
-        token_id: TokenId,
-    ) -> Promise;
+    #[test]
+    #[should_panic(expected = "Paras: Owner is not part of the bundle trade")]
+    fn test_invalid_approve_bundle_trade_owner() {
+        let (mut context, mut contract) = setup_contract();
 
-    fn callback_first_trade(
-        &mut self,
-        seller_nft_contract_id: AccountId,
-        seller_token_id: TokenId,
-        seller_approval_id: u64,
-    ) -> Promise;
+        let bundle_id = add_bundle_trade(&mut context, &mut contract);
+        contract.internal_approve_bundle_trade(bundle_id.0, accounts(2), "1:1".to_string(), accounts(4), 1);
+    }
 
-    fn callback_second_trade(
-        &mut self,
-        buyer_id: AccountId,
-        buyer_nft_contract_id: AccountId,
-        buyer_token_id: TokenId,
-        seller_id: AccountId,
-        seller_nft_contract_id: AccountId,
-        seller_token_id: TokenId,
-    ) -> Promise;
-}
+    fn add_bundle_listing(context: &mut VMContextBuilder, contract: &mut Contract, price: u128) -> U64 {
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .build());
+        contract.storage_deposit(None, None);
+
+        testing_env!(context.attached_deposit(1).build());
+        contract.add_bundle_listing(
+            vec![
+                BundleToken {
+                    nft_contract_id: accounts(2),
+                    token_id: "1:1".to_string(),
+                },
+                BundleToken {
+                    nft_contract_id: accounts(2),
+                    token_id: "1:2".to_string(),
+                },
+            ],
+            near_account(),
+            U128(price),
+        )
+    }
 
-fn add_accounts(accounts: Option<Vec<AccountId>>, set: &mut UnorderedSet<AccountId>) {
-    accounts.map(|ids| {
-        ids.iter().for_each(|id| {
-            set.insert(id);
-        })
-    });
-}
+    #[test]
+    fn test_bundle_listing() {
+        let (mut context, mut contract) = setup_contract();
 
-fn remove_accounts(accounts: Option<Vec<AccountId>>, set: &mut UnorderedSet<AccountId>) {
-    accounts.map(|ids| {
-        ids.iter().for_each(|id| {
-            set.remove(id);
-        })
-    });
-}
+        let one_near = 10u128.pow(24);
+        let bundle_id = add_bundle_listing(&mut context, &mut contract, one_near);
+        contract.internal_approve_bundle_listing(bundle_id.0, accounts(2), "1:1".to_string(), accounts(3), 1);
+        contract.internal_approve_bundle_listing(bundle_id.0, accounts(2), "1:2".to_string(), accounts(3), 2);
 
-fn make_triple(nft_contract_id: &AccountId, buyer_id: &AccountId, token: &str) -> String {
-    format!(
-        "{}{}{}{}{}",
-        nft_contract_id, DELIMETER, buyer_id, DELIMETER, token
-    )
-}
+        let bundle_listing = contract.get_bundle_listing(bundle_id);
+        assert_eq!(bundle_listing.tokens[1].approval_id, Some(U64(2)));
+        assert_eq!(bundle_listing.transaction_fees, vec![U128(500), U128(500)]);
 
-fn make_key_owner_by_id_trade(contract_account_id_token_id: String) -> String {
-    format!("{}{}trade", contract_account_id_token_id, DELIMETER)
-}
+        // the fee is locked when the bundle is listed
+        contract.transaction_fee.set(1000, None);
 
-#[cfg(all(test, not(target_arch = "wasm32")))]
-mod tests {
-    use super::*;
-    use near_sdk::test_utils::{accounts, VMContextBuilder};
-    use near_sdk::testing_env;
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(one_near)
+            .build());
+        contract.buy_bundle(bundle_id, None);
+        assert!(contract.get_bundle_listings(None, None).is_empty());
+        let escrow = contract.get_escrow(U64(0)).escrow;
+        assert_eq!(escrow.state, EscrowState::Escrowing);
+        assert_eq!(
+            escrow.purchase.unwrap().token_prices,
+            vec![U128(one_near / 2), U128(one_near / 2)]
+        );
 
-    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
-        let mut builder = VMContextBuilder::new();
-        builder
-            .current_account_id(accounts(0))
-            .signer_account_id(predecessor_account_id.clone())
-            .predecessor_account_id(predecessor_account_id);
-        builder
-    }
+        // both tokens are escrowed, they are released to the buyer and paid out
+        let mut payout = PayoutHashMap::new();
+        payout.insert(accounts(3), U128(one_near / 2));
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![
+                PromiseResult::Successful(near_sdk::serde_json::to_vec(&payout).unwrap()),
+                PromiseResult::Successful(near_sdk::serde_json::to_vec(&payout).unwrap()),
+            ]
+        );
+        assert!(contract.callback_escrow(U64(0)));
+        let escrow = contract.get_escrow(U64(0)).escrow;
+        assert_eq!(escrow.state, EscrowState::Releasing);
+        assert_eq!(escrow.destination(&escrow.tokens[0]), accounts(1));
 
-    fn setup_contract() -> (VMContextBuilder, Contract) {
-        let mut context = VMContextBuilder::new();
-        testing_env!(context.predecessor_account_id(accounts(0)).build());
-        let contract = Contract::new(
-            accounts(0),
-            accounts(1),
-            None,
-            Some(vec![accounts(2)]),
-            Some(vec![accounts(2)]),
-            500,
+        // 5% treasury fee on both tokens, as when listed
+        assert_eq!(
+            contract.get_treasury_balance(near_account()),
+            U128(one_near * 500 / 10_000)
         );
-        (context, contract)
     }
 
     #[test]
-    fn test_new() {
-        let mut context = get_context(accounts(0));
-        testing_env!(context.build());
-        let contract = Contract::new(
-            accounts(0),
-            accounts(1),
-            None,
-            Some(vec![accounts(2)]),
-            Some(vec![accounts(2)]),
-            500,
-        );
-        testing_env!(context.is_view(true).build());
-        assert_eq!(contract.get_owner(), accounts(0));
-        assert_eq!(contract.get_treasury(), accounts(1));
-        assert_eq!(contract.approved_nft_contract_ids(), vec![accounts(2)]);
-        assert_eq!(contract.transaction_fee.current_fee, 500);
+    fn test_bundle_listing_token_price() {
+        let token = BundleToken {
+            nft_contract_id: accounts(2),
+            token_id: "1:1".to_string(),
+        };
+        let bundle_listing = BundleListing {
+            owner_id: accounts(3),
+            tokens: vec![token.clone(), token.clone(), token],
+            ft_token_id: near_account(),
+            price: U128(10),
+            transaction_fees: vec![U128(500); 3],
+        };
+
+        // the price is split evenly, the last token takes the remainder
+        assert_eq!(bundle_listing.token_price(0), 3);
+        assert_eq!(bundle_listing.token_price(1), 3);
+        assert_eq!(bundle_listing.token_price(2), 4);
     }
 
     #[test]
-    fn test_set_treasury() {
+    fn test_bundle_listing_rollback() {
         let (mut context, mut contract) = setup_contract();
 
+        let one_near = 10u128.pow(24);
+        let bundle_id = add_bundle_listing(&mut context, &mut contract, one_near);
+        contract.internal_approve_bundle_listing(bundle_id.0, accounts(2), "1:1".to_string(), accounts(3), 1);
+        contract.internal_approve_bundle_listing(bundle_id.0, accounts(2), "1:2".to_string(), accounts(3), 2);
+
         testing_env!(context
-            .predecessor_account_id(accounts(0))
-            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(one_near)
             .build());
-
-        contract.set_treasury(accounts(5));
-        let new_treasury: AccountId = contract.get_treasury();
-        assert_eq!(new_treasury, accounts(5));
+        contract.buy_bundle(bundle_id, None);
+
+        // the second token failed to escrow, the first one is returned and nothing is sold
+        let mut payout = PayoutHashMap::new();
+        payout.insert(accounts(3), U128(one_near / 2));
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![
+                PromiseResult::Successful(near_sdk::serde_json::to_vec(&payout).unwrap()),
+                PromiseResult::Failed,
+            ]
+        );
+        assert!(!contract.callback_escrow(U64(0)));
+        let escrow = contract.get_escrow(U64(0)).escrow;
+        assert_eq!(escrow.state, EscrowState::RollingBack);
+        assert_eq!(escrow.destination(&escrow.tokens[0]), accounts(3));
+        assert!(escrow.purchase.is_none());
+        assert_eq!(contract.get_treasury_balance(near_account()), U128(0));
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.contains("resolve_bundle_purchase") && log.contains(&format!("\"refund\":\"{}\"", one_near))));
     }
 
     #[test]
-    #[should_panic(expected = "Paras: Owner only")]
-    fn test_invalid_set_treasury() {
+    #[should_panic(expected = "Paras: Bundle listing is not fully approved")]
+    fn test_invalid_buy_bundle_not_approved() {
         let (mut context, mut contract) = setup_contract();
 
+        let one_near = 10u128.pow(24);
+        let bundle_id = add_bundle_listing(&mut context, &mut contract, one_near);
+        contract.internal_approve_bundle_listing(bundle_id.0, accounts(2), "1:1".to_string(), accounts(3), 1);
+
         testing_env!(context
             .predecessor_account_id(accounts(1))
-            .attached_deposit(1)
+            .attached_deposit(one_near)
             .build());
+        contract.buy_bundle(bundle_id, None);
+    }
 
-        contract.set_treasury(accounts(5));
+    #[test]
+    #[should_panic(expected = "Paras: Owner is not the bundle listing owner")]
+    fn test_invalid_approve_bundle_listing_owner() {
+        let (mut context, mut contract) = setup_contract();
+
+        let bundle_id = add_bundle_listing(&mut context, &mut contract, 10u128.pow(24));
+        contract.internal_approve_bundle_listing(bundle_id.0, accounts(2), "1:1".to_string(), accounts(1), 1);
     }
 
     #[test]
-    fn test_transfer_ownership() {
+    #[should_panic(expected = "Paras: Duplicate token in bundle listing")]
+    fn test_invalid_add_bundle_listing_duplicate() {
         let (mut context, mut contract) = setup_contract();
 
         testing_env!(context
-            .predecessor_account_id(accounts(0))
-            .attached_deposit(1)
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
             .build());
+        contract.storage_deposit(None, None);
 
-        contract.transfer_ownership(accounts(5));
-        let new_owner: AccountId = contract.get_owner();
-        assert_eq!(new_owner, accounts(5));
+        testing_env!(context.attached_deposit(1).build());
+        let token = BundleToken {
+            nft_contract_id: accounts(2),
+            token_id: "1:1".to_string(),
+        };
+        contract.add_bundle_listing(vec![token.clone(), token], near_account(), U128(10u128.pow(24)));
     }
 
     #[test]
-    #[should_panic(expected = "Paras: Owner only")]
-    fn test_invalid_transfer_ownership() {
+    #[should_panic(expected = "Paras: Trade list does not exist")]
+    fn test_delete_trade() {
         let (mut context, mut contract) = setup_contract();
 
+        let one_near = 10u128.pow(24);
+
         testing_env!(context
-            .predecessor_account_id(accounts(5))
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.internal_add_trade(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(1),
+            Some("1:1".to_string()),
+            accounts(2),
+            1,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
             .attached_deposit(1)
             .build());
 
-        contract.transfer_ownership(accounts(5));
+        contract.delete_trade(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(1),
+            "1:2".to_string(),
+        );
+        contract.get_trade(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(1),
+            accounts(1),
+            "1:2".to_string(),
+        );
     }
 
     #[test]
-    fn test_add_approved_nft_contract_ids() {
+    fn test_internal_add_market_data_auction() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            None,
+            Some(U64(1999999952971000000)),
+            None,
+            Some(true),
+            None,
+            None,
+        );
+
+        let market = contract.get_market_data(accounts(2), "1:1".to_string());
+        assert_eq!(market.is_auction, Some(true));
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: the NFT is on auction")]
+    fn test_bid_invalid_purchase() {
         let (mut context, mut contract) = setup_contract();
 
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            None,
+            Some(U64(1999999952971000000)),
+            None,
+            Some(true),
+            None,
+            None,
+        );
+
         testing_env!(context
-            .predecessor_account_id(accounts(0))
-            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(10u128.pow(24))
             .build());
-
-        contract.add_approved_nft_contract_ids(vec![accounts(5)]);
-        let approved_nfts = contract.approved_nft_contract_ids();
-        assert_eq!(approved_nfts, vec![accounts(2), accounts(5)]);
+
+        contract.buy(accounts(2), "1:1".to_string(), None, None, None);
     }
 
     #[test]
-    fn test_remove_approved_nft_contract_ids() {
+    #[should_panic(expected = "Paras: Use ft_transfer_call to buy with")]
+    fn test_invalid_buy_ft_listing_with_near() {
         let (mut context, mut contract) = setup_contract();
 
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            accounts(4),
+            U128::from(10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
         testing_env!(context
-            .predecessor_account_id(accounts(0))
-            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(10u128.pow(24))
             .build());
 
-        contract.add_approved_nft_contract_ids(vec![accounts(5)]);
-        contract.remove_approved_nft_contract_ids(vec![accounts(5)]);
-        let approved_nfts = contract.approved_nft_contract_ids();
-        assert_eq!(approved_nfts, vec![accounts(2)]);
+        contract.buy(accounts(2), "1:1".to_string(), None, None, None);
     }
 
     #[test]
-    fn test_internal_add_market_data() {
+    fn test_buy_with_ft() {
         let (mut context, mut contract) = setup_contract();
 
         testing_env!(context.predecessor_account_id(accounts(0)).build());
@@ -2552,27 +8280,33 @@ mod tests {
             1,
             accounts(2),
             "1:1".to_string(),
-            near_account(),
-            U128::from(1 * 10u128.pow(24)),
+            accounts(4),
+            U128::from(10u128.pow(24)),
+            None,
+            None,
             None,
             None,
             None,
             None,
         );
 
-        let market = contract.get_market_data(accounts(2), "1:1".to_string());
-        assert_eq!(market.owner_id, accounts(3));
-        assert_eq!(market.approval_id, U64::from(1));
-        assert_eq!(market.ft_token_id, near_account());
-        assert_eq!(market.nft_contract_id, accounts(2));
-        assert_eq!(market.owner_id, accounts(3));
-        assert_eq!(market.token_id, "1:1".to_string());
-        assert_eq!(market.price, U128::from(1 * 10u128.pow(24)));
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+
+        contract.internal_buy_with_ft(
+            accounts(2),
+            "1:1".to_string(),
+            accounts(4),
+            accounts(1),
+            10u128.pow(24),
+            None,
+        );
+
+        assert!(contract.market.get(&format!("{}{}{}", accounts(2), DELIMETER, "1:1")).is_none());
     }
 
     #[test]
-    #[should_panic(expected = "Paras: price higher than 1000000000000000000000000000000000")]
-    fn test_invalid_price_higher_than_max_price() {
+    #[should_panic(expected = "Paras: The transferred amount should be exactly the price")]
+    fn test_invalid_buy_with_ft_amount() {
         let (mut context, mut contract) = setup_contract();
 
         testing_env!(context.predecessor_account_id(accounts(0)).build());
@@ -2582,21 +8316,69 @@ mod tests {
             1,
             accounts(2),
             "1:1".to_string(),
-            near_account(),
-            U128::from(1_000_000_000 * 10u128.pow(24)),
+            accounts(4),
+            U128::from(10u128.pow(24)),
+            None,
+            None,
+            None,
             None,
             None,
             None,
+        );
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+
+        contract.internal_buy_with_ft(
+            accounts(2),
+            "1:1".to_string(),
+            accounts(4),
+            accounts(1),
+            10u128.pow(23),
             None,
         );
     }
 
     #[test]
-    #[should_panic(expected = "Paras: Market data does not exist")]
-    fn test_delete_market_data() {
+    fn test_failed_ft_transfer_is_claimable() {
         let (mut context, mut contract) = setup_contract();
 
-        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![PromiseResult::Failed]
+        );
+
+        contract.resolve_ft_transfer(accounts(4), accounts(3), U128(100));
+        assert_eq!(contract.get_claimable_balance(accounts(3), accounts(4)), U128(100));
+
+        contract.resolve_ft_transfer(accounts(4), accounts(3), U128(50));
+        assert_eq!(contract.get_claimable_balance(accounts(3), accounts(4)), U128(150));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+
+        assert_eq!(contract.claim(accounts(4), None), U128(150));
+        assert_eq!(contract.get_claimable_balance(accounts(3), accounts(4)), U128(0));
+    }
+
+    #[test]
+    fn test_referral_fee_is_shared() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        let action_id = contract.set_referral_fee_share(5_000);
+        execute_admin_action_after_delay(&mut context, &mut contract, action_id);
+        assert_eq!(contract.get_referral_fee_share(), 5_000);
 
         contract.internal_add_market_data(
             accounts(3),
@@ -2604,189 +8386,436 @@ mod tests {
             accounts(2),
             "1:1".to_string(),
             near_account(),
-            U128::from(1 * 10u128.pow(24)),
+            U128::from(one_near),
+            None,
             None,
             None,
             None,
             None,
+            None,
+        );
+        let market_data = contract
+            .internal_get_market_data(&format!("{}{}{}", accounts(2), DELIMETER, "1:1"))
+            .unwrap();
+
+        let mut payout = PayoutHashMap::new();
+        payout.insert(accounts(3), U128(one_near));
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(near_sdk::serde_json::to_vec(&payout).unwrap())]
         );
 
+        contract.resolve_purchase(accounts(1), market_data, U128(one_near), U128(500), Some(accounts(5)), false, U128(0));
+
+        // 5% treasury fee, half of it to the referrer
+        let referral_fee = one_near * 500 / 10_000 / 2;
+        assert!(get_logs().contains(&format!(
+            r#"EVENT_JSON:{{"standard":"paras_marketplace","version":"1.0.0","event":"referral_fee","data":{{"referrer_id":"{}","buyer_id":"{}","nft_contract_id":"{}","token_id":"1:1","ft_token_id":"near","amount":"{}"}}}}"#,
+            accounts(5),
+            accounts(1),
+            accounts(2),
+            referral_fee
+        )));
+        assert_eq!(contract.get_treasury_balance(near_account()), U128(referral_fee));
+    }
+
+    #[test]
+    fn test_withdraw_treasury() {
+        let (mut context, mut contract) = setup_contract();
+
         testing_env!(context
-            .predecessor_account_id(accounts(3))
+            .predecessor_account_id(accounts(0))
             .attached_deposit(1)
             .build());
 
-        contract.delete_market_data(accounts(2), "1:1".to_string());
+        let action_id = contract.set_fee_beneficiaries(vec![
+            FeeBeneficiary { account_id: accounts(1), weight: 7_000 },
+            FeeBeneficiary { account_id: accounts(5), weight: 3_000 },
+        ]);
+        execute_admin_action_after_delay(&mut context, &mut contract, action_id);
 
-        contract.get_market_data(accounts(2), "1:1".to_string());
+        contract.internal_add_treasury_fee(
+            &accounts(4),
+            1_000,
+            None,
+            &accounts(3),
+            &accounts(2),
+            &"1:1".to_string(),
+        );
+        contract.internal_add_treasury_fee(
+            &accounts(4),
+            500,
+            None,
+            &accounts(3),
+            &accounts(2),
+            &"1:2".to_string(),
+        );
+        assert_eq!(contract.get_treasury_balances(), vec![(accounts(4), U128(1_500))]);
+
+        testing_env!(context.build());
+
+        assert_eq!(contract.withdraw_treasury(accounts(4), Some(U128(1_000))), U128(1_000));
+        assert_eq!(contract.get_treasury_balance(accounts(4)), U128(500));
+
+        let logs = get_logs();
+        assert_eq!(logs.len(), 2);
+        assert!(logs[0].contains(r#""event":"withdraw_treasury""#) && logs[0].contains(r#""amount":"700""#));
+        assert!(logs[1].contains(r#""amount":"300""#));
+
+        contract.withdraw_treasury(accounts(4), None);
+        assert!(contract.get_treasury_balances().is_empty());
     }
 
     #[test]
-    fn test_storage_deposit() {
+    fn test_set_fee_beneficiaries() {
         let (mut context, mut contract) = setup_contract();
 
         testing_env!(context
             .predecessor_account_id(accounts(0))
-            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .attached_deposit(1)
             .build());
 
-        contract.storage_deposit(None);
+        assert_eq!(
+            contract.get_fee_beneficiaries(),
+            vec![FeeBeneficiary { account_id: accounts(1), weight: 10_000 }]
+        );
+
+        let fee_beneficiaries = vec![
+            FeeBeneficiary { account_id: accounts(1), weight: 7_000 },
+            FeeBeneficiary { account_id: accounts(5), weight: 3_000 },
+        ];
+        let action_id = contract.set_fee_beneficiaries(fee_beneficiaries.clone());
+        execute_admin_action_after_delay(&mut context, &mut contract, action_id);
+        assert_eq!(contract.get_fee_beneficiaries(), fee_beneficiaries);
+    }
 
-        let storage_balance = contract.storage_balance_of(accounts(0)).0;
-        assert_eq!(STORAGE_ADD_MARKET_DATA, storage_balance);
+    #[test]
+    #[should_panic(expected = "Paras: Fee beneficiary weights should add up to 10000")]
+    fn test_invalid_fee_beneficiaries_weight() {
+        let (mut context, mut contract) = setup_contract();
 
         testing_env!(context
             .predecessor_account_id(accounts(0))
             .attached_deposit(1)
             .build());
 
-        contract.storage_withdraw();
+        contract.set_fee_beneficiaries(vec![
+            FeeBeneficiary { account_id: accounts(1), weight: 7_000 },
+            FeeBeneficiary { account_id: accounts(5), weight: 2_000 },
+        ]);
+    }
 
-        let storage_balance = contract.storage_balance_of(accounts(0)).0;
-        assert_eq!(0, storage_balance);
+    #[test]
+    #[should_panic(expected = "Paras: Cannot refer yourself")]
+    fn test_invalid_add_offer_self_referral() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(10u128.pow(24))
+            .build());
+
+        contract.add_offer(
+            accounts(2),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(10u128.pow(24)),
+            None,
+            Some(accounts(1)),
+            None,
+        );
     }
 
     #[test]
-    fn test_add_offer() {
+    #[should_panic(expected = "Paras: ft_on_transfer should only be called by an ft contract")]
+    fn test_invalid_ft_on_transfer_from_near() {
+        use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(near_account())
+            .signer_account_id(accounts(1))
+            .build());
+        contract.ft_on_transfer(
+            accounts(1),
+            U128(10u128.pow(24)),
+            "{\"market_type\":\"deposit\"}".to_string(),
+        );
+    }
+
+    #[test]
+    fn test_add_offer_from_internal_balance() {
         let (mut context, mut contract) = setup_contract();
 
         let one_near = 10u128.pow(24);
 
         testing_env!(context
-            .predecessor_account_id(accounts(0))
-            .attached_deposit(one_near)
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA * 3)
             .build());
+        contract.storage_deposit(None, None);
 
-        contract.internal_add_offer(
-            accounts(3),
+        testing_env!(context.attached_deposit(2 * one_near).build());
+        assert_eq!(contract.deposit(), U128(2 * one_near));
+
+        testing_env!(context.attached_deposit(0).build());
+        contract.add_offer(
+            accounts(2),
             Some("1:1".to_string()),
             None,
             near_account(),
             U128(one_near),
-            accounts(0),
+            None,
+            None,
+            None,
         );
+        assert_eq!(contract.get_claimable_balance(accounts(1), near_account()), U128(one_near));
 
-        let offer_data =
-            contract.get_offer(accounts(3), accounts(0), Some("1:1".to_string()), None);
+        // the replaced offer is refunded to the internal balance first
+        contract.add_offer(
+            accounts(2),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(one_near * 3 / 2),
+            None,
+            None,
+            None,
+        );
+        assert_eq!(
+            contract.get_claimable_balance(accounts(1), near_account()),
+            U128(one_near / 2)
+        );
 
-        assert_eq!(offer_data.buyer_id, accounts(0));
-        assert_eq!(offer_data.price, U128(one_near));
+        testing_env!(context.attached_deposit(1).build());
+        assert_eq!(contract.claim(near_account(), Some(U128(one_near / 4))), U128(one_near / 4));
+        assert_eq!(
+            contract.get_claimable_balance(accounts(1), near_account()),
+            U128(one_near / 4)
+        );
     }
 
     #[test]
-    #[should_panic(expected = "Paras: Offer does not exist")]
-    fn test_delete_offer() {
+    fn test_pooled_offers() {
         let (mut context, mut contract) = setup_contract();
 
         let one_near = 10u128.pow(24);
 
         testing_env!(context
-            .predecessor_account_id(accounts(0))
-            .attached_deposit(one_near)
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA * 3)
             .build());
+        contract.storage_deposit(None, None);
 
-        contract.internal_add_offer(
+        testing_env!(context.attached_deposit(one_near).build());
+        contract.deposit();
+
+        // both offers draw on the same pool, which covers only one of them
+        testing_env!(context.attached_deposit(0).build());
+        for token_id in ["1:1", "1:2"] {
+            contract.add_offer(
+                accounts(2),
+                Some(token_id.to_string()),
+                None,
+                near_account(),
+                U128(one_near),
+                None,
+                None,
+                Some(true),
+            );
+        }
+        assert_eq!(contract.get_claimable_balance(accounts(1), near_account()), U128(one_near));
+        assert!(contract.get_offer(accounts(2), accounts(1), Some("1:2".to_string()), None).is_pooled);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        let accepted = contract.internal_accept_offer(
+            accounts(2),
+            accounts(1),
+            "1:1".to_string(),
             accounts(3),
-            Some("1:1".to_string()),
+            1,
+            one_near,
+        );
+        assert!(matches!(accepted, PromiseOrValue::Promise(_)));
+        assert_eq!(contract.get_claimable_balance(accounts(1), near_account()), U128(0));
+
+        let accepted = contract.internal_accept_offer(
+            accounts(2),
+            accounts(1),
+            "1:2".to_string(),
+            accounts(4),
+            1,
+            one_near,
+        );
+        assert!(matches!(accepted, PromiseOrValue::Value(false)));
+
+        // deleting a pooled offer leaves nothing to refund
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.delete_offer(accounts(2), Some("1:2".to_string()), None);
+        assert_eq!(contract.get_claimable_balance(accounts(1), near_account()), U128(0));
+
+        // a failed transfer puts the accepted offer back into the pool
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.resolve_offer(
+            accounts(3),
+            OfferData {
+                buyer_id: accounts(1),
+                nft_contract_id: accounts(2),
+                token_id: Some("1:1".to_string()),
+                token_series_id: None,
+                ft_token_id: near_account(),
+                price: one_near,
+            },
+            "1:1".to_string(),
+            U128(500),
             None,
+            true,
+        );
+        assert_eq!(contract.get_claimable_balance(accounts(1), near_account()), U128(one_near));
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Insufficient near balance: 0, required 1000000000000000000000000")]
+    fn test_invalid_buy_insufficient_balance() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
             near_account(),
-            U128(one_near),
-            accounts(0),
+            U128::from(10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
         );
 
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+
+        contract.buy(accounts(2), "1:1".to_string(), None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Nothing to claim")]
+    fn test_invalid_claim_empty_balance() {
+        let (mut context, mut contract) = setup_contract();
+
         testing_env!(context
-            .predecessor_account_id(accounts(0))
+            .predecessor_account_id(accounts(3))
             .attached_deposit(1)
             .build());
 
-        contract.delete_offer(accounts(3), Some("1:1".to_string()), None);
-
-        contract.get_offer(accounts(3), accounts(1), Some("1:1".to_string()), None);
+        contract.claim(accounts(4), None);
     }
 
     #[test]
-    fn test_add_trade() {
+    fn test_dutch_auction_current_price() {
         let (mut context, mut contract) = setup_contract();
 
         let one_near = 10u128.pow(24);
+        let started_at: u64 = 1_000_000_000;
+        let ended_at: u64 = 2_000_000_000;
 
         testing_env!(context
             .predecessor_account_id(accounts(0))
-            .attached_deposit(one_near)
+            .block_timestamp(started_at - 1)
             .build());
 
-        contract.internal_add_trade(
+        contract.internal_add_market_data(
             accounts(3),
-            Some("1:1".to_string()),
-            None,
-            accounts(1),
-            Some("1:2".to_string()),
-            accounts(2),
             1,
-        );
-
-        let trade_data = contract.get_trade(
-            accounts(3),
-            Some("1:1".to_string()),
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(10 * one_near),
+            Some(U64(started_at)),
+            Some(U64(ended_at)),
+            Some(U128::from(2 * one_near)),
+            None,
+            None,
             None,
-            accounts(2),
-            accounts(1),
-            "1:2".to_string(),
         );
 
-        assert_eq!(trade_data.token_id.unwrap().to_string(), "1:1");
-        assert_eq!(trade_data.nft_contract_id, accounts(3));
+        assert_eq!(contract.get_current_price(accounts(2), "1:1".to_string()), U128(10 * one_near));
+
+        testing_env!(context.block_timestamp(started_at + (ended_at - started_at) / 4).build());
+        assert_eq!(contract.get_current_price(accounts(2), "1:1".to_string()), U128(8 * one_near));
+
+        testing_env!(context.block_timestamp(ended_at + 1).build());
+        assert_eq!(contract.get_current_price(accounts(2), "1:1".to_string()), U128(2 * one_near));
     }
 
     #[test]
-    #[should_panic(expected = "Paras: Trade list does not exist")]
-    fn test_delete_trade() {
+    fn test_dutch_auction_buy_with_overpayment() {
         let (mut context, mut contract) = setup_contract();
 
         let one_near = 10u128.pow(24);
+        let started_at: u64 = 1_000_000_000;
+        let ended_at: u64 = 2_000_000_000;
 
         testing_env!(context
             .predecessor_account_id(accounts(0))
-            .attached_deposit(one_near)
+            .block_timestamp(started_at)
             .build());
 
-        contract.internal_add_trade(
+        contract.internal_add_market_data(
             accounts(3),
-            Some("1:1".to_string()),
-            None,
-            accounts(1),
-            Some("1:1".to_string()),
-            accounts(2),
             1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(10 * one_near),
+            None,
+            Some(U64(ended_at)),
+            Some(U128::from(2 * one_near)),
+            None,
+            None,
+            None,
         );
 
         testing_env!(context
-            .predecessor_account_id(accounts(0))
-            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(started_at + (ended_at - started_at) / 2)
+            .attached_deposit(10 * one_near)
             .build());
 
-        contract.delete_trade(
-            accounts(3),
-            Some("1:1".to_string()),
-            None,
-            accounts(1),
-            "1:2".to_string(),
-        );
-        contract.get_trade(
-            accounts(3),
-            Some("1:1".to_string()),
-            None,
-            accounts(1),
-            accounts(1),
-            "1:2".to_string(),
-        );
+        contract.buy(accounts(2), "1:1".to_string(), None, Some(U128(10 * one_near)), None);
+
+        assert!(contract.market.get(&format!("{}{}{}", accounts(2), DELIMETER, "1:1")).is_none());
     }
 
     #[test]
-    fn test_internal_add_market_data_auction() {
+    fn test_dutch_auction_current_price_over_days() {
         let (mut context, mut contract) = setup_contract();
 
-        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let one_near = 10u128.pow(24);
+        let one_day: u64 = 86_400 * 10u64.pow(9);
+        let started_at: u64 = one_day;
+        let ended_at: u64 = started_at + 30 * one_day;
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(started_at)
+            .build());
 
         contract.internal_add_market_data(
             accounts(3),
@@ -2794,20 +8823,106 @@ mod tests {
             accounts(2),
             "1:1".to_string(),
             near_account(),
-            U128::from(1 * 10u128.pow(24)),
+            U128::from(1_000 * one_near),
+            Some(U64(started_at)),
+            Some(U64(ended_at)),
+            Some(U128::from(0)),
+            None,
             None,
-            Some(U64(1999999952971000000)),
             None,
-            Some(true),
         );
 
-        let market = contract.get_market_data(accounts(2), "1:1".to_string());
-        assert_eq!(market.is_auction, Some(true));
+        testing_env!(context.block_timestamp(started_at + 15 * one_day).build());
+        assert_eq!(contract.get_current_price(accounts(2), "1:1".to_string()), U128(500 * one_near));
+
+        testing_env!(context.block_timestamp(started_at + 27 * one_day).build());
+        assert_eq!(contract.get_current_price(accounts(2), "1:1".to_string()), U128(100 * one_near));
     }
 
     #[test]
-    #[should_panic(expected = "Paras: the NFT is on auction")]
-    fn test_bid_invalid_purchase() {
+    fn test_buy_legacy_listing_with_end_price() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+        let contract_and_token_id = format!("{}{}{}", accounts(2), DELIMETER, "1:1");
+        // older listings could store end_price without started_at and ended_at
+        contract.market.insert(
+            &contract_and_token_id,
+            &MarketData {
+                owner_id: accounts(3),
+                approval_id: 1,
+                nft_contract_id: accounts(2),
+                token_id: "1:1".to_string(),
+                ft_token_id: near_account(),
+                price: one_near,
+                bids: None,
+                started_at: None,
+                ended_at: None,
+                end_price: Some(2 * one_near),
+                accept_nft_contract_id: None,
+                accept_token_id: None,
+                is_auction: None,
+            },
+        );
+        assert_eq!(contract.get_current_price(accounts(2), "1:1".to_string()), U128(one_near));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(one_near)
+            .build());
+        contract.buy(accounts(2), "1:1".to_string(), None, Some(U128(one_near)), None);
+
+        assert!(contract.market.get(&contract_and_token_id).is_none());
+    }
+
+    #[test]
+    fn test_resolve_purchase_returns_ft_overpayment() {
+        let (mut context, mut contract) = setup_contract();
+
+        let market_data = || MarketData {
+            owner_id: accounts(3),
+            approval_id: 1,
+            nft_contract_id: accounts(2),
+            token_id: "1:1".to_string(),
+            ft_token_id: accounts(4),
+            price: 1_000,
+            bids: None,
+            started_at: None,
+            ended_at: None,
+            end_price: None,
+            accept_nft_contract_id: None,
+            accept_token_id: None,
+            is_auction: None,
+        };
+
+        let mut payout = PayoutHashMap::new();
+        payout.insert(accounts(3), U128(800));
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(near_sdk::serde_json::to_vec(&payout).unwrap())]
+        );
+        let unused_amount =
+            contract.resolve_purchase(accounts(1), market_data(), U128(800), U128(500), None, false, U128(200));
+        assert_eq!(unused_amount, U128(200));
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![PromiseResult::Failed]
+        );
+        let unused_amount =
+            contract.resolve_purchase(accounts(1), market_data(), U128(800), U128(500), None, false, U128(200));
+        assert_eq!(unused_amount, U128(1_000));
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: End price should be lower than price")]
+    fn test_invalid_dutch_auction_end_price() {
         let (mut context, mut contract) = setup_contract();
 
         testing_env!(context.predecessor_account_id(accounts(0)).build());
@@ -2818,19 +8933,14 @@ mod tests {
             accounts(2),
             "1:1".to_string(),
             near_account(),
-            U128::from(1 * 10u128.pow(24)),
+            U128::from(10u128.pow(24)),
             None,
             Some(U64(1999999952971000000)),
+            Some(U128::from(2 * 10u128.pow(24))),
+            None,
+            None,
             None,
-            Some(true),
         );
-
-        testing_env!(context
-            .predecessor_account_id(accounts(1))
-            .attached_deposit(10u128.pow(24))
-            .build());
-
-        contract.buy(accounts(2), "1:1".to_string(), None, None);
     }
 
     #[test]
@@ -2850,8 +8960,18 @@ mod tests {
             Some(U64(1999999952971000000)),
             None,
             Some(true),
+            None,
+            None,
         );
 
+        for bidder_id in [accounts(0), accounts(4)] {
+            testing_env!(context
+                .predecessor_account_id(bidder_id)
+                .attached_deposit(STORAGE_ADD_MARKET_DATA)
+                .build());
+            contract.storage_deposit(None, None);
+        }
+
         testing_env!(context
             .predecessor_account_id(accounts(0))
             .attached_deposit(10u128.pow(24) + 1)
@@ -2862,6 +8982,7 @@ mod tests {
             near_account(),
             "1:1".to_string(),
             U128::from(10u128.pow(24) + 1),
+            None,
         );
 
         testing_env!(context
@@ -2874,6 +8995,7 @@ mod tests {
             near_account(),
             "1:1".to_string(),
             U128::from(10u128.pow(24) + 10u128.pow(24) * 5 / 100 + 1),
+            None,
         );
 
         testing_env!(context
@@ -2882,6 +9004,191 @@ mod tests {
             .build());
 
         contract.end_auction(accounts(2), "1:1".to_string());
+
+        // only the storage_used entry is left once the bids are cleared
+        assert!(contract.get_storage_used(accounts(4)).0 < 100);
+
+        // the outbid bidder pays for the refund entry until it is claimed
+        assert_eq!(
+            contract.get_claimable_balance(accounts(0), near_account()),
+            U128::from(10u128.pow(24) + 1)
+        );
+        assert_eq!(contract.get_claimable_balance(accounts(4), near_account()), U128(0));
+        assert!(contract.get_storage_used(accounts(0)).0 > 100);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.claim(near_account(), None);
+        assert!(contract.get_storage_used(accounts(0)).0 < 100);
+    }
+
+    #[test]
+    fn test_buy_with_nft() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128(one_near),
+            None,
+            None,
+            None,
+            None,
+            Some(accounts(2)),
+            Some("2:1".to_string()),
+        );
+        let market_data = contract.get_market_data(accounts(2), "1:1".to_string());
+        assert_eq!(market_data.accept_token_id, Some("2:1".to_string()));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .build());
+        contract.storage_deposit(None, None);
+        testing_env!(context.attached_deposit(one_near).build());
+        contract.deposit();
+
+        testing_env!(context.attached_deposit(0).build());
+        contract.internal_buy_with_nft(
+            accounts(2),
+            "1:1".to_string(),
+            accounts(1),
+            accounts(2),
+            "2:1".to_string(),
+            2,
+        );
+        assert_eq!(contract.get_claimable_balance(accounts(1), near_account()), U128(0));
+        assert_eq!(contract.get_supply_by_owner_id(accounts(3)), U64(0));
+
+        // a failed swap credits the escrowed price back to the buyer
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![PromiseResult::Failed, PromiseResult::Failed]
+        );
+        assert!(!contract.callback_escrow(U64(0)));
+        assert_eq!(contract.get_claimable_balance(accounts(1), near_account()), U128(one_near));
+        // nothing reached the marketplace, the escrow is rolled back right away
+        assert!(contract.get_escrows(None, None, None).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Listing only accepts an NFT as payment")]
+    fn test_invalid_buy_nft_payment_listing() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128(10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            Some(accounts(2)),
+            Some("2:1".to_string()),
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(10u128.pow(24))
+            .build());
+        contract.buy(accounts(2), "1:1".to_string(), None, None, None);
+    }
+
+    #[test]
+    fn test_failed_near_transfer_is_claimable() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![PromiseResult::Failed]
+        );
+
+        contract.resolve_ft_transfer(near_account(), accounts(3), U128(100));
+        assert_eq!(contract.get_claimable_balance(accounts(3), near_account()), U128(100));
+    }
+
+    #[test]
+    fn test_failed_near_payouts_are_claimable() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::free(),
+            HashMap::default(),
+            vec![
+                PromiseResult::Successful(vec![]),
+                PromiseResult::Failed,
+                PromiseResult::Successful(vec![]),
+            ]
+        );
+
+        contract.resolve_near_payouts(vec![
+            (accounts(3), U128(100)),
+            (accounts(4), U128(200)),
+            (accounts(5), U128(300)),
+        ]);
+        assert_eq!(contract.get_claimable_balance(accounts(3), near_account()), U128(0));
+        assert_eq!(contract.get_claimable_balance(accounts(4), near_account()), U128(200));
+        assert_eq!(contract.get_claimable_balance(accounts(5), near_account()), U128(0));
+
+        // accounts(4) has no storage deposit, the contract pays for its entry
+        assert_eq!(contract.get_storage_used(accounts(4)), U64(0));
+        testing_env!(context.predecessor_account_id(accounts(4)).attached_deposit(1).build());
+        contract.claim(near_account(), None);
+        assert_eq!(contract.get_claimable_balance(accounts(4), near_account()), U128(0));
+        assert_eq!(contract.get_storage_used(accounts(4)), U64(0));
+    }
+
+    #[test]
+    fn test_claimable_balance_needs_storage_deposit() {
+        let (mut context, mut contract) = setup_contract();
+
+        // without a storage deposit the amount is transferred instead
+        contract.internal_add_claimable_balance(&accounts(1), &near_account(), 100);
+        assert_eq!(contract.get_claimable_balance(accounts(1), near_account()), U128(0));
+        assert_eq!(contract.get_storage_used(accounts(1)), U64(0));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .build());
+        contract.storage_deposit(None, None);
+
+        contract.internal_add_claimable_balance(&accounts(1), &near_account(), 100);
+        assert_eq!(contract.get_claimable_balance(accounts(1), near_account()), U128(100));
+        assert!(contract.get_storage_used(accounts(1)).0 > 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Paras: Insufficient storage paid for the internal balance")]
+    fn test_invalid_deposit_without_storage_deposit() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(10u128.pow(24))
+            .build());
+        contract.deposit();
     }
 
     #[test]
@@ -2893,8 +9200,10 @@ mod tests {
             .attached_deposit(1)
             .build());
 
-        contract.set_transaction_fee(100, None);
+        let action_id = contract.set_transaction_fee(100, None);
+        assert_eq!(contract.get_transaction_fee().current_fee, 500);
 
+        execute_admin_action_after_delay(&mut context, &mut contract, action_id);
         assert_eq!(contract.get_transaction_fee().current_fee, 100);
     }
 
@@ -2914,7 +9223,8 @@ mod tests {
         let next_fee: u16 = 100;
         let start_time: Timestamp = 1618109122863866400;
         let start_time_sec: TimestampSec = to_sec(start_time);
-        contract.set_transaction_fee(next_fee, Some(start_time_sec));
+        let action_id = contract.set_transaction_fee(next_fee, Some(start_time_sec));
+        execute_admin_action_after_delay(&mut context, &mut contract, action_id);
 
         assert_eq!(contract.get_transaction_fee().current_fee, 500);
         assert_eq!(contract.get_transaction_fee().next_fee, Some(next_fee));
@@ -2934,6 +9244,29 @@ mod tests {
         assert_eq!(contract.get_transaction_fee().start_time, None);
     }
 
+    #[test]
+    fn test_change_transaction_fee_executed_after_start_time() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        let start_time: Timestamp = 1618109122863866400;
+        let action_id = contract.set_transaction_fee(100, Some(to_sec(start_time)));
+
+        // the queued fee is executed after its start_time, it applies right away
+        testing_env!(context
+            .block_timestamp(start_time + DEFAULT_ADMIN_ACTION_DELAY)
+            .attached_deposit(1)
+            .build());
+        contract.execute_admin_action(action_id);
+        assert_eq!(contract.get_transaction_fee().current_fee, 100);
+        assert_eq!(contract.get_transaction_fee().next_fee, None);
+        assert_eq!(contract.get_transaction_fee().start_time, None);
+    }
+
     #[test]
     fn test_transaction_fee_locked(){
         let (mut context, mut contract) = setup_contract();
@@ -2950,7 +9283,8 @@ mod tests {
         let next_fee: u16 = 100;
         let start_time: Timestamp = 1618109122863866400;
         let start_time_sec: TimestampSec = to_sec(start_time);
-        contract.set_transaction_fee(next_fee, Some(start_time_sec));
+        let action_id = contract.set_transaction_fee(next_fee, Some(start_time_sec));
+        execute_admin_action_after_delay(&mut context, &mut contract, action_id);
 
         contract.internal_add_market_data(
             accounts(3),
@@ -2963,6 +9297,8 @@ mod tests {
             None,
             None,
             None,
+            None,
+            None,
         );
 
         assert_eq!(contract.get_transaction_fee().current_fee, 500);
@@ -2987,6 +9323,61 @@ mod tests {
         assert_eq!(market_data_transaction_fee, 500);
     }
 
+    #[test]
+    fn test_nft_contract_transaction_fee() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        let action_id = contract.set_nft_contract_transaction_fee(accounts(2), 200, None);
+        execute_admin_action_after_delay(&mut context, &mut contract, action_id);
+        assert_eq!(contract.get_nft_contract_transaction_fee(accounts(2)).unwrap().current_fee, 200);
+        assert_eq!(contract.get_effective_transaction_fee(accounts(2)), 200);
+        assert_eq!(contract.get_effective_transaction_fee(accounts(3)), 500);
+
+        // scheduled change of the override
+        let next_fee: u16 = 100;
+        let start_time: Timestamp = DEFAULT_ADMIN_ACTION_DELAY * 3;
+        let action_id = contract.set_nft_contract_transaction_fee(accounts(2), next_fee, Some(to_sec(start_time)));
+        testing_env!(context
+            .block_timestamp(DEFAULT_ADMIN_ACTION_DELAY * 2)
+            .build());
+        contract.execute_admin_action(action_id);
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        let market = contract.get_market_data(accounts(2), "1:1".to_string());
+        assert_eq!(market.transaction_fee.0, 200);
+
+        testing_env!(context.block_timestamp(start_time).build());
+        assert_eq!(contract.get_effective_transaction_fee(accounts(2)), next_fee);
+        assert_eq!(contract.calculate_nft_contract_transaction_fee(&accounts(2)), next_fee as u128);
+        assert_eq!(contract.get_transaction_fee().current_fee, 500);
+
+        let action_id = contract.remove_nft_contract_transaction_fee(accounts(2));
+        testing_env!(context
+            .block_timestamp(start_time + DEFAULT_ADMIN_ACTION_DELAY)
+            .build());
+        contract.execute_admin_action(action_id);
+        assert!(contract.get_nft_contract_transaction_fee(accounts(2)).is_none());
+        assert_eq!(contract.get_effective_transaction_fee(accounts(2)), 500);
+    }
+
     #[test]
     fn test_audit_issue_1_extra_attached_near_may_be_locked() {}
 
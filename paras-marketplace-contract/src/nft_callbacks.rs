@@ -28,6 +28,16 @@ pub struct MarketArgs {
     pub buyer_nft_contract_id: Option<AccountId>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub buyer_token_id: Option<TokenId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buyer_amount: Option<U128>, // trade top-up from the buyer's internal balance
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seller_amount: Option<U128>, // trade top-up requested from the seller
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accept_nft_contract_id: Option<AccountId>, // sale paid with an NFT
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accept_token_id: Option<TokenId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bundle_id: Option<U64>,
 }
 
 trait NonFungibleTokenApprovalsReceiver {
@@ -77,7 +87,12 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
             seller_token_id,
             seller_token_series_id,
             buyer_nft_contract_id,
-            buyer_token_id
+            buyer_token_id,
+            buyer_amount,
+            seller_amount,
+            accept_nft_contract_id,
+            accept_token_id,
+            bundle_id,
         } = near_sdk::serde_json::from_str(&msg).expect("Not valid MarketArgs");
 
         // replace old approval id on trade
@@ -97,22 +112,8 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
 
         if market_type == "sale" {
             assert!(price.is_some(), "Paras: price not specified");
+            self.assert_not_paused(Operation::Listing, &nft_contract_id);
 
-            let storage_amount = self.storage_minimum_balance().0;
-            let owner_paid_storage = self.storage_deposits.get(&signer_id).unwrap_or(0);
-            let signer_storage_required =
-                (self.get_supply_by_owner_id(signer_id).0 + 1) as u128 * storage_amount;
-
-            if owner_paid_storage < signer_storage_required {
-                let notif=format!("Insufficient storage paid: {}, for {} sales at {} rate of per sale",
-                owner_paid_storage,
-                signer_storage_required / storage_amount,
-                storage_amount
-                );
-                env::log_str(&notif);
-                return;
-            }
-            
             self.internal_delete_market_data(&nft_contract_id, &token_id);
 
             let ft_token_id_res = ft_token_id.unwrap_or(near_account());
@@ -132,10 +133,14 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
                 ended_at,
                 end_price,
                 is_auction,
+                accept_nft_contract_id,
+                accept_token_id,
             );
+            self.assert_storage_covered(&signer_id);
         } else if market_type == "accept_offer" {
             assert!(buyer_id.is_some(), "Paras: Account id is not specified");
             assert!(price.is_some(), "Paras: Price is not specified (for check)");
+            self.assert_not_paused(Operation::Offer, &nft_contract_id);
 
             self.internal_accept_offer(
                 nft_contract_id,
@@ -152,6 +157,7 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
                 "Paras: accepting offer series for Paras NFT only"
             );
             assert!(price.is_some(), "Paras: Price is not specified (for check)");
+            self.assert_not_paused(Operation::Offer, &nft_contract_id);
 
             self.internal_accept_offer_series(
                 nft_contract_id,
@@ -161,22 +167,60 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
                 approval_id,
                 price.unwrap().0,
             );
-        } else if market_type == "add_trade" {
+        } else if market_type == "accept_collection_offer" {
+            assert!(buyer_id.is_some(), "Paras: Account id is not specified");
+            assert!(price.is_some(), "Paras: Price is not specified (for check)");
+            self.assert_not_paused(Operation::Offer, &nft_contract_id);
 
-            let storage_amount = self.storage_minimum_balance().0;
-            let owner_paid_storage = self.storage_deposits.get(&signer_id).unwrap_or(0);
-            let signer_storage_required =
-                (self.get_supply_by_owner_id(signer_id).0 + 1) as u128 * storage_amount;
-
-            if owner_paid_storage < signer_storage_required {
-                let notif=format!("Insufficient storage paid: {}, for {} sales at {} rate of per sale",
-                                  owner_paid_storage,
-                                  signer_storage_required / storage_amount,
-                                  storage_amount
-                );
-                env::log_str(&notif);
-                return;
-            }
+            self.internal_accept_collection_offer(
+                nft_contract_id,
+                buyer_id.unwrap(),
+                token_id,
+                owner_id,
+                approval_id,
+                price.unwrap().0,
+            );
+        } else if market_type == "buy_with_nft" {
+            assert!(seller_nft_contract_id.is_some(), "Paras: Seller NFT contract id is not specified");
+            assert!(seller_token_id.is_some(), "Paras: Seller token id is not specified");
+            self.assert_not_paused(Operation::Trade, &nft_contract_id);
+
+            self.internal_buy_with_nft(
+                seller_nft_contract_id.unwrap(),
+                seller_token_id.unwrap(),
+                owner_id,
+                nft_contract_id,
+                token_id,
+                approval_id,
+            );
+        } else if market_type == "approve_bundle_listing" {
+            assert!(bundle_id.is_some(), "Paras: Bundle id is not specified");
+            self.assert_not_paused(Operation::Listing, &nft_contract_id);
+
+            self.internal_approve_bundle_listing(
+                bundle_id.unwrap().0,
+                nft_contract_id,
+                token_id,
+                owner_id,
+                approval_id,
+            );
+            self.assert_storage_covered(&signer_id);
+        } else if market_type == "approve_bundle_trade" {
+            assert!(bundle_id.is_some(), "Paras: Bundle id is not specified");
+            self.assert_not_paused(Operation::Trade, &nft_contract_id);
+
+            self.internal_approve_bundle_trade(
+                bundle_id.unwrap().0,
+                nft_contract_id,
+                token_id,
+                owner_id,
+                approval_id,
+            );
+            self.assert_storage_covered(&signer_id);
+        } else if market_type == "add_trade" {
+            assert!(seller_nft_contract_id.is_some(), "Paras: Seller NFT contract id is not specified");
+            self.assert_not_paused(Operation::Trade, &nft_contract_id);
+            self.assert_not_paused(Operation::Trade, seller_nft_contract_id.as_ref().unwrap());
 
             self.add_trade(
                 seller_nft_contract_id.unwrap(),
@@ -186,12 +230,18 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
                 owner_id,
                 Some(token_id),
                 approval_id,
+                ft_token_id,
+                buyer_amount,
+                seller_amount,
             );
+            self.assert_storage_covered(&signer_id);
         } else if market_type == "accept_trade" {
 
             assert!(buyer_id.is_some(), "Paras: Account id is not specified");
             assert!(buyer_nft_contract_id.is_some(), "Paras: Buyer NFT contract id is not specified");
             assert!(buyer_token_id.is_some(), "Paras: Buyer token id is not specified");
+            self.assert_not_paused(Operation::Trade, &nft_contract_id);
+            self.assert_not_paused(Operation::Trade, buyer_nft_contract_id.as_ref().unwrap());
 
             self.internal_accept_trade(
                 nft_contract_id,
@@ -212,6 +262,8 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
             );
             assert!(buyer_nft_contract_id.is_some(), "Paras: Buyer NFT contract id is not specified");
             assert!(buyer_token_id.is_some(), "Paras: Buyer token id is not specified");
+            self.assert_not_paused(Operation::Trade, &nft_contract_id);
+            self.assert_not_paused(Operation::Trade, buyer_nft_contract_id.as_ref().unwrap());
 
             self.internal_accept_trade_series(
                 nft_contract_id,
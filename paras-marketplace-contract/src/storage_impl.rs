@@ -0,0 +1,220 @@
+use crate::*;
+use near_contract_standards::storage_management::{
+    StorageBalance, StorageBalanceBounds, StorageManagement,
+};
+
+#[near_bindgen]
+impl StorageManagement for Contract {
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let storage_account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let mut deposit = env::attached_deposit();
+        let balance = self.storage_deposits.get(&storage_account_id);
+
+        if registration_only.unwrap_or(false) {
+            if balance.is_some() {
+                // already registered, refund the whole deposit
+                if deposit > 0 {
+                    Promise::new(env::predecessor_account_id()).transfer(deposit);
+                }
+                return self.storage_balance_of(storage_account_id).unwrap();
+            }
+            if deposit > STORAGE_ADD_MARKET_DATA {
+                Promise::new(env::predecessor_account_id())
+                    .transfer(deposit - STORAGE_ADD_MARKET_DATA);
+                deposit = STORAGE_ADD_MARKET_DATA;
+            }
+        }
+
+        if balance.is_none() {
+            assert!(
+                deposit >= STORAGE_ADD_MARKET_DATA,
+                "Requires minimum deposit of {}",
+                STORAGE_ADD_MARKET_DATA
+            );
+        }
+
+        self.storage_deposits
+            .insert(&storage_account_id, &(balance.unwrap_or(0) + deposit));
+
+        self.storage_balance_of(storage_account_id).unwrap()
+    }
+
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_one_yocto();
+        let owner_id = env::predecessor_account_id();
+        let storage_balance = self
+            .storage_balance_of(owner_id.clone())
+            .expect("Paras: Account is not registered");
+
+        let amount = amount.map_or(storage_balance.available.0, |amount| amount.0);
+        assert!(
+            amount <= storage_balance.available.0,
+            "Paras: Amount exceeds the available storage balance: {}",
+            storage_balance.available.0
+        );
+
+        if amount > 0 {
+            self.storage_deposits
+                .insert(&owner_id, &(storage_balance.total.0 - amount));
+            self.internal_transfer_near(owner_id.clone(), amount);
+        }
+
+        self.storage_balance_of(owner_id).unwrap()
+    }
+
+    /// Removes the account's storage balance and refunds it. With force, the account's
+    /// listings, offers and trades are removed (and their escrow refunded) first.
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        if !self.storage_deposits.contains_key(&account_id) {
+            return false;
+        }
+
+        if force.unwrap_or(false) {
+            self.internal_remove_account_entries(&account_id);
+        }
+
+        // the storage_used entry is the only thing left to pay for on a clean account
+        let storage_used = self.storage_used.get(&account_id).unwrap_or(0);
+        let initial_storage_usage = env::storage_usage();
+        self.storage_used.remove(&account_id);
+        assert!(
+            storage_used <= initial_storage_usage - env::storage_usage()
+                && self.legacy_by_owner_id.get(&account_id).is_none(),
+            "Paras: Can't unregister an account with active listings, offers, trades or bids"
+        );
+
+        let balance = self.storage_deposits.remove(&account_id).unwrap();
+        if balance > 0 {
+            self.internal_transfer_near(account_id, balance);
+        }
+
+        true
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: U128(STORAGE_ADD_MARKET_DATA),
+            max: None,
+        }
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.storage_deposits.get(&account_id).map(|total| StorageBalance {
+            total: U128(total),
+            available: U128(total.saturating_sub(self.internal_storage_required(&account_id))),
+        })
+    }
+}
+
+impl Contract {
+    fn internal_remove_account_entries(&mut self, account_id: &AccountId) {
+        let keys = self.internal_by_owner_id_keys(account_id, 0, self.internal_by_owner_id_len(account_id));
+
+        for key in keys {
+            if let Some(contract_account_id) =
+                key.strip_suffix(&format!("{}collection_offer", DELIMETER))
+            {
+                if let Some(collection_offer) =
+                    self.collection_offers.get(&contract_account_id.to_string())
+                {
+                    self.internal_delete_collection_offer(
+                        collection_offer.nft_contract_id.clone(),
+                        collection_offer.buyer_id.clone(),
+                    );
+                    self.internal_transfer(
+                        &collection_offer.ft_token_id,
+                        collection_offer.buyer_id.clone(),
+                        collection_offer.price * collection_offer.quantity as u128,
+                    );
+
+                    MarketEvent::DeleteCollectionOffer(DeleteCollectionOfferLog {
+                        buyer_id: collection_offer.buyer_id,
+                        nft_contract_id: collection_offer.nft_contract_id,
+                        is_expired: false,
+                    })
+                    .emit();
+                }
+            } else if let Some(offer_data) = self.offers.get(&key) {
+                let token = offer_data
+                    .token_id
+                    .clone()
+                    .or(offer_data.token_series_id.clone())
+                    .unwrap();
+                let is_pooled = self.pooled_offers.contains(&key);
+                self.internal_delete_offer(
+                    offer_data.nft_contract_id.clone(),
+                    offer_data.buyer_id.clone(),
+                    token,
+                );
+                if !is_pooled {
+                    self.internal_transfer(
+                        &offer_data.ft_token_id,
+                        offer_data.buyer_id.clone(),
+                        offer_data.price,
+                    );
+                }
+
+                MarketEvent::DeleteOffer(DeleteOfferLog {
+                    buyer_id: offer_data.buyer_id,
+                    nft_contract_id: offer_data.nft_contract_id,
+                    token_id: offer_data.token_id,
+                    token_series_id: offer_data.token_series_id,
+                    is_expired: false,
+                })
+                .emit();
+            } else if let Some(market_data) = self.internal_get_market_data(&key) {
+                self.internal_delete_market_data(&market_data.nft_contract_id, &market_data.token_id);
+
+                MarketEvent::DeleteMarketData(DeleteMarketDataLog {
+                    owner_id: market_data.owner_id,
+                    nft_contract_id: market_data.nft_contract_id,
+                    token_id: market_data.token_id,
+                })
+                .emit();
+            }
+        }
+
+        let trade_ids = self
+            .trades_by_owner_id
+            .get(account_id)
+            .map_or(vec![], |trade_ids| trade_ids.to_vec());
+
+        for buyer_contract_account_id_token_id in trade_ids {
+            if let Some(trade_list) = self.trades.get(&buyer_contract_account_id_token_id) {
+                // nft_contract_id||account_id||token_id
+                let mut key_iter = buyer_contract_account_id_token_id.splitn(3, DELIMETER);
+                let buyer_nft_contract_id: AccountId = key_iter.next().unwrap().parse().unwrap();
+                let buyer_token_id = key_iter.nth(1).unwrap().to_string();
+
+                for (contract_account_id_token_id, trade_data) in trade_list.trade_data {
+                    self.internal_delete_trade(
+                        account_id.clone(),
+                        buyer_contract_account_id_token_id.clone(),
+                        contract_account_id_token_id,
+                        true,
+                    );
+
+                    MarketEvent::DeleteTrade(DeleteTradeLog {
+                        buyer_id: account_id.clone(),
+                        nft_contract_id: trade_data.nft_contract_id,
+                        token_id: trade_data.token_id,
+                        token_series_id: trade_data.token_series_id,
+                        buyer_nft_contract_id: buyer_nft_contract_id.clone(),
+                        buyer_token_id: buyer_token_id.clone(),
+                    })
+                    .emit();
+                }
+            }
+            self.internal_remove_trade_list(&buyer_contract_account_id_token_id, account_id);
+        }
+    }
+}